@@ -6,20 +6,31 @@ pub(crate) mod users;
 use anyhow::{anyhow, Result};
 use serde::{Deserialize, Serialize};
 use serde_json::error::Category;
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashSet};
 use std::fs::DirBuilder;
 use std::io::Seek;
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 use std::{fs, io, process};
 use thiserror::Error;
 
+use crate::activate::etc_files::acl::AttributedFiles;
 use crate::activate::etc_files::etc_tree::StateV0;
-use crate::{StorePath, STATE_FILE_NAME, SYSTEM_MANAGER_STATE_DIR};
+use crate::activate::etc_files::host_facts::TemplatedFiles;
+use crate::activate::etc_files::overlay::EtcOverlay;
+use crate::activate::etc_files::patch::PatchedFiles;
+use crate::activate::etc_files::secrets::SecretDerivedFiles;
+use crate::activate::services::user_units::{self, UserServices};
+use crate::{in_root, is_live_root, systemd, StorePath, STATE_FILE_NAME, SYSTEM_MANAGER_STATE_DIR};
 
 #[derive(Error, Debug)]
 pub enum ActivationError<R> {
     #[error("")]
-    WithPartialResult { result: R, source: anyhow::Error },
+    WithPartialResult {
+        /// Boxed, the state reached by a partial activation can be large.
+        result: Box<R>,
+        source: anyhow::Error,
+    },
 }
 
 impl<R> ActivationError<R> {
@@ -28,7 +39,7 @@ impl<R> ActivationError<R> {
         E: Into<anyhow::Error>,
     {
         Self::WithPartialResult {
-            result,
+            result: Box::new(result),
             source: source.into(),
         }
     }
@@ -45,17 +56,45 @@ pub enum FileStatus {
 
 type EtcTree = HashSet<PathBuf>;
 type BackedUpFiles = HashSet<PathBuf>;
+type DivertedFiles = HashSet<PathBuf>;
 #[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct EtcFilesState {
     pub files: EtcTree,
     pub backed_up_files: BackedUpFiles,
+    /// Replaced package files for which we registered a dpkg diversion.
+    #[serde(default)]
+    pub diverted_files: DivertedFiles,
+    /// Distro-owned files in which we only set some keys, with their original values.
+    #[serde(default)]
+    pub patched_files: PatchedFiles,
+    /// Copied files whose content was rendered from secrets at activation time.
+    #[serde(default)]
+    pub secret_derived_files: SecretDerivedFiles,
+    /// Copied files templated with host facts, along with the substituted facts.
+    #[serde(default)]
+    pub templated_files: TemplatedFiles,
+    /// Copied files with ACL entries or extended attributes.
+    #[serde(default)]
+    pub attributed_files: AttributedFiles,
+    /// The overlay mounted over /etc, if activated in overlay mode.
+    #[serde(default)]
+    pub overlay: Option<EtcOverlay>,
 }
 
 impl EtcFilesState {
     pub fn contains(&self, path: &Path) -> bool {
         self.files.contains(path) || self.backed_up_files.contains(path)
     }
+
+    /// The managed files, including the ones provided by the overlay.
+    pub fn all_files(&self) -> EtcTree {
+        let mut files = self.files.clone();
+        if let Some(overlay) = &self.overlay {
+            files.extend(overlay.files.iter().cloned());
+        }
+        files
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -63,6 +102,12 @@ impl EtcFilesState {
 pub struct StateV1 {
     pub(crate) file_tree: EtcFilesState,
     pub(crate) services: services::Services,
+    /// The user units activated in the running user managers, by user name.
+    #[serde(default)]
+    pub(crate) user_services: UserServices,
+    /// The symlinks created to enable units through their `[Install]` section.
+    #[serde(default)]
+    pub(crate) enabled_units: services::EnabledUnits,
     pub(crate) version: u32,
 }
 
@@ -71,6 +116,8 @@ impl Default for StateV1 {
         Self {
             file_tree: EtcFilesState::default(),
             services: services::Services::default(),
+            user_services: UserServices::default(),
+            enabled_units: services::EnabledUnits::default(),
             version: 1,
         }
     }
@@ -128,34 +175,137 @@ impl StateV1 {
     }
 }
 
-pub fn activate(store_path: &StorePath, ephemeral: bool) -> Result<()> {
-    log::info!("Activating system-manager profile: {store_path}");
-    if ephemeral {
-        log::info!("Running in ephemeral mode");
+/// How long to wait for systemd jobs, unless the units ask for more.
+pub const DEFAULT_JOB_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How many journal lines to report for each failed unit by default.
+pub const DEFAULT_JOURNAL_LINES: usize = 20;
+
+/// Tunables of the service activation.
+#[derive(Debug, Clone)]
+pub struct ServiceOptions {
+    /// How long to watch the units for failures once their jobs finished.
+    pub settle_time: Duration,
+    /// How long to wait for the jobs of units without a timeout of their own.
+    pub job_timeout: Duration,
+    /// How many journal lines to report for each failed unit.
+    pub journal_lines: usize,
+}
+
+impl Default for ServiceOptions {
+    fn default() -> Self {
+        Self {
+            settle_time: Duration::ZERO,
+            job_timeout: DEFAULT_JOB_TIMEOUT,
+            journal_lines: DEFAULT_JOURNAL_LINES,
+        }
     }
+}
 
-    log::info!("Running pre-activation assertions...");
-    if !run_preactivation_assertions(store_path)?.success() {
-        anyhow::bail!("Failure in pre-activation assertions.");
+/// What the activation did, printed by the engine with `--output json`.
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ActivationReport {
+    pub success: bool,
+    /// Why the activation failed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// The units we queued a job for or that failed, with the journal of the failed ones.
+    pub units: BTreeMap<String, services::UnitReport>,
+    /// The user units we queued a job for, by user name.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub user_units: BTreeMap<String, BTreeMap<String, services::UnitReport>>,
+}
+
+pub fn activate(
+    store_path: &StorePath,
+    root: &Path,
+    ephemeral: bool,
+    overlay: bool,
+    service_options: &ServiceOptions,
+) -> ActivationResult<ActivationReport> {
+    let mut report = ActivationReport::default();
+    match activate_and_report(
+        store_path,
+        root,
+        ephemeral,
+        overlay,
+        service_options,
+        &mut report,
+    ) {
+        Ok(()) => {
+            report.success = true;
+            Ok(report)
+        }
+        Err(e) => {
+            report.error = Some(format!("{e:#}"));
+            Err(ActivationError::with_partial_result(report, e))
+        }
     }
+}
+
+fn activate_and_report(
+    store_path: &StorePath,
+    root: &Path,
+    ephemeral: bool,
+    overlay: bool,
+    service_options: &ServiceOptions,
+    report: &mut ActivationReport,
+) -> Result<()> {
+    log::info!("Activating system-manager profile: {store_path}");
+    log_activation_mode(root, ephemeral, overlay);
+    run_preactivation_assertions_if_live(store_path, root)?;
+    // Released once the activation is over, whether it succeeded or not.
+    let _inhibitor_lock = is_live_root(root)
+        .then(|| systemd::InhibitorLock::take_or_warn("Activating a system-manager profile"))
+        .flatten();
 
-    let state_file = &get_state_file()?;
+    let state_file = &get_state_file(root)?;
     let old_state = StateV1::from_file(state_file)?;
 
+    let unit_plan = if is_live_root(root) {
+        services::prepare(store_path, &old_state.services, ephemeral, service_options)
+    } else {
+        services::UnitPlan::default()
+    };
+
     log::info!("Activating etc files...");
 
-    match etc_files::activate(store_path, old_state.file_tree, ephemeral) {
+    match etc_files::activate(store_path, old_state.file_tree, root, ephemeral, overlay) {
+        Ok(etc_tree) if !is_live_root(root) => activate_in_root(
+            store_path,
+            StateV1 {
+                file_tree: etc_tree,
+                ..old_state
+            },
+            root,
+            state_file,
+        ),
         Ok(etc_tree) => {
             log::info!("Restarting sysinit-reactivation.target...");
-            services::restart_sysinit_reactivation_target()?;
+            if let Err(e) =
+                services::restart_sysinit_reactivation_target(service_options.job_timeout)
+            {
+                // The new /etc is in place but the services were not switched: bring back the
+                // units we stopped, and record where we are before giving up.
+                if let Err(e) = services::restart_stopped_units(&unit_plan) {
+                    log::error!("Error restarting the units stopped for the activation: {e:?}");
+                }
+                StateV1 {
+                    file_tree: etc_tree,
+                    ..old_state
+                }
+                .write_to_file(state_file)?;
+                return Err(e);
+            }
 
             // Restart userborn before tmpfiles so users exist when tmpfiles runs
-            if let Err(e) = services::restart_userborn_if_exists() {
+            if let Err(e) = services::restart_userborn_if_exists(service_options.job_timeout) {
                 log::error!("Error restarting userborn.service: {e}");
             }
 
             log::info!("Activating tmp files...");
-            let tmp_result = tmp_files::activate(&etc_tree.files);
+            let tmp_result = tmp_files::activate(&etc_tree.all_files(), root);
             if let Err(e) = &tmp_result {
                 log::error!("Error during activation of tmp files");
                 log::error!("{e}");
@@ -164,34 +314,72 @@ pub fn activate(store_path: &StorePath, ephemeral: bool) -> Result<()> {
             }
 
             log::info!("Activating systemd services...");
-            let final_state = match services::activate(store_path, old_state.services, ephemeral) {
-                Ok(services) => StateV1 {
-                    file_tree: etc_tree,
-                    services,
-                    version: 1,
-                },
+            let activation = match services::activate(
+                store_path,
+                old_state.services,
+                ephemeral,
+                &old_state.enabled_units,
+                &unit_plan,
+                service_options,
+            ) {
+                Ok(activation) => activation,
                 Err(ActivationError::WithPartialResult { result, source }) => {
                     log::error!("Error during activation: {source:?}");
-                    StateV1 {
-                        file_tree: etc_tree,
-                        services: result,
-                        version: 1,
-                    }
+                    *result
                 }
             };
-            final_state.write_to_file(state_file)?;
+            let user_activation = if ephemeral {
+                if !old_state.user_services.is_empty() {
+                    log::warn!("User units are not activated in ephemeral mode");
+                }
+                user_units::UserUnitsActivation::default()
+            } else {
+                log::info!("Activating systemd user units...");
+                user_units::activate(store_path, &old_state.user_services, service_options)
+            };
+            StateV1 {
+                file_tree: etc_tree,
+                services: activation.services.clone(),
+                user_services: user_activation.services.clone(),
+                enabled_units: activation.enabled.clone(),
+                version: 1,
+            }
+            .write_to_file(state_file)?;
+            report.units = activation.report();
+            report.user_units = user_activation
+                .outcomes
+                .iter()
+                .map(|(user, outcomes)| (user.clone(), services::outcomes_report(outcomes)))
+                .collect();
 
             if let Err(e) = tmp_result {
                 return Err(e.into());
             }
 
+            let mut failed_units: Vec<String> = activation
+                .failed_units()
+                .into_iter()
+                .map(str::to_owned)
+                .collect();
+            failed_units.extend(user_activation.failed_units());
+            if !failed_units.is_empty() {
+                anyhow::bail!(
+                    "{} unit(s) failed to activate: {}",
+                    failed_units.len(),
+                    failed_units.join(", ")
+                );
+            }
+
             Ok(())
         }
         Err(ActivationError::WithPartialResult { result, source }) => {
             log::error!("Error during activation: {source:?}");
             log::debug!("Resulting file tree: {:?}", result);
+            if let Err(e) = services::restart_stopped_units(&unit_plan) {
+                log::error!("Error restarting the units stopped for the activation: {e:?}");
+            }
             let final_state = StateV1 {
-                file_tree: result,
+                file_tree: *result,
                 ..old_state
             };
             final_state.write_to_file(state_file)?;
@@ -200,36 +388,39 @@ pub fn activate(store_path: &StorePath, ephemeral: bool) -> Result<()> {
     }
 }
 
-pub fn prepopulate(store_path: &StorePath, ephemeral: bool) -> Result<()> {
+pub fn prepopulate(
+    store_path: &StorePath,
+    root: &Path,
+    ephemeral: bool,
+    overlay: bool,
+) -> Result<()> {
     log::info!("Pre-populating system-manager profile: {store_path}");
-    if ephemeral {
-        log::info!("Running in ephemeral mode");
-    }
+    log_activation_mode(root, ephemeral, overlay);
+    run_preactivation_assertions_if_live(store_path, root)?;
 
-    log::info!("Running pre-activation assertions...");
-    if !run_preactivation_assertions(store_path)?.success() {
-        anyhow::bail!("Failure in pre-activation assertions.");
-    }
-
-    let state_file = &get_state_file()?;
+    let state_file = &get_state_file(root)?;
     let old_state = StateV1::from_file(state_file)?;
 
     log::info!("Activating etc files...");
 
-    match etc_files::activate(store_path, old_state.file_tree, ephemeral) {
+    match etc_files::activate(store_path, old_state.file_tree, root, ephemeral, overlay) {
         Ok(etc_tree) => {
             log::info!("Registering systemd services...");
             match services::get_active_services(store_path, old_state.services) {
                 Ok(services) => StateV1 {
                     file_tree: etc_tree,
                     services,
+                    user_services: old_state.user_services,
+                    enabled_units: old_state.enabled_units,
                     version: 1,
                 },
                 Err(ActivationError::WithPartialResult { result, source }) => {
                     log::error!("Error during activation: {source:?}");
                     StateV1 {
                         file_tree: etc_tree,
-                        services: result,
+                        services: *result,
+                        user_services: old_state.user_services,
+                        enabled_units: old_state.enabled_units,
                         version: 1,
                     }
                 }
@@ -238,7 +429,7 @@ pub fn prepopulate(store_path: &StorePath, ephemeral: bool) -> Result<()> {
         Err(ActivationError::WithPartialResult { result, source }) => {
             log::error!("Error during activation: {source:?}");
             StateV1 {
-                file_tree: result,
+                file_tree: *result,
                 ..old_state
             }
         }
@@ -247,6 +438,72 @@ pub fn prepopulate(store_path: &StorePath, ephemeral: bool) -> Result<()> {
     Ok(())
 }
 
+/// Finish activating a system that is not running: its services can't be started, and
+/// userborn and tmpfiles are pointed at the alternate root instead.
+///
+/// `state` is the previous state with the new /etc tree.
+fn activate_in_root(
+    store_path: &StorePath,
+    state: StateV1,
+    root: &Path,
+    state_file: &Path,
+) -> Result<()> {
+    log::info!("Registering systemd services...");
+    let services = match services::activate_in_root(store_path, state.services, root) {
+        Ok(services) => services,
+        Err(ActivationError::WithPartialResult { result, source }) => {
+            log::error!("Error during activation: {source:?}");
+            *result
+        }
+    };
+
+    if let Some(unit_file) = services
+        .get("userborn.service")
+        .and_then(services::ServiceConfig::unit_file)
+    {
+        if let Err(e) = users::run_userborn_in_root(unit_file, root) {
+            log::error!("Error creating users: {e:?}");
+        }
+    }
+
+    log::info!("Activating tmp files...");
+    let tmp_result = tmp_files::activate(&state.file_tree.all_files(), root);
+    if let Err(e) = &tmp_result {
+        log::error!("Error during activation of tmp files");
+        log::error!("{e}");
+    }
+
+    // Neither the user units nor the enablement links are touched without a running
+    // service manager, keep tracking them for the next activation.
+    StateV1 { services, ..state }.write_to_file(state_file)?;
+    tmp_result.map_err(Into::into)
+}
+
+fn log_activation_mode(root: &Path, ephemeral: bool, overlay: bool) {
+    if !is_live_root(root) {
+        log::info!("Activating into the alternate root {}", root.display());
+    }
+    if ephemeral {
+        log::info!("Running in ephemeral mode");
+    }
+    if overlay {
+        log::info!("Running in overlay mode");
+    }
+}
+
+fn run_preactivation_assertions_if_live(store_path: &StorePath, root: &Path) -> Result<()> {
+    // The assertions inspect the running system, not the alternate root.
+    if !is_live_root(root) {
+        log::info!("Skipping pre-activation assertions for an alternate root");
+        return Ok(());
+    }
+    log::info!("Running pre-activation assertions...");
+    if !run_preactivation_assertions(store_path)?.success() {
+        anyhow::bail!("Failure in pre-activation assertions.");
+    }
+    Ok(())
+}
+
 fn run_preactivation_assertions(store_path: &StorePath) -> Result<process::ExitStatus> {
     let status = process::Command::new(
         store_path
@@ -260,10 +517,9 @@ fn run_preactivation_assertions(store_path: &StorePath) -> Result<process::ExitS
     Ok(status)
 }
 
-pub(crate) fn get_state_file() -> Result<PathBuf> {
-    let state_file = Path::new(SYSTEM_MANAGER_STATE_DIR).join(STATE_FILE_NAME);
-    DirBuilder::new()
-        .recursive(true)
-        .create(SYSTEM_MANAGER_STATE_DIR)?;
+pub(crate) fn get_state_file(root: &Path) -> Result<PathBuf> {
+    let state_dir = in_root(root, SYSTEM_MANAGER_STATE_DIR);
+    let state_file = state_dir.join(STATE_FILE_NAME);
+    DirBuilder::new().recursive(true).create(&state_dir)?;
     Ok(state_file)
 }
@@ -1,19 +1,27 @@
+pub mod acl;
+mod dir_fd;
 pub mod etc_tree;
+pub mod host_facts;
+pub mod overlay;
+pub mod packages;
+pub mod patch;
+pub mod secrets;
 use anyhow::{anyhow, Context};
 use im::HashMap;
 use regex;
 use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
-use std::fs::Permissions;
-use std::os::unix::prelude::PermissionsExt;
-use std::os::unix::{self, fs as unixfs};
+use std::collections::{BTreeMap, HashSet};
+use std::ffi::{OsStr, OsString};
 use std::path::{Path, PathBuf};
 use std::sync::OnceLock;
 use std::{fs, io};
 
 use super::ActivationResult;
 use crate::activate::{ActivationError, EtcFilesState};
-use crate::{etc_dir, remove_file, remove_link, StorePath};
+use crate::{etc_dir, StorePath};
+pub(crate) use dir_fd::validate_target;
+use dir_fd::{Dir, EntryKind};
+use packages::PackageDatabase;
 
 type EtcActivationResult = ActivationResult<EtcFilesState>;
 
@@ -23,6 +31,22 @@ fn get_uid_gid_regex() -> &'static regex::Regex {
     UID_GID_REGEX.get_or_init(|| regex::Regex::new(r"^\+[0-9]+$").expect("could not compile regex"))
 }
 
+/// The system we activate: the packages owning the files we replace, and the facts
+/// substituted into templates.
+struct Host<'a> {
+    packages: &'a dyn PackageDatabase,
+    facts: host_facts::RootFacts,
+}
+
+/// How many entries an activation left alone, replaced, created or removed.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct EntryCounts {
+    unchanged: usize,
+    updated: usize,
+    created: usize,
+    removed: usize,
+}
+
 const BACKUP_SUFFIX: &str = "system-manager-backup";
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -37,6 +61,23 @@ struct EtcFile {
     mode: String,
     #[serde(default)]
     replace_existing: bool,
+    /// What to do when replacing a file owned by a distro package
+    #[serde(default)]
+    on_package_conflict: packages::PackageConflictPolicy,
+    /// Set only some keys of the existing file instead of replacing it
+    #[serde(default)]
+    patch: Option<patch::EtcPatch>,
+    /// Replace `@secret:<path>@` placeholders when copying the file
+    #[serde(default)]
+    render_secrets: bool,
+    /// Replace `@fact:<name>@` placeholders with host facts when copying the file
+    #[serde(default)]
+    template: bool,
+    /// Additional POSIX ACL entries, like `group:monitoring:r--`
+    #[serde(default)]
+    acl: Vec<String>,
+    #[serde(default)]
+    xattrs: BTreeMap<String, String>,
 }
 
 type EtcFiles = HashMap<String, EtcFile>;
@@ -76,6 +117,11 @@ fn read_config(store_path: &StorePath) -> anyhow::Result<EtcFilesConfig> {
     let reader = io::BufReader::new(file);
     let config: EtcFilesConfig = serde_json::from_reader(reader)?;
     log::debug!("{config}");
+    // A target escaping /etc would let a profile overwrite any file on the system.
+    for (name, entry) in &config.entries {
+        dir_fd::validate_target(&entry.target)
+            .with_context(|| format!("Invalid environment.etc entry {name}"))?;
+    }
     Ok(config)
 }
 
@@ -89,33 +135,85 @@ fn read_config(store_path: &StorePath) -> anyhow::Result<EtcFilesConfig> {
 /// 2. After merging the "static" entries listed in the previous step with the "copy" ones
 ///    coming from the system-manager state, we create all these files on the disk, backing
 ///    up the conflicts if necessary.
+///
+/// In overlay mode, the static entries are not linked one by one. Instead, the static env
+/// gets mounted over /etc once all the other entries are in place.
 pub fn activate(
     store_path: &StorePath,
     old_state: EtcFilesState,
+    root: &Path,
     ephemeral: bool,
+    overlay: bool,
 ) -> EtcActivationResult {
     let config = read_config(store_path)
         .map_err(|e| ActivationError::with_partial_result(old_state.clone(), e))?;
+    let packages = packages::RootPackages::new(root);
+    let mut counts = EntryCounts::default();
+    activate_with(
+        config,
+        old_state,
+        root,
+        ephemeral,
+        overlay,
+        &packages,
+        &mut counts,
+    )
+}
 
-    let etc_dir = etc_dir(ephemeral);
+/// Etc files activation of `config`, looking up the packages owning replaced files in
+/// `packages`, and counting what happened to the entries in `counts`.
+#[allow(clippy::too_many_arguments)]
+fn activate_with(
+    config: EtcFilesConfig,
+    old_state: EtcFilesState,
+    root: &Path,
+    ephemeral: bool,
+    overlay: bool,
+    packages: &dyn PackageDatabase,
+    counts: &mut EntryCounts,
+) -> EtcActivationResult {
+    let host = Host {
+        packages,
+        facts: host_facts::RootFacts::new(root),
+    };
+    let etc_dir = etc_dir(root, ephemeral);
     log::info!("Creating /etc entries in {}", etc_dir.display());
 
-    let mut new_state = EtcFilesState::default();
-
     // Walk through static link, list entries
-    let mut entries = match list_static_entries(&config) {
+    let static_entries = match list_static_entries(&config) {
         Ok(e) => e,
         Err(e) => {
             return Err(ActivationError::WithPartialResult {
-                result: old_state,
+                result: Box::new(old_state),
                 source: e,
             })
         }
     };
+    let (mut entries, next_overlay) = if overlay {
+        let files = static_entries
+            .into_iter()
+            .map(|e| etc_dir.join(e.target))
+            .collect();
+        let next_overlay =
+            overlay::prepare(&config.static_env.store_path, &etc_dir, root, files)
+                .map_err(|e| ActivationError::with_partial_result(old_state.clone(), e))?;
+        (Vec::new(), Some(next_overlay))
+    } else {
+        (static_entries, None)
+    };
+    let patches: Vec<(PathBuf, patch::EtcPatch)> = config
+        .entries
+        .values()
+        .filter_map(|v| {
+            v.patch
+                .clone()
+                .map(|patch| (etc_dir.join(&v.target), patch))
+        })
+        .collect();
     let mut non_static_entries: Vec<EtcFile> = config
         .entries
         .values()
-        .filter(|v| v.mode != "symlink")
+        .filter(|v| v.mode != "symlink" && v.patch.is_none())
         .cloned()
         .map(|mut v| {
             v.source.store_path = v.source.store_path.join(&v.target);
@@ -123,92 +221,278 @@ pub fn activate(
         })
         .collect();
     entries.append(&mut non_static_entries);
+    let new_state = EtcFilesState {
+        // Diversions stay registered until their entry leaves the config.
+        diverted_files: old_state.diverted_files.clone(),
+        // The old overlay stays in the state until it gets unmounted.
+        overlay: old_state.overlay.clone(),
+        ..Default::default()
+    };
+    // The other entries live in the distro's /etc, underneath the overlay.
+    let beneath = overlay::beneath(old_state.overlay.as_ref(), || {
+        let new_state = update_etc_entries(
+            entries,
+            patches,
+            new_state.clone(),
+            &old_state,
+            &etc_dir,
+            &host,
+            counts,
+        )?;
+        let boot_unit = match &next_overlay {
+            Some(next_overlay) => overlay::install_boot_unit(next_overlay, root),
+            None if old_state.overlay.is_some() => overlay::remove_boot_unit(&etc_dir),
+            None => Ok(()),
+        };
+        boot_unit.map_err(|e| ActivationError::with_partial_result(new_state.clone(), e))?;
+        Ok(new_state)
+    });
+    let mut new_state =
+        beneath.map_err(|e| ActivationError::with_partial_result(new_state.clone(), e))??;
+    // Swap the overlays last, and back to back.
+    if let Some(old_overlay) = &old_state.overlay {
+        overlay::unmount(old_overlay)
+            .map_err(|e| ActivationError::with_partial_result(new_state.clone(), e))?;
+        new_state.overlay = None;
+    }
+    if let Some(next_overlay) = next_overlay {
+        overlay::mount(&next_overlay, old_state.overlay.as_ref())
+            .map_err(|e| ActivationError::with_partial_result(new_state.clone(), e))?;
+        new_state.overlay = Some(next_overlay);
+    }
+    log::info!(
+        "/etc entries: {} unchanged, {} updated, {} created, {} removed",
+        counts.unchanged,
+        counts.updated,
+        counts.created,
+        counts.removed
+    );
+    Ok(new_state)
+}
+
+/// Create and patch the entries, then remove the ones gone from the config.
+fn update_etc_entries(
+    entries: Vec<EtcFile>,
+    patches: Vec<(PathBuf, patch::EtcPatch)>,
+    new_state: EtcFilesState,
+    old_state: &EtcFilesState,
+    etc_dir: &Path,
+    host: &Host,
+    counts: &mut EntryCounts,
+) -> EtcActivationResult {
     // Create dirs and link/copy entries
-    new_state = create_etc_files(entries, new_state.clone(), &old_state, &etc_dir)?;
-    // Delete unecessary files
+    let mut new_state = create_etc_files(entries, new_state, old_state, etc_dir, host, counts)?;
+    // Delete unecessary files, and restore the backups of the entries gone from the config
+    let gone_backups: HashSet<PathBuf> = old_state
+        .backed_up_files
+        .iter()
+        .filter(|f| !new_state.contains(f))
+        .cloned()
+        .collect();
+    new_state
+        .backed_up_files
+        .extend(gone_backups.iter().cloned());
     let files_to_delete: HashSet<PathBuf> = old_state
         .files
         .difference(&new_state.files)
+        .chain(&gone_backups)
         .map(|f| f.to_owned())
         .collect();
-    new_state = delete_paths(&files_to_delete, new_state);
+    counts.removed = files_to_delete.len();
+    new_state = delete_paths(&files_to_delete, new_state, host.packages);
+    // Set the patched keys, and restore the ones that are gone from the config
+    new_state.patched_files = patch::activate(patches, &old_state.patched_files);
     Ok(new_state)
 }
 
-pub fn deactivate(old_state: EtcFilesState) -> EtcActivationResult {
+pub fn deactivate(mut old_state: EtcFilesState) -> EtcActivationResult {
+    if let Some(old_overlay) = &old_state.overlay {
+        overlay::unmount(old_overlay)
+            .map_err(|e| ActivationError::with_partial_result(old_state.clone(), e))?;
+        if let Err(e) = overlay::remove_boot_unit(&old_overlay.mount_point) {
+            log::error!("Cannot remove the unit mounting the /etc overlay at boot: {e:?}");
+        }
+        old_state.overlay = None;
+    }
+    let packages = packages::RootPackages::new(Path::new("/"));
     let files = old_state.files.clone();
-    let mut final_state = delete_paths(&files, old_state);
+    let mut final_state = delete_paths(&files, old_state, &packages);
     for file_to_restore in &final_state.backed_up_files.clone() {
-        let _ = restore_backup(file_to_restore)
-            .map(|_| final_state.backed_up_files.remove(&file_to_restore.clone()));
+        // The backup replaces our copy, along with its ACL and extended attributes.
+        let _ = restore_backup(file_to_restore).map(|_| {
+            final_state.backed_up_files.remove(&file_to_restore.clone());
+            final_state.attributed_files.remove(file_to_restore);
+        });
+    }
+    for diverted in final_state.diverted_files.clone() {
+        match packages.remove_diversion(&diverted) {
+            Ok(()) => {
+                final_state.diverted_files.remove(&diverted);
+            }
+            Err(e) => log::error!(
+                "Cannot remove the diversion of {}: {e:?}",
+                diverted.display()
+            ),
+        }
     }
+    final_state.patched_files = patch::deactivate(final_state.patched_files);
     log::info!("Done");
     Ok(final_state)
 }
 
-fn backup_path_for(path: &Path) -> PathBuf {
-    let mut s = path.to_owned();
-    s.add_extension(BACKUP_SUFFIX);
-    s
+fn backup_name_for(name: &OsStr) -> OsString {
+    let mut backup_name = name.to_owned();
+    backup_name.push(".");
+    backup_name.push(BACKUP_SUFFIX);
+    backup_name
 }
 
-fn backup_existing_file(path: &Path) -> anyhow::Result<()> {
-    let backup_path = backup_path_for(path);
+/// Back up an existing file we are about to replace, applying the package conflict policy
+/// of the entry first.
+fn backup_conflicting_file(
+    dir: &Dir,
+    name: &OsStr,
+    target: &Path,
+    policy: packages::PackageConflictPolicy,
+    host: &Host,
+    state: &mut EtcFilesState,
+) -> anyhow::Result<()> {
+    let backup = dir.path().join(backup_name_for(name));
+    if packages::handle_conflict(host.packages, target, &backup, policy)? {
+        state.diverted_files.insert(target.to_owned());
+    }
+    backup_existing_file(dir, name)
+}
+
+fn backup_existing_file(dir: &Dir, name: &OsStr) -> anyhow::Result<()> {
+    let backup_name = backup_name_for(name);
     log::info!(
         "Backing up existing file {} to {}",
-        path.display(),
-        backup_path.display()
+        dir.path().join(name).display(),
+        dir.path().join(&backup_name).display()
     );
-    fs::rename(path, &backup_path)?;
-    Ok(())
+    dir.rename(name, &backup_name)
 }
 
 fn restore_backup(path: &Path) -> anyhow::Result<()> {
-    let backup_path = backup_path_for(path);
-    if backup_path.exists() || backup_path.is_symlink() {
+    let (dir, name) = Dir::open_parent(path, false)?;
+    let backup_name = backup_name_for(&name);
+    if dir.kind(&backup_name)?.exists() {
         log::info!(
             "Restoring backup {} to {}",
-            backup_path.display(),
+            dir.path().join(&backup_name).display(),
             path.display()
         );
-        fs::rename(&backup_path, path)?;
+        dir.rename(&backup_name, &name)?;
     } else {
         log::warn!(
             "Backup file {} not found, cannot restore",
-            backup_path.display()
+            dir.path().join(&backup_name).display()
         );
     }
     Ok(())
 }
 
-fn delete_paths(paths: &HashSet<PathBuf>, mut state: EtcFilesState) -> EtcFilesState {
+fn delete_paths(
+    paths: &HashSet<PathBuf>,
+    mut state: EtcFilesState,
+    packages: &dyn PackageDatabase,
+) -> EtcFilesState {
     for path in paths {
-        if path.exists() {
-            if path.is_symlink() {
-                let _ = remove_link(path).map(|_| state.files.remove(path));
-            } else if path.is_file() {
-                let _ = remove_file(path).map(|_| state.files.remove(path));
-            } else {
-                log::warn!(
-                    "Trying to delete {}, but it seems to be a directory",
-                    path.display()
-                );
+        let deleted = Dir::open_parent(path, false).and_then(|(dir, name)| {
+            match dir.kind(&name)? {
+                EntryKind::Symlink => {
+                    log::info!("Removing symlink: {}", path.display());
+                    dir.unlink(&name)?;
+                }
+                EntryKind::File => {
+                    log::info!("Removing file: {}", path.display());
+                    dir.unlink(&name)?;
+                }
+                EntryKind::Directory | EntryKind::Other => {
+                    anyhow::bail!("{} seems to be a directory", path.display())
+                }
+                EntryKind::Missing => {}
+            }
+            Ok(())
+        });
+        match deleted {
+            Ok(()) => {
+                state.files.remove(path);
+                state.attributed_files.remove(path);
             }
+            Err(e) => log::warn!("Cannot delete {}: {e:?}", path.display()),
         }
-        if state.backed_up_files.contains(path) {
-            let _ = restore_backup(path).map(|_| state.backed_up_files.remove(path));
+        if state.backed_up_files.contains(path) && restore_backup(path).is_ok() {
+            state.backed_up_files.remove(path);
+            // The package manager owns the file again.
+            if state.diverted_files.contains(path) {
+                match packages.remove_diversion(path) {
+                    Ok(()) => {
+                        state.diverted_files.remove(path);
+                    }
+                    Err(e) => {
+                        log::error!("Cannot remove the diversion of {}: {e:?}", path.display())
+                    }
+                }
+            }
         }
     }
     state
 }
 
+/// How many links `resolve_link` follows before giving up.
+const MAX_LINK_HOPS: usize = 40;
+
+/// Follow the chain of links starting at `link` to the entry it ends on, so that sources
+/// reached through several links are recorded the same way. Relative links are resolved
+/// against the directory containing them.
+fn resolve_link(link: &Path) -> anyhow::Result<PathBuf> {
+    let mut path = link.to_path_buf();
+    for _ in 0..MAX_LINK_HOPS {
+        if !fs::symlink_metadata(&path)
+            .with_context(|| format!("Failed to stat {}", path.display()))?
+            .is_symlink()
+        {
+            return Ok(path);
+        }
+        let target = fs::read_link(&path)?;
+        path = normalize(&path.parent().unwrap_or(Path::new("/")).join(target));
+    }
+    anyhow::bail!("Too many levels of symbolic links at {}", link.display())
+}
+
+/// Drop the `.` and `..` components of an absolute `path`, without looking at the filesystem.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            std::path::Component::CurDir => {}
+            std::path::Component::ParentDir => {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
+
 /// List all the files contained in `config_entries` in a DFS fashion.
+///
+/// The static env can hold thousands of entries, so we stay away from anything that
+/// scales with the number of path components or config entries: links are resolved
+/// with a single `readlink`, and `replaceExisting` is looked up in an index.
 fn list_static_entries(config_entries: &EtcFilesConfig) -> anyhow::Result<Vec<EtcFile>> {
     let mut files = Vec::new();
 
+    let entries_by_target: std::collections::HashMap<&Path, &EtcFile> = config_entries
+        .entries
+        .values()
+        .map(|e| (e.target.as_path(), e))
+        .collect();
+
     /// Helper data structure used to keep track of the relative path
     /// from the `static_env` directory.
-    #[derive(Clone)]
     struct DirToVisit {
         absolute_path: PathBuf,
         path_from_root: PathBuf,
@@ -218,61 +502,55 @@ fn list_static_entries(config_entries: &EtcFilesConfig) -> anyhow::Result<Vec<Et
         absolute_path: config_entries.static_env.store_path.clone(),
         path_from_root: PathBuf::from(""),
     }];
-    let mut i = 0;
-
-    while i < dirs_to_visit.len() {
-        let dir = dirs_to_visit
-            .get(i)
-            .context("ERROR: index error in dir loop")?
-            .clone();
-        let dir_content = fs::read_dir(&dir.absolute_path)?;
+
+    while let Some(dir) = dirs_to_visit.pop() {
+        let dir_content = fs::read_dir(&dir.absolute_path)
+            .with_context(|| format!("Failed to read {}", dir.absolute_path.display()))?;
         for file in dir_content {
             let file = file?;
-            let canon_path = fs::canonicalize(file.path()).context(format!(
-                "Failed to get the canonical path of {}",
-                file.path().display()
-            ))?;
-            if canon_path.is_dir() {
-                log::debug!("{} is a dir", canon_path.display());
-                let dirname = file.file_name();
-                let mut path_from_root = dir.path_from_root.clone();
-                path_from_root.push(dirname);
+            let source = if file.file_type()?.is_symlink() {
+                resolve_link(&file.path())?
+            } else {
+                file.path()
+            };
+            let path_from_root = dir.path_from_root.join(file.file_name());
+            if fs::metadata(&source)
+                .with_context(|| format!("Failed to stat {}", source.display()))?
+                .is_dir()
+            {
+                log::debug!("{} is a dir", source.display());
                 dirs_to_visit.push(DirToVisit {
-                    absolute_path: canon_path,
+                    absolute_path: source,
                     path_from_root,
                 });
             } else {
                 log::debug!("{} is a file", file.path().display());
-                let target = dir.path_from_root.clone().join(file.file_name());
-                // Is this file entry available in the config? If so, inherit replace_existing.
-                let replace_existing = config_entries
-                    .entries
-                    .iter()
-                    .find(|e| e.1.target == target)
-                    .map(|e| e.1.replace_existing)
-                    .unwrap_or(false);
+                // Is this file entry available in the config? If so, inherit its conflict handling.
+                let config_entry = entries_by_target.get(path_from_root.as_path());
+                let replace_existing = config_entry.is_some_and(|e| e.replace_existing);
+                let on_package_conflict = config_entry
+                    .map(|e| e.on_package_conflict)
+                    .unwrap_or_default();
                 let etc_file = EtcFile {
-                    source: StorePath {
-                        store_path: canon_path,
-                    },
-                    target: PathBuf::from("/etc").join(target),
+                    source: StorePath { store_path: source },
+                    target: path_from_root,
                     uid: 0,
                     gid: 0,
                     group: "".to_string(),
                     user: "".to_string(),
                     mode: "symlink".to_string(),
                     replace_existing,
+                    on_package_conflict,
+                    patch: None,
+                    render_secrets: false,
+                    template: false,
+                    acl: Vec::new(),
+                    xattrs: BTreeMap::new(),
                 };
-                log::debug!(
-                    "add file: {:?}, path_from_root: {:?}, absolute_path: {:?}",
-                    etc_file,
-                    dir.path_from_root,
-                    dir.absolute_path
-                );
+                log::debug!("add file: {etc_file:?}");
                 files.push(etc_file);
             }
         }
-        i += 1;
     }
     Ok(files)
 }
@@ -282,15 +560,17 @@ fn create_etc_files(
     mut state: EtcFilesState,
     old_state: &EtcFilesState,
     etc_dir: &Path,
+    host: &Host,
+    counts: &mut EntryCounts,
 ) -> EtcActivationResult {
     files.sort_by(|a, b| a.target.cmp(&b.target));
     for file in files {
         let target = file.target.clone();
-        state = match create_etc_file(file, state, old_state, etc_dir) {
+        state = match create_etc_file(file, state, old_state, etc_dir, host, counts) {
             Ok(state) => state,
             Err(ActivationError::WithPartialResult { result, source }) => {
                 log::warn!("Can't link/copy {} to : {}", target.display(), source);
-                result
+                *result
             }
         }
     }
@@ -306,6 +586,8 @@ fn create_etc_file(
     mut state: EtcFilesState,
     old_state: &EtcFilesState,
     etc_dir: &Path,
+    host: &Host,
+    counts: &mut EntryCounts,
 ) -> EtcActivationResult {
     let target = PathBuf::from(etc_dir).join(&file.target);
     log::debug!(
@@ -314,9 +596,10 @@ fn create_etc_file(
         target.display(),
         file.target.display()
     );
-    // Create all dirs
+    // Open the parent dir, creating all dirs on the way
     log::debug!("Creating all dirs up to {:?}", target.parent());
-    target.parent().map(fs::create_dir_all);
+    let (dir, name) = Dir::open_parent(&target, true)
+        .map_err(|e| ActivationError::with_partial_result(state.clone(), e))?;
 
     // We want to override all the Ubuntu systemd .wants and .requires entries.
     // We did not find a proper way to do that from the Nix static env,
@@ -324,27 +607,31 @@ fn create_etc_file(
     let target_is_in_systemd_dir = is_inside_systemd_dependency_dir(&target);
 
     if file.mode == "symlink" {
-        // On some symlinks, target.exists() returns false. Not sure why.
-        let exists = target.exists() || target.is_symlink();
+        let exists = dir
+            .kind(&name)
+            .map_err(|e| ActivationError::with_partial_result(state.clone(), e))?
+            .exists();
         if exists {
             // If the target exists and has been created by a previous system-manager activation,
             // replace it.
             if old_state.contains(&target) {
-                log::debug!(
-                    "{} is managed by system-manager. Deleting.",
-                    &target.display()
-                );
-                fs::remove_file(&target).map_err(|e| ActivationError::WithPartialResult {
-                    result: state.clone(),
-                    source: e.into(),
-                })?;
-                log::debug!("{} is managed by system-manager.", &target.display());
-                unix::fs::symlink(file.source.store_path, &target).map_err(|e| {
-                    ActivationError::WithPartialResult {
-                        result: state.clone(),
-                        source: e.into(),
-                    }
-                })?;
+                // Leave identical links alone, recreating them would wake up inotify watchers.
+                let unchanged = dir.kind(&name).ok() == Some(EntryKind::Symlink)
+                    && dir.read_link(&name).ok().as_ref() == Some(&file.source.store_path);
+                if unchanged {
+                    log::debug!("{} is up to date.", &target.display());
+                    counts.unchanged += 1;
+                } else {
+                    log::debug!(
+                        "{} is managed by system-manager. Deleting.",
+                        &target.display()
+                    );
+                    dir.unlink(&name)
+                        .map_err(|e| ActivationError::with_partial_result(state.clone(), e))?;
+                    dir.symlink(&file.source.store_path, &name)
+                        .map_err(|e| ActivationError::with_partial_result(state.clone(), e))?;
+                    counts.updated += 1;
+                }
                 // Check whether this file is a backup or plain link in the old state.
                 if old_state.files.contains(&target) {
                     state.files.insert(target);
@@ -356,7 +643,8 @@ fn create_etc_file(
                     "{} already exists but it's set to replace. Backup and link again.",
                     file.source
                 );
-                state = backup_and_link(&target, &file.source.store_path, state)?;
+                state = backup_and_link(&dir, &name, &target, &file, host, state)?;
+                counts.updated += 1;
             } else {
                 log::warn!(
                     "Error while creating file in /etc: Unmanaged path already exists in filesystem, please remove it and run system-manager again: {}\nSet replaceExisting if you're willing to override it.",
@@ -366,33 +654,49 @@ fn create_etc_file(
         } else {
             // Target do not exist on the filesystem
             log::debug!("Symlink {} => {}", file.source, target.display());
-            unix::fs::symlink(file.source.store_path, &target).map_err(|e| {
-                ActivationError::WithPartialResult {
-                    result: state.clone(),
-                    source: e.into(),
-                }
-            })?;
+            dir.symlink(&file.source.store_path, &name)
+                .map_err(|e| ActivationError::with_partial_result(state.clone(), e))?;
             state.files.insert(target);
+            counts.created += 1;
         }
     } else {
         log::debug!("{} is a regular file", file.source);
-        state = copy_file(&file.source.store_path, &target, &file, old_state, state)?;
+        state = copy_file(
+            &file.source.store_path,
+            &dir,
+            &name,
+            &target,
+            &file,
+            old_state,
+            state,
+            host,
+            counts,
+        )?;
     }
     Ok(state)
 }
 
 fn backup_and_link(
+    dir: &Dir,
+    name: &OsStr,
     target: &Path,
-    link_path: &Path,
+    file: &EtcFile,
+    host: &Host,
     mut dir_state: EtcFilesState,
 ) -> EtcActivationResult {
-    backup_existing_file(target)
-        .map_err(|e| ActivationError::with_partial_result(dir_state.clone(), e))?;
+    let link_path = &file.source.store_path;
+    backup_conflicting_file(
+        dir,
+        name,
+        target,
+        file.on_package_conflict,
+        host,
+        &mut dir_state,
+    )
+    .map_err(|e| ActivationError::with_partial_result(dir_state.clone(), e))?;
     log::debug!("Symlink {} => {}", link_path.display(), target.display());
-    unix::fs::symlink(link_path, target).map_err(|e| ActivationError::WithPartialResult {
-        result: dir_state.clone(),
-        source: e.into(),
-    })?;
+    dir.symlink(link_path, name)
+        .map_err(|e| ActivationError::with_partial_result(dir_state.clone(), e))?;
     dir_state.backed_up_files.insert(target.to_owned());
     Ok(dir_state)
 }
@@ -408,7 +712,7 @@ fn is_inside_systemd_dependency_dir(link_path: &Path) -> bool {
                 .is_some()
                 && parent
                     .parent()
-                    .map(|pp| pp.ends_with("systemd/system"))
+                    .map(|pp| pp.ends_with("systemd/system") || pp.ends_with("systemd/user"))
                     .unwrap_or(false)
         })
         .unwrap_or(false)
@@ -458,25 +762,43 @@ fn find_gid(entry: &EtcFile) -> anyhow::Result<u32> {
 
 /// Copy a file from source to target.
 /// Failing to copy a file shouldn't stop the overall activation, hence the anyhow return.
+///
+/// A managed file whose content, mode and owner already match is left untouched.
+#[allow(clippy::too_many_arguments)]
 fn copy_file(
     source: &Path,
+    dir: &Dir,
+    name: &OsStr,
     target: &PathBuf,
     entry: &EtcFile,
     old_state: &EtcFilesState,
     mut new_state: EtcFilesState,
+    host: &Host,
+    counts: &mut EntryCounts,
 ) -> EtcActivationResult {
-    let exists = target.exists() || target.is_symlink();
+    let kind = dir
+        .kind(name)
+        .map_err(|e| ActivationError::with_partial_result(new_state.clone(), e))?;
+    let exists = kind.exists();
     let exists_and_need_backup = exists && !old_state.contains(target) && entry.replace_existing;
     if exists && !old_state.contains(target) {
         if exists_and_need_backup {
-            backup_existing_file(target).map_err(|e| ActivationError::WithPartialResult {
-                result: new_state.clone(),
+            backup_conflicting_file(
+                dir,
+                name,
+                target,
+                entry.on_package_conflict,
+                host,
+                &mut new_state,
+            )
+            .map_err(|e| ActivationError::WithPartialResult {
+                result: Box::new(new_state.clone()),
                 source: e,
             })?;
         } else {
             let error = anyhow!("File {} already exists, ignoring. Set replaceExisting if you want to back it up and override it.", target.display());
             return Err(ActivationError::WithPartialResult {
-                result: new_state,
+                result: Box::new(new_state),
                 source: error,
             });
         }
@@ -491,26 +813,107 @@ fn copy_file(
         state: &EtcFilesState,
     ) -> ActivationError<EtcFilesState> {
         ActivationError::WithPartialResult {
-            result: state.clone(),
+            result: Box::new(state.clone()),
             source: e.into(),
         }
     }
-    if exists && old_state.contains(target) {
-        log::debug!("remove {}, we're managing it.", target.display());
-        fs::remove_file(target).map_err(|e| to_activation_result(e, &new_state))?;
-    }
-    log::debug!("copy {} to {}", source.display(), target.display());
-    fs::copy(source, target).map_err(|e| to_activation_result(e, &new_state))?;
     let mode_int =
         u32::from_str_radix(&entry.mode, 8).map_err(|e| to_activation_result(e, &new_state))?;
-    fs::set_permissions(target, Permissions::from_mode(mode_int))
+    let uid = find_uid(entry).map_err(|e| to_activation_result(e, &new_state))?;
+    let gid = find_gid(entry).map_err(|e| to_activation_result(e, &new_state))?;
+    let content = if entry.template || entry.render_secrets {
+        log::debug!("render {} into {}", source.display(), target.display());
+        let mut content =
+            fs::read_to_string(source).map_err(|e| to_activation_result(e, &new_state))?;
+        // Substitute the host facts first, so that secret values never get interpreted.
+        let templated = if entry.template {
+            if old_state
+                .templated_files
+                .get(target)
+                .is_some_and(|f| f.facts_changed(host.facts.get()))
+            {
+                log::info!("Host facts of {} changed, re-rendering", target.display());
+            }
+            let (rendered, templated) = host_facts::render(&content, host.facts.get())
+                .map_err(|e| to_activation_result(e, &new_state))?;
+            content = rendered;
+            Some(templated)
+        } else {
+            None
+        };
+        // Secrets are read again on every activation, whether their sources changed or not.
+        let secret_derived = if entry.render_secrets {
+            let (rendered, secret_derived) =
+                secrets::render(&content).map_err(|e| to_activation_result(e, &new_state))?;
+            content = rendered;
+            Some(secret_derived)
+        } else {
+            None
+        };
+        if let Some(templated) = templated {
+            new_state.templated_files.insert(target.clone(), templated);
+        }
+        if let Some(secret_derived) = secret_derived {
+            new_state
+                .secret_derived_files
+                .insert(target.clone(), secret_derived);
+        }
+        content.into_bytes()
+    } else {
+        log::debug!("copy {} to {}", source.display(), target.display());
+        fs::read(source).map_err(|e| to_activation_result(e, &new_state))?
+    };
+    let attributes = acl::FileAttributes {
+        acl: entry.acl.clone(),
+        xattrs: entry.xattrs.clone(),
+    };
+    let xattrs = attributes
+        .to_xattrs(mode_int)
         .map_err(|e| to_activation_result(e, &new_state))?;
-    unixfs::chown(
-        target,
-        Some(find_uid(entry).map_err(|e| to_activation_result(e, &new_state))?),
-        Some(find_gid(entry).map_err(|e| to_activation_result(e, &new_state))?),
-    )
-    .map_err(|e| to_activation_result(e, &new_state))?;
+    let effective_mode = attributes
+        .effective_mode(mode_int)
+        .map_err(|e| to_activation_result(e, &new_state))?;
+    let unchanged = kind == EntryKind::File
+        && old_state.contains(target)
+        && dir.mode_and_owner(name).ok() == Some((effective_mode, uid, gid))
+        && dir.read(name).ok().as_deref() == Some(content.as_slice());
+    // Only the attributes we manage are compared, the other ones aren't ours.
+    let attributes_changed = old_state
+        .attributed_files
+        .get(target)
+        .cloned()
+        .unwrap_or_default()
+        != attributes;
+    let attributes_drifted = unchanged && !attributes_changed && {
+        let current = dir.xattrs(name).unwrap_or_default();
+        attributes
+            .xattr_names()
+            .any(|xattr| current.get(xattr) != xattrs.get(xattr))
+    };
+    if attributes_drifted {
+        log::warn!(
+            "The ACL or extended attributes of {} were changed outside of system-manager, restoring them",
+            target.display()
+        );
+    }
+    if !attributes.is_empty() {
+        new_state
+            .attributed_files
+            .insert(target.clone(), attributes);
+    }
+    if unchanged && !attributes_changed && !attributes_drifted {
+        log::debug!("{} is up to date.", target.display());
+        counts.unchanged += 1;
+    } else {
+        // Whatever is at the target gets replaced in one go, never written through.
+        dir.write_file(name, &content, mode_int, uid, gid, &xattrs)
+            .map_err(|e| to_activation_result(e, &new_state))?;
+        if exists {
+            counts.updated += 1;
+        } else {
+            counts.created += 1;
+        }
+    }
     // Update the state depending whether or not we backed up a file before
     if exists_and_need_backup || (old_state.backed_up_files.contains(target)) {
         new_state.backed_up_files.insert(target.clone());
@@ -519,3 +922,450 @@ fn copy_file(
     }
     Ok(new_state)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+    use std::time::Instant;
+
+    /// A synthetic static env shaped like a large profile: a store directory full of
+    /// certificates, linked as a whole, plus thousands of individually linked files.
+    #[test]
+    fn large_static_env_is_listed_quickly() {
+        let root = tempfile::tempdir().unwrap();
+        let store = root.path().join("store");
+        let static_env = root.path().join("static-env");
+        let certs = store.join("certs");
+        fs::create_dir_all(&certs).unwrap();
+        for i in 0..2000 {
+            fs::write(certs.join(format!("cert-{i}.pem")), "").unwrap();
+        }
+        fs::create_dir_all(static_env.join("ssl")).unwrap();
+        symlink(&certs, static_env.join("ssl").join("certs")).unwrap();
+
+        let mut entries = EtcFiles::new();
+        for unit in 0..3000 {
+            let source = store.join(format!("unit-{unit}.service"));
+            fs::write(&source, "[Unit]\n").unwrap();
+            let target = PathBuf::from(format!("systemd/system/unit-{unit}.service"));
+            fs::create_dir_all(static_env.join(target.parent().unwrap())).unwrap();
+            symlink(&source, static_env.join(&target)).unwrap();
+            entries.insert(
+                target.display().to_string(),
+                EtcFile {
+                    source: StorePath { store_path: source },
+                    target,
+                    uid: 0,
+                    gid: 0,
+                    group: "".to_string(),
+                    user: "".to_string(),
+                    mode: "symlink".to_string(),
+                    replace_existing: unit % 2 == 0,
+                    on_package_conflict: Default::default(),
+                    patch: None,
+                    render_secrets: false,
+                    template: false,
+                    acl: Vec::new(),
+                    xattrs: BTreeMap::new(),
+                },
+            );
+        }
+        let config = EtcFilesConfig {
+            entries,
+            static_env: StorePath {
+                store_path: static_env,
+            },
+        };
+
+        let start = Instant::now();
+        let files = list_static_entries(&config).unwrap();
+        let elapsed = start.elapsed();
+
+        assert_eq!(files.len(), 5000);
+        let cert = files
+            .iter()
+            .find(|f| f.target == Path::new("ssl/certs/cert-42.pem"))
+            .unwrap();
+        assert_eq!(cert.source.store_path, certs.join("cert-42.pem"));
+        let unit = files
+            .iter()
+            .find(|f| f.target == Path::new("systemd/system/unit-42.service"))
+            .unwrap();
+        assert_eq!(unit.source.store_path, store.join("unit-42.service"));
+        assert!(unit.replace_existing);
+        // Generous bound, only meant to catch the listing going quadratic again.
+        assert!(elapsed.as_secs() < 5, "listing took {elapsed:?}");
+    }
+
+    #[test]
+    fn chains_of_links_are_resolved_to_their_end() {
+        let root = tempfile::tempdir().unwrap();
+        let store = root.path().join("store");
+        fs::create_dir_all(store.join("a")).unwrap();
+        fs::create_dir_all(store.join("b")).unwrap();
+        fs::write(store.join("b/nginx.conf"), "").unwrap();
+        symlink(store.join("b/nginx.conf"), store.join("a/nginx.conf")).unwrap();
+        symlink("./../a/nginx.conf", store.join("b/link")).unwrap();
+
+        assert_eq!(
+            resolve_link(&store.join("b/link")).unwrap(),
+            store.join("b/nginx.conf")
+        );
+        symlink("loop", store.join("loop")).unwrap();
+        assert!(resolve_link(&store.join("loop")).is_err());
+    }
+
+    /// A configuration holding `entries` and an empty static env living in `dir`.
+    fn config(dir: &Path, entries: &[EtcFile]) -> EtcFilesConfig {
+        let static_env = dir.join("static-env");
+        fs::create_dir_all(&static_env).unwrap();
+        EtcFilesConfig {
+            entries: entries
+                .iter()
+                .map(|e| (e.target.display().to_string(), e.clone()))
+                .collect(),
+            static_env: StorePath {
+                store_path: static_env,
+            },
+        }
+    }
+
+    /// An entry copying `content` to `target`, owned by the user running the tests.
+    fn copied(dir: &Path, target: &str, content: &str) -> EtcFile {
+        let source = dir.join("sources");
+        fs::create_dir_all(source.join(target).parent().unwrap()).unwrap();
+        fs::write(source.join(target), content).unwrap();
+        let (uid, gid) = (nix::unistd::getuid(), nix::unistd::getgid());
+        EtcFile {
+            source: StorePath { store_path: source },
+            target: PathBuf::from(target),
+            uid: uid.as_raw(),
+            gid: gid.as_raw(),
+            group: format!("+{gid}"),
+            user: format!("+{uid}"),
+            mode: "0644".to_string(),
+            replace_existing: false,
+            on_package_conflict: Default::default(),
+            patch: None,
+            render_secrets: false,
+            template: false,
+            acl: Vec::new(),
+            xattrs: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn unchanged_entries_are_left_untouched() {
+        use std::os::unix::fs::MetadataExt;
+        let store = tempfile::tempdir().unwrap();
+        let root = tempfile::tempdir().unwrap();
+        let etc = root.path().join("etc");
+        let generation = |name: &str, content: &str, linked: bool| {
+            let dir = store.path().join(name);
+            fs::create_dir_all(dir.join("static-env")).unwrap();
+            if linked {
+                fs::write(dir.join("static-env/motd"), "hello").unwrap();
+            }
+            config(&dir, &[copied(&dir, "app.conf", content)])
+        };
+        let activate = |config, state| {
+            let mut counts = EntryCounts::default();
+            let packages = packages::tests::FakePackages::default();
+            let state = activate_with(
+                config,
+                state,
+                root.path(),
+                false,
+                false,
+                &packages,
+                &mut counts,
+            )
+            .unwrap();
+            (state, counts)
+        };
+        let identity = |name: &str| {
+            let metadata = fs::symlink_metadata(etc.join(name)).unwrap();
+            (metadata.ino(), metadata.mtime(), metadata.mtime_nsec())
+        };
+
+        let (state, counts) = activate(generation("first", "v1", true), EtcFilesState::default());
+        let created = EntryCounts {
+            created: 2,
+            ..Default::default()
+        };
+        assert_eq!(counts, created);
+        let before = (identity("app.conf"), identity("motd"));
+
+        let (state, counts) = activate(generation("first", "v1", true), state);
+        let unchanged = EntryCounts {
+            unchanged: 2,
+            ..Default::default()
+        };
+        assert_eq!(counts, unchanged);
+        assert_eq!((identity("app.conf"), identity("motd")), before);
+
+        let (_, counts) = activate(generation("second", "v2", false), state);
+        let updated = EntryCounts {
+            updated: 1,
+            removed: 1,
+            ..Default::default()
+        };
+        assert_eq!(counts, updated);
+        assert_eq!(fs::read_to_string(etc.join("app.conf")).unwrap(), "v2");
+        assert!(fs::symlink_metadata(etc.join("motd")).is_err());
+    }
+
+    #[test]
+    fn attributes_changed_behind_our_back_are_restored() {
+        use std::os::fd::AsRawFd;
+        let store = tempfile::tempdir().unwrap();
+        let root = tempfile::tempdir().unwrap();
+        let target = root.path().join("etc/app.conf");
+        let entry = EtcFile {
+            acl: vec![format!("group:{}:r--", nix::unistd::getgid())],
+            xattrs: BTreeMap::from([("user.origin".to_owned(), "system-manager".to_owned())]),
+            ..copied(store.path(), "app.conf", "v1")
+        };
+        let activate = |state| {
+            let mut counts = EntryCounts::default();
+            let state = activate_with(
+                config(store.path(), std::slice::from_ref(&entry)),
+                state,
+                root.path(),
+                false,
+                false,
+                &packages::tests::FakePackages::default(),
+                &mut counts,
+            )
+            .unwrap();
+            (state, counts.unchanged, counts.updated)
+        };
+        let xattrs = || {
+            let (dir, name) = Dir::open_parent(&target, false).unwrap();
+            dir.xattrs(&name).unwrap()
+        };
+        let file = || fs::File::open(&target).unwrap();
+
+        let (state, _, _) = activate(EtcFilesState::default());
+        let managed = xattrs();
+        assert_eq!(managed["user.origin"], b"system-manager");
+        assert!(managed.contains_key(acl::ACL_XATTR));
+        let (state, unchanged, updated) = activate(state);
+        assert_eq!((unchanged, updated), (1, 0));
+
+        let value = b"edited";
+        let name = c"user.origin";
+        let set = unsafe {
+            libc::fsetxattr(
+                file().as_raw_fd(),
+                name.as_ptr(),
+                value.as_ptr().cast(),
+                value.len(),
+                0,
+            )
+        };
+        assert_eq!(set, 0);
+        let (state, unchanged, updated) = activate(state);
+        assert_eq!((unchanged, updated), (0, 1));
+        assert_eq!(xattrs(), managed);
+
+        let acl = std::ffi::CString::new(acl::ACL_XATTR).unwrap();
+        assert_eq!(
+            unsafe { libc::fremovexattr(file().as_raw_fd(), acl.as_ptr()) },
+            0
+        );
+        let (_, unchanged, updated) = activate(state);
+        assert_eq!((unchanged, updated), (0, 1));
+        assert_eq!(xattrs(), managed);
+    }
+
+    #[test]
+    fn diversions_are_removed_with_their_entry() {
+        let store = tempfile::tempdir().unwrap();
+        let root = tempfile::tempdir().unwrap();
+        let issue = root.path().join("etc/issue");
+        fs::create_dir_all(issue.parent().unwrap()).unwrap();
+        fs::write(&issue, "Ubuntu\n").unwrap();
+        let packages =
+            packages::tests::FakePackages::default().owning(&issue, packages::PackageManager::Dpkg);
+        let entry = EtcFile {
+            replace_existing: true,
+            on_package_conflict: packages::PackageConflictPolicy::Divert,
+            ..copied(store.path(), "issue", "system-manager\n")
+        };
+
+        let state = activate_with(
+            config(store.path(), &[entry]),
+            EtcFilesState::default(),
+            root.path(),
+            false,
+            false,
+            &packages,
+            &mut EntryCounts::default(),
+        )
+        .unwrap();
+        assert_eq!(fs::read_to_string(&issue).unwrap(), "system-manager\n");
+        assert!(state.diverted_files.contains(&issue));
+        assert_eq!(
+            *packages.diversions.lock().unwrap(),
+            std::slice::from_ref(&issue)
+        );
+
+        let state = activate_with(
+            config(store.path(), &[]),
+            state,
+            root.path(),
+            false,
+            false,
+            &packages,
+            &mut EntryCounts::default(),
+        )
+        .unwrap();
+        assert_eq!(fs::read_to_string(&issue).unwrap(), "Ubuntu\n");
+        assert!(state.backed_up_files.is_empty());
+        assert!(state.diverted_files.is_empty());
+        assert!(packages.diversions.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn other_roots_are_activated_with_their_own_facts_and_packages() {
+        let store = tempfile::tempdir().unwrap();
+        let root = tempfile::tempdir().unwrap();
+        let etc = root.path().join("etc");
+        fs::create_dir_all(&etc).unwrap();
+        fs::write(etc.join("hostname"), "image\n").unwrap();
+        fs::write(etc.join("os-release"), "ID=debian\nVERSION_ID=\"12\"\n").unwrap();
+        fs::write(etc.join("issue"), "Debian\n").unwrap();
+        let dpkg_info = root.path().join("var/lib/dpkg/info");
+        fs::create_dir_all(&dpkg_info).unwrap();
+        fs::write(dpkg_info.join("base-files.list"), "/etc/issue\n").unwrap();
+        let entries = [
+            EtcFile {
+                template: true,
+                ..copied(store.path(), "motd", "@fact:hostname@ @fact:osVersionId@\n")
+            },
+            EtcFile {
+                template: true,
+                ..copied(store.path(), "kernel", "@fact:kernelRelease@\n")
+            },
+            EtcFile {
+                replace_existing: true,
+                on_package_conflict: packages::PackageConflictPolicy::Fail,
+                ..copied(store.path(), "issue", "system-manager\n")
+            },
+        ];
+
+        activate_with(
+            config(store.path(), &entries),
+            EtcFilesState::default(),
+            root.path(),
+            false,
+            false,
+            &packages::RootPackages::new(root.path()),
+            &mut EntryCounts::default(),
+        )
+        .unwrap();
+        let read = |name: &str| fs::read_to_string(etc.join(name)).unwrap();
+        assert_eq!(read("motd"), "image 12\n");
+        // The kernel we run on says nothing about the one the root boots.
+        assert!(!etc.join("kernel").exists());
+        // The base-files package of the root owns /etc/issue.
+        assert_eq!(read("issue"), "Debian\n");
+    }
+
+    /// Run `f` on a thread of its own, in a private mount namespace, if we may.
+    fn in_mount_namespace(f: impl FnOnce() + Send) -> bool {
+        use nix::mount::{mount, MsFlags};
+        use nix::sched::{unshare, CloneFlags};
+        std::thread::scope(|scope| {
+            scope
+                .spawn(|| {
+                    if unshare(CloneFlags::CLONE_NEWNS).is_err() {
+                        return false;
+                    }
+                    let flags = MsFlags::MS_REC | MsFlags::MS_PRIVATE;
+                    mount(None::<&str>, "/", None::<&str>, flags, None::<&str>).unwrap();
+                    f();
+                    true
+                })
+                .join()
+                .unwrap()
+        })
+    }
+
+    #[test]
+    fn overlays_are_swapped_once_the_distro_etc_is_updated() {
+        let store = tempfile::tempdir().unwrap();
+        let root = tempfile::tempdir().unwrap();
+        let etc = root.path().join("etc");
+        fs::create_dir_all(&etc).unwrap();
+        fs::write(etc.join("hostname"), "distro\n").unwrap();
+        let generation = |name: &str| {
+            let dir = store.path().join(name);
+            fs::create_dir_all(dir.join("static-env")).unwrap();
+            fs::write(dir.join("static-env/motd"), name).unwrap();
+            config(&dir, &[copied(&dir, "app.conf", name)])
+        };
+        let read = |name: &str| fs::read_to_string(etc.join(name)).unwrap();
+        let boot_unit = etc.join("systemd/system/system-manager-etc-overlay.service");
+        let packages = packages::tests::FakePackages::default();
+
+        let namespaced = in_mount_namespace(|| {
+            let state = EtcFilesState::default();
+            let state = activate_with(
+                generation("first"),
+                state,
+                root.path(),
+                false,
+                true,
+                &packages,
+                &mut EntryCounts::default(),
+            )
+            .unwrap();
+            assert_eq!(
+                (read("motd"), read("app.conf"), read("hostname")),
+                ("first".into(), "first".into(), "distro\n".into())
+            );
+            assert!(boot_unit.exists());
+
+            // A copy of the entry in the upper layer does not shadow the next generation.
+            fs::write(etc.join("motd"), "edited").unwrap();
+            let state = activate_with(
+                generation("second"),
+                state,
+                root.path(),
+                false,
+                true,
+                &packages,
+                &mut EntryCounts::default(),
+            )
+            .unwrap();
+            assert_eq!(
+                (read("motd"), read("app.conf")),
+                ("second".into(), "second".into())
+            );
+
+            let state = activate_with(
+                generation("third"),
+                state,
+                root.path(),
+                false,
+                false,
+                &packages,
+                &mut EntryCounts::default(),
+            )
+            .unwrap();
+            assert_eq!(state.overlay, None);
+            assert!(fs::symlink_metadata(etc.join("motd")).unwrap().is_symlink());
+            assert_eq!(
+                (read("motd"), read("app.conf")),
+                ("third".into(), "third".into())
+            );
+            assert!(!boot_unit.exists());
+        });
+        if !namespaced {
+            eprintln!("Cannot unshare the mount namespace, skipping");
+        }
+    }
+}
@@ -0,0 +1,220 @@
+//! POSIX ACLs and extended attributes of copy-mode etc files.
+//!
+//! Both are set as extended attributes of the file: ACLs are stored by the kernel in the
+//! `system.posix_acl_access` attribute, in the binary format of `posix_acl_xattr.h`.
+//! We encode them ourselves rather than depending on libacl.
+
+use anyhow::{anyhow, Context};
+use im::HashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use super::dir_fd::Xattrs;
+
+pub const ACL_XATTR: &str = "system.posix_acl_access";
+
+const ACL_VERSION: u32 = 2;
+const ACL_UNDEFINED_ID: u32 = u32::MAX;
+const ACL_USER_OBJ: u16 = 0x01;
+const ACL_USER: u16 = 0x02;
+const ACL_GROUP_OBJ: u16 = 0x04;
+const ACL_GROUP: u16 = 0x08;
+const ACL_MASK: u16 = 0x10;
+const ACL_OTHER: u16 = 0x20;
+
+/// The ACL entries and extended attributes of a copied etc file, as recorded in the state.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FileAttributes {
+    #[serde(default)]
+    pub acl: Vec<String>,
+    #[serde(default)]
+    pub xattrs: BTreeMap<String, String>,
+}
+
+pub type AttributedFiles = HashMap<PathBuf, FileAttributes>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct AclEntry {
+    tag: u16,
+    id: u32,
+    perms: u16,
+}
+
+impl FileAttributes {
+    pub fn is_empty(&self) -> bool {
+        self.acl.is_empty() && self.xattrs.is_empty()
+    }
+
+    /// The extended attributes to set on a file with the given mode, ACL included.
+    pub fn to_xattrs(&self, mode: u32) -> anyhow::Result<Xattrs> {
+        let mut xattrs: Xattrs = self
+            .xattrs
+            .iter()
+            .map(|(name, value)| (name.clone(), value.clone().into_bytes()))
+            .collect();
+        if !self.acl.is_empty() {
+            xattrs.insert(ACL_XATTR.to_owned(), encode_acl(mode, &self.parse_acl()?));
+        }
+        Ok(xattrs)
+    }
+
+    /// The names of the extended attributes we manage on the file.
+    pub fn xattr_names(&self) -> impl Iterator<Item = &str> {
+        self.xattrs
+            .keys()
+            .map(String::as_str)
+            .chain((!self.acl.is_empty()).then_some(ACL_XATTR))
+    }
+
+    /// The mode the file ends up with: with an ACL, the group bits hold the ACL mask.
+    pub fn effective_mode(&self, mode: u32) -> anyhow::Result<u32> {
+        if self.acl.is_empty() {
+            return Ok(mode);
+        }
+        let mask = acl_mask(mode, &self.parse_acl()?);
+        Ok((mode & !0o070) | (u32::from(mask) << 3))
+    }
+
+    fn parse_acl(&self) -> anyhow::Result<Vec<AclEntry>> {
+        let mut entries = self
+            .acl
+            .iter()
+            .map(|entry| {
+                parse_acl_entry(entry).with_context(|| format!("Invalid ACL entry {entry}"))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        entries.sort();
+        if let Some(w) = entries
+            .windows(2)
+            .find(|w| (w[0].tag, w[0].id) == (w[1].tag, w[1].id))
+        {
+            anyhow::bail!("Duplicate ACL entries for id {}", w[0].id);
+        }
+        Ok(entries)
+    }
+}
+
+/// Parse a `user:<name>:<perms>` or `group:<name>:<perms>` entry.
+fn parse_acl_entry(entry: &str) -> anyhow::Result<AclEntry> {
+    let [kind, name, perms] = entry.split(':').collect::<Vec<_>>()[..] else {
+        anyhow::bail!("expected <user|group>:<name>:<perms>");
+    };
+    let (tag, id) = match kind {
+        "user" | "u" => (ACL_USER, resolve_user(name)?),
+        "group" | "g" => (ACL_GROUP, resolve_group(name)?),
+        _ => anyhow::bail!("unknown ACL entry type {kind}"),
+    };
+    Ok(AclEntry {
+        tag,
+        id,
+        perms: parse_perms(perms)?,
+    })
+}
+
+fn resolve_user(name: &str) -> anyhow::Result<u32> {
+    if let Ok(uid) = name.parse() {
+        return Ok(uid);
+    }
+    nix::unistd::User::from_name(name)?
+        .map(|user| user.uid.as_raw())
+        .ok_or_else(|| anyhow!("user {name} not found"))
+}
+
+fn resolve_group(name: &str) -> anyhow::Result<u32> {
+    if let Ok(gid) = name.parse() {
+        return Ok(gid);
+    }
+    nix::unistd::Group::from_name(name)?
+        .map(|group| group.gid.as_raw())
+        .ok_or_else(|| anyhow!("group {name} not found"))
+}
+
+fn parse_perms(perms: &str) -> anyhow::Result<u16> {
+    perms.chars().try_fold(0, |acc, c| match c {
+        'r' => Ok(acc | 4),
+        'w' => Ok(acc | 2),
+        'x' => Ok(acc | 1),
+        '-' => Ok(acc),
+        _ => Err(anyhow!("invalid permission {c}")),
+    })
+}
+
+/// The union of the permissions granted to the group class.
+fn acl_mask(mode: u32, entries: &[AclEntry]) -> u16 {
+    entries
+        .iter()
+        .fold(((mode >> 3) & 0o7) as u16, |mask, e| mask | e.perms)
+}
+
+/// Encode a full access ACL, completing the named entries with the ones derived from `mode`.
+/// Entries must be sorted, the kernel rejects them otherwise.
+fn encode_acl(mode: u32, named: &[AclEntry]) -> Vec<u8> {
+    let obj = |tag, shift: u32| AclEntry {
+        tag,
+        id: ACL_UNDEFINED_ID,
+        perms: ((mode >> shift) & 0o7) as u16,
+    };
+    let mut entries = vec![obj(ACL_USER_OBJ, 6)];
+    entries.extend(named.iter().filter(|e| e.tag == ACL_USER));
+    entries.push(obj(ACL_GROUP_OBJ, 3));
+    entries.extend(named.iter().filter(|e| e.tag == ACL_GROUP));
+    entries.push(AclEntry {
+        tag: ACL_MASK,
+        id: ACL_UNDEFINED_ID,
+        perms: acl_mask(mode, named),
+    });
+    entries.push(obj(ACL_OTHER, 0));
+
+    let mut encoded = ACL_VERSION.to_le_bytes().to_vec();
+    for entry in entries {
+        encoded.extend(entry.tag.to_le_bytes());
+        encoded.extend(entry.perms.to_le_bytes());
+        encoded.extend(entry.id.to_le_bytes());
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn acl_is_encoded_in_kernel_order() {
+        let attributes = FileAttributes {
+            acl: vec!["group:1001:r--".to_owned(), "user:1000:rw-".to_owned()],
+            xattrs: BTreeMap::new(),
+        };
+        let xattrs = attributes.to_xattrs(0o600).unwrap();
+        let acl = &xattrs[ACL_XATTR];
+        let entries: Vec<(u16, u16, u32)> = acl[4..]
+            .chunks(8)
+            .map(|c| {
+                (
+                    u16::from_le_bytes([c[0], c[1]]),
+                    u16::from_le_bytes([c[2], c[3]]),
+                    u32::from_le_bytes([c[4], c[5], c[6], c[7]]),
+                )
+            })
+            .collect();
+        assert_eq!(
+            entries,
+            vec![
+                (ACL_USER_OBJ, 6, ACL_UNDEFINED_ID),
+                (ACL_USER, 6, 1000),
+                (ACL_GROUP_OBJ, 0, ACL_UNDEFINED_ID),
+                (ACL_GROUP, 4, 1001),
+                (ACL_MASK, 6, ACL_UNDEFINED_ID),
+                (ACL_OTHER, 0, ACL_UNDEFINED_ID),
+            ]
+        );
+        assert_eq!(attributes.effective_mode(0o600).unwrap(), 0o660);
+        assert!(FileAttributes {
+            acl: vec!["other::r--".to_owned()],
+            xattrs: BTreeMap::new(),
+        }
+        .to_xattrs(0o644)
+        .is_err());
+    }
+}
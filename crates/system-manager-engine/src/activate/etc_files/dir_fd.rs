@@ -0,0 +1,447 @@
+//! Symlink-safe filesystem operations for /etc entries.
+//!
+//! The engine runs as root, while some of the directories it manages can be writable
+//! by other users. Path-based calls like `fs::remove_file`, `fs::copy` or `chown` follow
+//! symlinks planted in such a directory, which would let a local user redirect our
+//! writes anywhere on the system. Instead, we walk down to the parent directory of every
+//! entry with `O_NOFOLLOW`, and only ever touch the final component relative to that
+//! directory file descriptor.
+
+use anyhow::{anyhow, Context};
+use nix::errno::Errno;
+use nix::fcntl::{self, AtFlags, OFlag};
+use nix::sys::stat::{self, FileStat, Mode, SFlag};
+use nix::unistd::{self, Gid, Uid, UnlinkatFlags};
+use std::collections::BTreeMap;
+use std::ffi::{CString, OsStr, OsString};
+use std::fs::File;
+use std::io::{Read, Write};
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static TMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// How many symlinks we follow while opening a directory, like the kernel's `ELOOP` limit.
+const MAX_SYMLINK_HOPS: usize = 40;
+
+/// Extended attributes of a file, by name.
+pub type Xattrs = BTreeMap<String, Vec<u8>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    Missing,
+    Symlink,
+    File,
+    Directory,
+    Other,
+}
+
+impl EntryKind {
+    pub fn exists(self) -> bool {
+        self != EntryKind::Missing
+    }
+}
+
+/// An open directory, used as the anchor of `*at` system calls.
+pub struct Dir {
+    fd: OwnedFd,
+    path: PathBuf,
+}
+
+impl Dir {
+    /// Open the parent directory of `path` and return it along with the final component.
+    ///
+    /// Every directory on the way is opened with `O_NOFOLLOW`. A symlinked directory is
+    /// only followed if the link is owned by root and lives in a directory that only root
+    /// can write to, since nobody else could have planted it there. Its target is then
+    /// walked down the same way.
+    pub fn open_parent(path: &Path, create: bool) -> anyhow::Result<(Dir, OsString)> {
+        let mut components = path.components();
+        if components.next() != Some(Component::RootDir) {
+            anyhow::bail!("{} is not an absolute path", path.display());
+        }
+        let Some(Component::Normal(name)) = components.next_back() else {
+            anyhow::bail!("{} has no file name", path.display());
+        };
+        let mut dir = Dir::open_root()?;
+        let mut hops = MAX_SYMLINK_HOPS;
+        for component in components {
+            match component {
+                Component::Normal(child) => dir = dir.open_child(child, create, &mut hops)?,
+                _ => anyhow::bail!(
+                    "{} contains a relative component, refusing to use it",
+                    path.display()
+                ),
+            }
+        }
+        Ok((dir, name.to_owned()))
+    }
+
+    fn open_root() -> anyhow::Result<Dir> {
+        let fd = fcntl::open(
+            "/",
+            OFlag::O_RDONLY | OFlag::O_DIRECTORY | OFlag::O_CLOEXEC,
+            Mode::empty(),
+        )?;
+        Ok(Dir {
+            fd,
+            path: PathBuf::from("/"),
+        })
+    }
+
+    fn open_child(&self, name: &OsStr, create: bool, hops: &mut usize) -> anyhow::Result<Dir> {
+        let path = self.path.join(name);
+        let flags = OFlag::O_RDONLY | OFlag::O_DIRECTORY | OFlag::O_CLOEXEC;
+        let fd = match fcntl::openat(&self.fd, name, flags | OFlag::O_NOFOLLOW, Mode::empty()) {
+            Ok(fd) => fd,
+            Err(Errno::ENOENT) if create => {
+                log::debug!("Creating directory {}", path.display());
+                match stat::mkdirat(&self.fd, name, Mode::from_bits_truncate(0o755)) {
+                    Ok(()) | Err(Errno::EEXIST) => {}
+                    Err(e) => {
+                        return Err(e).with_context(|| format!("Cannot create {}", path.display()))
+                    }
+                }
+                fcntl::openat(&self.fd, name, flags | OFlag::O_NOFOLLOW, Mode::empty())
+                    .with_context(|| format!("Cannot open {}", path.display()))?
+            }
+            Err(Errno::ELOOP) | Err(Errno::ENOTDIR) if self.trusted_symlink(name)? => {
+                log::debug!("Following root-owned symlink {}", path.display());
+                self.open_link_target(name, hops)
+                    .with_context(|| format!("Cannot open {}", path.display()))?
+                    .fd
+            }
+            Err(e) => return Err(e).with_context(|| format!("Cannot open {}", path.display())),
+        };
+        Ok(Dir { fd, path })
+    }
+
+    /// Open the directory the symlink `name` points to, checking every directory on the way
+    /// like `open_parent` does.
+    fn open_link_target(&self, name: &OsStr, hops: &mut usize) -> anyhow::Result<Dir> {
+        *hops = hops
+            .checked_sub(1)
+            .ok_or_else(|| anyhow!("too many levels of symbolic links"))?;
+        let target = PathBuf::from(fcntl::readlinkat(&self.fd, name)?);
+        let mut dir = if target.is_absolute() {
+            Dir::open_root()?
+        } else {
+            self.open_relative(Path::new("."))?
+        };
+        for component in target.components() {
+            match component {
+                Component::Normal(child) => dir = dir.open_child(child, false, hops)?,
+                Component::ParentDir => dir = dir.open_relative(Path::new(".."))?,
+                Component::RootDir | Component::CurDir | Component::Prefix(_) => {}
+            }
+        }
+        Ok(dir)
+    }
+
+    /// Open `.` or `..`, which can't be symlinks.
+    fn open_relative(&self, name: &Path) -> anyhow::Result<Dir> {
+        let flags = OFlag::O_RDONLY | OFlag::O_DIRECTORY | OFlag::O_CLOEXEC;
+        let fd = fcntl::openat(&self.fd, name, flags, Mode::empty())?;
+        Ok(Dir {
+            fd,
+            path: self.path.join(name),
+        })
+    }
+
+    fn trusted_symlink(&self, name: &OsStr) -> anyhow::Result<bool> {
+        let link = self.stat(name)?;
+        if !is_kind(&link, SFlag::S_IFLNK) {
+            return Ok(false);
+        }
+        let parent = stat::fstat(&self.fd)?;
+        let trusted = link.st_uid == 0 && parent.st_uid == 0 && parent.st_mode & 0o022 == 0;
+        if !trusted {
+            log::warn!(
+                "Refusing to follow {}: symlinked directories must be owned by root and live in a directory only root can write to",
+                self.path.join(name).display()
+            );
+        }
+        Ok(trusted)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn stat(&self, name: &OsStr) -> nix::Result<FileStat> {
+        stat::fstatat(&self.fd, name, AtFlags::AT_SYMLINK_NOFOLLOW)
+    }
+
+    /// What `name` is, without following symlinks.
+    pub fn kind(&self, name: &OsStr) -> anyhow::Result<EntryKind> {
+        match self.stat(name) {
+            Ok(st) if is_kind(&st, SFlag::S_IFLNK) => Ok(EntryKind::Symlink),
+            Ok(st) if is_kind(&st, SFlag::S_IFREG) => Ok(EntryKind::File),
+            Ok(st) if is_kind(&st, SFlag::S_IFDIR) => Ok(EntryKind::Directory),
+            Ok(_) => Ok(EntryKind::Other),
+            Err(Errno::ENOENT) => Ok(EntryKind::Missing),
+            Err(e) => {
+                Err(e).with_context(|| format!("Cannot stat {}", self.path.join(name).display()))
+            }
+        }
+    }
+
+    /// The permission bits, owner and group of `name`, without following symlinks.
+    pub fn mode_and_owner(&self, name: &OsStr) -> anyhow::Result<(u32, u32, u32)> {
+        let st = self
+            .stat(name)
+            .with_context(|| format!("Cannot stat {}", self.path.join(name).display()))?;
+        Ok((st.st_mode & 0o7777, st.st_uid, st.st_gid))
+    }
+
+    /// Remove a file or a symlink, never a directory.
+    pub fn unlink(&self, name: &OsStr) -> anyhow::Result<()> {
+        unistd::unlinkat(&self.fd, name, UnlinkatFlags::NoRemoveDir)
+            .with_context(|| format!("Cannot remove {}", self.path.join(name).display()))
+    }
+
+    pub fn symlink(&self, target: &Path, name: &OsStr) -> anyhow::Result<()> {
+        unistd::symlinkat(target, &self.fd, name).with_context(|| {
+            format!(
+                "Cannot create symlink {} -> {}",
+                self.path.join(name).display(),
+                target.display()
+            )
+        })
+    }
+
+    pub fn read_link(&self, name: &OsStr) -> anyhow::Result<PathBuf> {
+        fcntl::readlinkat(&self.fd, name)
+            .map(PathBuf::from)
+            .with_context(|| format!("Cannot read symlink {}", self.path.join(name).display()))
+    }
+
+    pub fn rename(&self, from: &OsStr, to: &OsStr) -> anyhow::Result<()> {
+        fcntl::renameat(&self.fd, from, &self.fd, to).with_context(|| {
+            format!(
+                "Cannot rename {} to {}",
+                self.path.join(from).display(),
+                self.path.join(to).display()
+            )
+        })
+    }
+
+    /// Read a regular file, refusing to follow a symlink.
+    pub fn read(&self, name: &OsStr) -> anyhow::Result<Vec<u8>> {
+        let mut content = Vec::new();
+        self.open(name)?.read_to_end(&mut content)?;
+        Ok(content)
+    }
+
+    /// The extended attributes of a regular file, refusing to follow a symlink.
+    pub fn xattrs(&self, name: &OsStr) -> anyhow::Result<Xattrs> {
+        let file = self.open(name)?;
+        get_xattrs(&file).with_context(|| {
+            format!(
+                "Cannot read the xattrs of {}",
+                self.path.join(name).display()
+            )
+        })
+    }
+
+    fn open(&self, name: &OsStr) -> anyhow::Result<File> {
+        fcntl::openat(
+            &self.fd,
+            name,
+            OFlag::O_RDONLY | OFlag::O_NOFOLLOW | OFlag::O_CLOEXEC,
+            Mode::empty(),
+        )
+        .map(File::from)
+        .with_context(|| format!("Cannot open {}", self.path.join(name).display()))
+    }
+
+    /// Atomically replace `name` with a regular file with the given content, mode, owner
+    /// and extended attributes.
+    ///
+    /// The content first goes to a temporary file that is only accessible by root, and
+    /// gets renamed into place once its mode and owner are set. This way it is never
+    /// readable by anyone else in between, and whatever was at `name` is replaced
+    /// rather than written through.
+    pub fn write_file(
+        &self,
+        name: &OsStr,
+        content: &[u8],
+        mode: u32,
+        uid: u32,
+        gid: u32,
+        xattrs: &Xattrs,
+    ) -> anyhow::Result<()> {
+        let mut tmp_name = OsString::from(".");
+        tmp_name.push(name);
+        tmp_name.push(format!(
+            ".system-manager-tmp-{}-{}",
+            std::process::id(),
+            TMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let fd = fcntl::openat(
+            &self.fd,
+            tmp_name.as_os_str(),
+            OFlag::O_WRONLY | OFlag::O_CREAT | OFlag::O_EXCL | OFlag::O_NOFOLLOW | OFlag::O_CLOEXEC,
+            Mode::from_bits_truncate(0o600),
+        )
+        .with_context(|| format!("Cannot create {}", self.path.join(&tmp_name).display()))?;
+        let mut file = File::from(fd);
+        let result = (|| -> anyhow::Result<()> {
+            file.write_all(content)?;
+            unistd::fchown(&file, Some(Uid::from_raw(uid)), Some(Gid::from_raw(gid)))?;
+            stat::fchmod(&file, Mode::from_bits_truncate(mode))?;
+            // After the mode, setting an ACL updates the group bits with its mask.
+            for (xattr, value) in xattrs {
+                set_xattr(&file, xattr, value)
+                    .with_context(|| format!("Cannot set the {xattr} attribute"))?;
+            }
+            file.sync_all()?;
+            self.rename(&tmp_name, name)
+        })();
+        if result.is_err() {
+            let _ = unistd::unlinkat(&self.fd, tmp_name.as_os_str(), UnlinkatFlags::NoRemoveDir);
+        }
+        result.with_context(|| format!("Cannot write {}", self.path.join(name).display()))
+    }
+}
+
+fn set_xattr(file: &File, name: &str, value: &[u8]) -> anyhow::Result<()> {
+    let name = CString::new(name)?;
+    // SAFETY: the name is NUL-terminated, and value is valid for value.len() bytes.
+    let ret = unsafe {
+        libc::fsetxattr(
+            file.as_raw_fd(),
+            name.as_ptr(),
+            value.as_ptr().cast(),
+            value.len(),
+            0,
+        )
+    };
+    Errno::result(ret)?;
+    Ok(())
+}
+
+fn get_xattrs(file: &File) -> anyhow::Result<Xattrs> {
+    let fd = file.as_raw_fd();
+    // SAFETY: a null buffer of size 0 only queries the size of the list.
+    let size = Errno::result(unsafe { libc::flistxattr(fd, std::ptr::null_mut(), 0) })?;
+    let mut names = vec![0u8; size as usize];
+    // SAFETY: the buffer is valid for names.len() bytes.
+    let size =
+        Errno::result(unsafe { libc::flistxattr(fd, names.as_mut_ptr().cast(), names.len()) })?;
+    names.truncate(size as usize);
+
+    let mut xattrs = Xattrs::new();
+    for name in names.split(|b| *b == 0).filter(|n| !n.is_empty()) {
+        let c_name = CString::new(name)?;
+        // SAFETY: same as above, with a NUL-terminated name.
+        let size = Errno::result(unsafe {
+            libc::fgetxattr(fd, c_name.as_ptr(), std::ptr::null_mut(), 0)
+        })?;
+        let mut value = vec![0u8; size as usize];
+        let size = Errno::result(unsafe {
+            libc::fgetxattr(fd, c_name.as_ptr(), value.as_mut_ptr().cast(), value.len())
+        })?;
+        value.truncate(size as usize);
+        xattrs.insert(String::from_utf8_lossy(name).into_owned(), value);
+    }
+    Ok(xattrs)
+}
+
+fn is_kind(st: &FileStat, kind: SFlag) -> bool {
+    SFlag::from_bits_truncate(st.st_mode) & SFlag::S_IFMT == kind
+}
+
+/// Check that an etc target stays inside the directory it is relative to:
+/// it must be a relative path made of plain components only.
+pub fn validate_target(target: &Path) -> anyhow::Result<()> {
+    if target.as_os_str().is_empty() {
+        return Err(anyhow!("empty etc target"));
+    }
+    if let Some(component) = target
+        .components()
+        .find(|c| !matches!(c, Component::Normal(_)))
+    {
+        anyhow::bail!(
+            "invalid etc target {}: {} components are not allowed, targets must be relative to /etc",
+            target.display(),
+            match component {
+                Component::ParentDir => "'..'",
+                Component::CurDir => "'.'",
+                _ => "absolute",
+            }
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::unix::fs::{symlink, PermissionsExt};
+
+    #[test]
+    fn targets_must_stay_inside_etc() {
+        assert!(validate_target(Path::new("systemd/journald.conf")).is_ok());
+        assert!(validate_target(Path::new("")).is_err());
+        assert!(validate_target(Path::new("/etc/passwd")).is_err());
+        assert!(validate_target(Path::new("../root/.ssh/authorized_keys")).is_err());
+        assert!(validate_target(Path::new("foo/../../shadow")).is_err());
+    }
+
+    #[test]
+    fn planted_symlinks_are_not_followed() {
+        let root = tempfile::tempdir().unwrap();
+        let outside = root.path().join("outside");
+        fs::create_dir(&outside).unwrap();
+        let etc = root.path().join("etc");
+        fs::create_dir(&etc).unwrap();
+
+        // A symlinked directory in a directory that others can write to, whoever owns
+        // the link: anybody could have planted it there.
+        symlink(&outside, etc.join("app")).unwrap();
+        fs::set_permissions(&etc, fs::Permissions::from_mode(0o777)).unwrap();
+        let (dir, name) = Dir::open_parent(&etc.join("app.conf"), false).unwrap();
+        assert!(Dir::open_parent(&etc.join("app").join("config"), true).is_err());
+        assert!(!outside.join("config").exists());
+
+        // A symlink in place of the file gets replaced, not written through.
+        symlink(outside.join("stolen"), etc.join("app.conf")).unwrap();
+        assert_eq!(dir.kind(&name).unwrap(), EntryKind::Symlink);
+        assert!(dir.read(&name).is_err());
+        let uid = nix::unistd::getuid().as_raw();
+        let gid = nix::unistd::getgid().as_raw();
+        dir.write_file(&name, b"content\n", 0o640, uid, gid, &Xattrs::new())
+            .unwrap();
+        assert_eq!(dir.kind(&name).unwrap(), EntryKind::File);
+        assert!(!outside.join("stolen").exists());
+        assert_eq!(fs::read(etc.join("app.conf")).unwrap(), b"content\n");
+    }
+
+    #[test]
+    fn symlink_targets_are_walked_down_safely() {
+        let root = tempfile::tempdir().unwrap();
+        let outside = root.path().join("outside");
+        fs::create_dir(&outside).unwrap();
+        let open = root.path().join("open");
+        fs::create_dir(&open).unwrap();
+        symlink(&outside, open.join("link")).unwrap();
+        fs::set_permissions(&open, fs::Permissions::from_mode(0o777)).unwrap();
+        let secure = root.path().join("secure");
+        fs::create_dir(&secure).unwrap();
+        symlink(&outside, secure.join("direct")).unwrap();
+        symlink("../open/link", secure.join("through-open")).unwrap();
+
+        let (dir, _) = Dir::open_parent(&secure.join("entry"), false).unwrap();
+        let mut hops = MAX_SYMLINK_HOPS;
+        assert!(dir
+            .open_link_target(OsStr::new("direct"), &mut hops)
+            .is_ok());
+        // The second link lives in a directory anybody can write to.
+        assert!(dir
+            .open_link_target(OsStr::new("through-open"), &mut hops)
+            .is_err());
+    }
+}
@@ -0,0 +1,277 @@
+//! Activation-time templating of copy-mode etc files with host facts.
+//!
+//! Some configuration needs host-local values that are not known at build time.
+//! Templated entries can reference them with `@fact:<name>@` placeholders, which
+//! get substituted when the file is copied to /etc. The substituted values are
+//! recorded in the state, so that we can tell when the facts drifted.
+//!
+//! When activating another root, the facts are read from its files, and the ones that
+//! only make sense for the running host, like its kernel or its network, are left out.
+
+use anyhow::anyhow;
+use im::HashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use crate::{in_root, is_live_root};
+
+/// The facts that can be referenced from a template, in documentation order.
+pub const FACT_NAMES: &[&str] = &[
+    "hostname",
+    "machineId",
+    "kernelRelease",
+    "architecture",
+    "cpuCount",
+    "osId",
+    "osVersionId",
+    "primaryInterface",
+    "primaryIpv4",
+    "primaryIpv6",
+];
+
+static PLACEHOLDER_REGEX: OnceLock<regex::Regex> = OnceLock::new();
+
+fn get_placeholder_regex() -> &'static regex::Regex {
+    PLACEHOLDER_REGEX.get_or_init(|| {
+        regex::Regex::new(r"@fact:([A-Za-z0-9]+)@").expect("could not compile regex")
+    })
+}
+
+/// Facts gathered from the running host. Facts that could not be determined are absent.
+pub type HostFacts = BTreeMap<&'static str, String>;
+
+/// The facts substituted into a templated etc file, as recorded in the state.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TemplatedFile {
+    pub facts: BTreeMap<String, String>,
+}
+
+pub type TemplatedFiles = HashMap<PathBuf, TemplatedFile>;
+
+impl TemplatedFile {
+    /// Whether any of the substituted facts changed since this file was rendered.
+    pub fn facts_changed(&self, current: &HostFacts) -> bool {
+        self.facts
+            .iter()
+            .any(|(name, value)| current.get(name.as_str()) != Some(value))
+    }
+}
+
+/// The facts of the system installed in a root, gathered once per activation.
+pub struct RootFacts {
+    root: PathBuf,
+    facts: OnceLock<HostFacts>,
+}
+
+impl RootFacts {
+    pub fn new(root: &Path) -> RootFacts {
+        RootFacts {
+            root: root.to_owned(),
+            facts: OnceLock::new(),
+        }
+    }
+
+    pub fn get(&self) -> &HostFacts {
+        self.facts.get_or_init(|| {
+            let facts = gather(&self.root);
+            log::debug!("Host facts: {facts:?}");
+            facts
+        })
+    }
+}
+
+/// Replace all the fact placeholders in `template`.
+pub fn render(template: &str, facts: &HostFacts) -> anyhow::Result<(String, TemplatedFile)> {
+    let mut substituted = BTreeMap::new();
+    let mut rendered = String::with_capacity(template.len());
+    let mut last = 0;
+    for captures in get_placeholder_regex().captures_iter(template) {
+        let placeholder = captures.get(0).expect("capture 0 always exists");
+        let name = &captures[1];
+        if !FACT_NAMES.contains(&name) {
+            anyhow::bail!(
+                "Unknown host fact {name}, expected one of: {}",
+                FACT_NAMES.join(", ")
+            );
+        }
+        let value = facts
+            .get(name)
+            .ok_or_else(|| anyhow!("Host fact {name} is not available on this host"))?;
+        rendered.push_str(&template[last..placeholder.start()]);
+        rendered.push_str(value);
+        last = placeholder.end();
+        substituted.insert(name.to_owned(), value.clone());
+    }
+    rendered.push_str(&template[last..]);
+    Ok((rendered, TemplatedFile { facts: substituted }))
+}
+
+fn gather(root: &Path) -> HostFacts {
+    let mut facts = HostFacts::new();
+    let mut insert = |name: &'static str, value: anyhow::Result<String>| match value {
+        Ok(value) => {
+            facts.insert(name, value);
+        }
+        Err(e) => log::debug!("Host fact {name} is not available: {e}"),
+    };
+    let read_trimmed = |path: &str| -> anyhow::Result<String> {
+        Ok(fs::read_to_string(in_root(root, path))?.trim().to_owned())
+    };
+
+    if is_live_root(root) {
+        insert(
+            "hostname",
+            nix::unistd::gethostname()
+                .map_err(Into::into)
+                .map(|h| h.to_string_lossy().into_owned()),
+        );
+    } else {
+        insert("hostname", read_trimmed("/etc/hostname"));
+    }
+    insert("machineId", read_trimmed("/etc/machine-id"));
+    let os_release = read_os_release(root);
+    insert(
+        "osId",
+        os_release
+            .get("ID")
+            .cloned()
+            .ok_or_else(|| anyhow!("no ID in os-release")),
+    );
+    insert(
+        "osVersionId",
+        os_release
+            .get("VERSION_ID")
+            .cloned()
+            .ok_or_else(|| anyhow!("no VERSION_ID in os-release")),
+    );
+    if !is_live_root(root) {
+        return facts;
+    }
+
+    match nix::sys::utsname::uname() {
+        Ok(uname) => {
+            insert(
+                "kernelRelease",
+                Ok(uname.release().to_string_lossy().into_owned()),
+            );
+            insert(
+                "architecture",
+                Ok(uname.machine().to_string_lossy().into_owned()),
+            );
+        }
+        Err(e) => log::debug!("uname failed: {e}"),
+    }
+    insert(
+        "cpuCount",
+        std::thread::available_parallelism()
+            .map_err(Into::into)
+            .map(|n| n.to_string()),
+    );
+    let interface = default_route_interface();
+    if let Ok(ref interface) = interface {
+        insert(
+            "primaryIpv4",
+            interface_address(interface, |ip| ip.is_ipv4()),
+        );
+        insert(
+            "primaryIpv6",
+            interface_address(interface, |ip| match ip {
+                // Link-local addresses are not routable, skip them.
+                IpAddr::V6(ip) => (ip.segments()[0] & 0xffc0) != 0xfe80,
+                IpAddr::V4(_) => false,
+            }),
+        );
+    }
+    insert("primaryInterface", interface);
+    facts
+}
+
+/// Parse /etc/os-release, falling back to /usr/lib/os-release as per os-release(5).
+fn read_os_release(root: &Path) -> BTreeMap<String, String> {
+    let content = fs::read_to_string(in_root(root, "/etc/os-release"))
+        .or_else(|_| fs::read_to_string(in_root(root, "/usr/lib/os-release")))
+        .unwrap_or_default();
+    content
+        .lines()
+        .filter(|line| !line.trim_start().starts_with('#'))
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, value)| {
+            (
+                key.trim().to_owned(),
+                value
+                    .trim()
+                    .trim_matches(|c| c == '"' || c == '\'')
+                    .to_owned(),
+            )
+        })
+        .collect()
+}
+
+/// The interface holding the IPv4 default route, according to /proc/net/route.
+fn default_route_interface() -> anyhow::Result<String> {
+    let routes = fs::read_to_string("/proc/net/route")?;
+    routes
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            // Iface, Destination, Gateway, Flags, RefCnt, Use, Metric, ...
+            match fields.as_slice() {
+                [iface, "00000000", _, _, _, _, metric, ..] => {
+                    Some((metric.parse::<u32>().unwrap_or(u32::MAX), iface.to_string()))
+                }
+                _ => None,
+            }
+        })
+        .min()
+        .map(|(_, iface)| iface)
+        .ok_or_else(|| anyhow!("no default route"))
+}
+
+fn interface_address(interface: &str, filter: impl Fn(&IpAddr) -> bool) -> anyhow::Result<String> {
+    nix::ifaddrs::getifaddrs()?
+        .filter(|addr| addr.interface_name == interface)
+        .filter_map(|addr| addr.address)
+        .filter_map(|addr| {
+            addr.as_sockaddr_in()
+                .map(|a| IpAddr::V4(a.ip()))
+                .or_else(|| addr.as_sockaddr_in6().map(|a| IpAddr::V6(a.ip())))
+        })
+        .find(filter)
+        .map(|ip| ip.to_string())
+        .ok_or_else(|| anyhow!("no matching address on {interface}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_substitutes_known_facts() {
+        let facts = HostFacts::from([
+            ("hostname", "web-1".to_owned()),
+            ("cpuCount", "8".to_owned()),
+        ]);
+        let (rendered, templated) = render(
+            "server_name @fact:hostname@;\nworkers @fact:cpuCount@;\n",
+            &facts,
+        )
+        .unwrap();
+        assert_eq!(rendered, "server_name web-1;\nworkers 8;\n");
+        assert_eq!(templated.facts.len(), 2);
+
+        assert!(render("@fact:primaryIpv4@", &facts)
+            .unwrap_err()
+            .to_string()
+            .contains("not available"));
+        assert!(render("@fact:colour@", &facts)
+            .unwrap_err()
+            .to_string()
+            .contains("Unknown host fact"));
+    }
+}
@@ -0,0 +1,276 @@
+//! Overlayfs-based /etc, as an alternative to linking every static entry.
+//!
+//! The static env of the profile becomes the topmost read-only lower layer of an overlay
+//! mounted over /etc, with the distro's /etc right below it. Writes made to /etc while
+//! the overlay is mounted end up in a persistent upper layer. Switching to another
+//! generation replaces the mount, and deactivation unmounts it, leaving the distro's
+//! /etc exactly as it was.
+//!
+//! Copied, patched and templated entries are still managed one by one, directly in the
+//! distro's /etc. While an overlay is mounted, that happens on a thread of its own, in a
+//! private mount namespace where the overlay is detached. Everyone else keeps seeing the
+//! old generation until the new overlay replaces it, right after the old one is gone.
+//!
+//! Since nothing mounts the overlay back after a reboot, we install a unit doing it in
+//! the distro's /etc, next to the copied entries.
+
+use anyhow::Context;
+use nix::mount::{self, MntFlags, MsFlags};
+use nix::sched::{self, CloneFlags};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::thread;
+
+use super::dir_fd::{Dir, EntryKind};
+use crate::in_root;
+
+const OVERLAY_DIR: &str = "/var/lib/system-manager/etc-overlay";
+const OVERLAY_SOURCE: &str = "system-manager-etc";
+const BOOT_UNIT: &str = "systemd/system/system-manager-etc-overlay.service";
+const BOOT_UNIT_LINK: &str =
+    "systemd/system/sysinit.target.wants/system-manager-etc-overlay.service";
+
+/// An overlay mounted over /etc, as recorded in the state.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EtcOverlay {
+    pub mount_point: PathBuf,
+    pub lower: PathBuf,
+    pub upper: PathBuf,
+    pub work: PathBuf,
+    /// The entries provided by the lower layer, absolute.
+    pub files: HashSet<PathBuf>,
+}
+
+/// Describe the overlay of `static_env` over `etc_dir`, and create its layers in `root`.
+pub fn prepare(
+    static_env: &Path,
+    etc_dir: &Path,
+    root: &Path,
+    files: HashSet<PathBuf>,
+) -> anyhow::Result<EtcOverlay> {
+    let overlay_dir = in_root(root, OVERLAY_DIR);
+    let overlay = EtcOverlay {
+        mount_point: etc_dir.to_owned(),
+        lower: static_env.to_owned(),
+        upper: overlay_dir.join("upper"),
+        work: overlay_dir.join("work"),
+        files,
+    };
+    // Catch unusable paths before anything gets unmounted.
+    overlay.mount_options(root)?;
+    for dir in [&overlay.upper, &overlay.work] {
+        fs::create_dir_all(dir).with_context(|| format!("Cannot create {}", dir.display()))?;
+    }
+    Ok(overlay)
+}
+
+/// Mount `overlay` over its mount point, once `previous` got unmounted.
+///
+/// Whatever the upper layer holds for the entries of both generations is dropped first,
+/// so that the entries of the new static env are not shadowed by stale copies.
+pub fn mount(overlay: &EtcOverlay, previous: Option<&EtcOverlay>) -> anyhow::Result<()> {
+    let entries = previous
+        .iter()
+        .flat_map(|p| p.files.iter())
+        .chain(&overlay.files);
+    for entry in entries {
+        let Ok(relative) = entry.strip_prefix(&overlay.mount_point) else {
+            continue;
+        };
+        let copy = overlay.upper.join(relative);
+        if fs::symlink_metadata(&copy).is_ok_and(|m| !m.is_dir()) {
+            log::debug!("Dropping {} from the upper layer", copy.display());
+            fs::remove_file(&copy).with_context(|| format!("Cannot remove {}", copy.display()))?;
+        }
+    }
+    let options = overlay.mount_options(Path::new("/"))?;
+    log::info!(
+        "Mounting {} over {}",
+        overlay.lower.display(),
+        overlay.mount_point.display()
+    );
+    log::debug!("overlay options: {options}");
+    mount::mount(
+        Some(OVERLAY_SOURCE),
+        &overlay.mount_point,
+        Some("overlay"),
+        MsFlags::MS_NOSUID | MsFlags::MS_NODEV,
+        Some(options.as_str()),
+    )
+    .with_context(|| {
+        format!(
+            "Cannot mount the /etc overlay over {}",
+            overlay.mount_point.display()
+        )
+    })
+}
+
+/// Unmount a previously mounted overlay.
+///
+/// The unmount is lazy, so that processes holding files open in the old /etc don't make
+/// the switch fail. They keep seeing the old generation until they reopen them.
+pub fn unmount(overlay: &EtcOverlay) -> anyhow::Result<()> {
+    if !is_mounted(&overlay.mount_point)? {
+        // Most likely because the machine rebooted since the last activation.
+        log::info!(
+            "The overlay over {} is not mounted anymore",
+            overlay.mount_point.display()
+        );
+        return Ok(());
+    }
+    log::info!(
+        "Unmounting the overlay over {}",
+        overlay.mount_point.display()
+    );
+    mount::umount2(&overlay.mount_point, MntFlags::MNT_DETACH).with_context(|| {
+        format!(
+            "Cannot unmount the /etc overlay at {}",
+            overlay.mount_point.display()
+        )
+    })
+}
+
+/// Run `f` with the distro's /etc in place of `overlay`, without unmounting it for the
+/// rest of the system.
+pub fn beneath<T: Send>(
+    overlay: Option<&EtcOverlay>,
+    f: impl FnOnce() -> T + Send,
+) -> anyhow::Result<T> {
+    let Some(overlay) = overlay else {
+        return Ok(f());
+    };
+    if !is_mounted(&overlay.mount_point)? {
+        return Ok(f());
+    }
+    thread::scope(|scope| {
+        let detached = scope.spawn(|| {
+            // Only the mount namespace of this thread is unshared.
+            sched::unshare(CloneFlags::CLONE_NEWNS)
+                .context("Cannot unshare the mount namespace")?;
+            // Keep the unmount below from propagating back to the host.
+            mount::mount(
+                None::<&str>,
+                "/",
+                None::<&str>,
+                MsFlags::MS_REC | MsFlags::MS_PRIVATE,
+                None::<&str>,
+            )
+            .context("Cannot make the mounts private")?;
+            mount::umount2(&overlay.mount_point, MntFlags::MNT_DETACH).with_context(|| {
+                format!(
+                    "Cannot detach the /etc overlay at {}",
+                    overlay.mount_point.display()
+                )
+            })?;
+            Ok(f())
+        });
+        detached
+            .join()
+            .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+    })
+}
+
+/// Install the unit mounting `overlay` back at boot into the distro's /etc.
+pub fn install_boot_unit(overlay: &EtcOverlay, root: &Path) -> anyhow::Result<()> {
+    let unit = format!(
+        "[Unit]
+Description=Mount the system-manager /etc overlay
+DefaultDependencies=no
+RequiresMountsFor=/nix/store {OVERLAY_DIR}
+After=local-fs.target
+Before=sysinit.target
+
+[Service]
+Type=oneshot
+RemainAfterExit=yes
+ExecStart=mount -t overlay -o nosuid,nodev,{} {OVERLAY_SOURCE} /etc
+# The units of the profile only show up once the overlay is mounted.
+ExecStartPost=systemctl daemon-reload
+ExecStartPost=systemctl start --no-block system-manager.target
+",
+        overlay.mount_options(root)?
+    );
+    let (dir, name) = Dir::open_parent(&overlay.mount_point.join(BOOT_UNIT), true)?;
+    dir.write_file(&name, unit.as_bytes(), 0o644, 0, 0, &Default::default())?;
+    let (dir, name) = Dir::open_parent(&overlay.mount_point.join(BOOT_UNIT_LINK), true)?;
+    if dir.kind(&name)? == EntryKind::Missing {
+        let target = Path::new("..").join(Path::new(BOOT_UNIT).file_name().unwrap_or_default());
+        dir.symlink(&target, &name)?;
+    }
+    Ok(())
+}
+
+/// Remove the unit installed by `install_boot_unit` from `etc_dir`.
+pub fn remove_boot_unit(etc_dir: &Path) -> anyhow::Result<()> {
+    for path in [BOOT_UNIT_LINK, BOOT_UNIT] {
+        let (dir, name) = Dir::open_parent(&etc_dir.join(path), false)?;
+        if dir.kind(&name)?.exists() {
+            log::debug!("Removing {}", dir.path().join(&name).display());
+            dir.unlink(&name)?;
+        }
+    }
+    Ok(())
+}
+
+/// Whether our overlay is the topmost mount at `mount_point`, according to mountinfo.
+fn is_mounted(mount_point: &Path) -> anyhow::Result<bool> {
+    // The mounts of the calling thread, which may not share those of the process.
+    let mountinfo = fs::read_to_string("/proc/thread-self/mountinfo")?;
+    Ok(mountinfo
+        .lines()
+        .filter_map(parse_mountinfo_line)
+        .rfind(|(point, _, _)| Path::new(point) == mount_point)
+        .is_some_and(|(_, fs_type, source)| fs_type == "overlay" && source == OVERLAY_SOURCE))
+}
+
+/// Extract the mount point, filesystem type and source of a mountinfo line.
+fn parse_mountinfo_line(line: &str) -> Option<(&str, &str, &str)> {
+    // 36 35 98:0 /mnt1 /mnt2 rw,noatime master:1 - ext3 /dev/root rw,errors=continue
+    let (mount, fs) = line.split_once(" - ")?;
+    let mount_point = mount.split(' ').nth(4)?;
+    let mut fs = fs.split(' ');
+    Some((mount_point, fs.next()?, fs.next()?))
+}
+
+impl EtcOverlay {
+    /// The options mounting the overlay, with the layers seen from inside `root`.
+    fn mount_options(&self, root: &Path) -> anyhow::Result<String> {
+        let paths = [&self.lower, &self.mount_point, &self.upper, &self.work]
+            .map(|p| Path::new("/").join(p.strip_prefix(root).unwrap_or(p)));
+        // The overlay options are separated by commas, and lower layers by colons.
+        if let Some(path) = paths
+            .iter()
+            .find(|p| p.to_string_lossy().contains([',', ':', '\\']))
+        {
+            anyhow::bail!(
+                "{} cannot be used as an overlay layer, it contains a special character",
+                path.display()
+            );
+        }
+        Ok(format!(
+            "lowerdir={}:{},upperdir={},workdir={}",
+            paths[0].display(),
+            paths[1].display(),
+            paths[2].display(),
+            paths[3].display()
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mountinfo_lines_are_parsed() {
+        let line = "812 29 0:97 / /etc rw,nosuid,nodev,relatime shared:431 - overlay system-manager-etc rw,lowerdir=/nix/store/abc-etc:/etc";
+        assert_eq!(
+            parse_mountinfo_line(line),
+            Some(("/etc", "overlay", "system-manager-etc"))
+        );
+        assert_eq!(parse_mountinfo_line("garbage"), None);
+    }
+}
@@ -0,0 +1,311 @@
+//! Detection of /etc files owned by distro packages.
+//!
+//! When we replace a file shipped by a package, the next package upgrade may write a
+//! `.dpkg-new` file next to it, or overwrite our entry altogether. We detect these
+//! conflicts from the dpkg file lists or the rpm database, and handle them according to
+//! the policy of the entry. On dpkg systems, a diversion can be registered so that the
+//! package manager installs its version of the file next to ours instead.
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::OnceLock;
+
+use crate::in_root;
+
+const DPKG_INFO_DIR: &str = "/var/lib/dpkg/info";
+const RPM_DB_DIRS: &[&str] = &["/var/lib/rpm", "/usr/lib/sysimage/rpm"];
+
+/// What to do when an entry replaces a file owned by a package.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum PackageConflictPolicy {
+    #[default]
+    Warn,
+    Fail,
+    /// Register a dpkg diversion, warn on other package managers.
+    Divert,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PackageManager {
+    Dpkg,
+    Rpm,
+}
+
+/// Where we look up package ownership and register diversions.
+pub trait PackageDatabase: Sync {
+    /// The package manager and the name of the package owning `path`, if any.
+    fn owning_package(&self, path: &Path) -> Option<(PackageManager, String)>;
+
+    /// Tell the package manager to install its version of `path` to `backup`.
+    fn add_diversion(&self, path: &Path, backup: &Path) -> anyhow::Result<()>;
+
+    /// Remove a diversion registered by `handle_conflict`.
+    fn remove_diversion(&self, path: &Path) -> anyhow::Result<()>;
+}
+
+/// The package databases of the system installed in `root`.
+///
+/// Paths are given as they are seen from outside of the root, and looked up as they are
+/// seen from inside of it.
+pub struct RootPackages {
+    root: PathBuf,
+    dpkg_files: OnceLock<HashMap<PathBuf, String>>,
+}
+
+impl RootPackages {
+    pub fn new(root: &Path) -> RootPackages {
+        RootPackages {
+            root: root.to_owned(),
+            dpkg_files: OnceLock::new(),
+        }
+    }
+
+    /// `path` as seen from inside of the root.
+    fn inside(&self, path: &Path) -> PathBuf {
+        Path::new("/").join(path.strip_prefix(&self.root).unwrap_or(path))
+    }
+
+    /// Index of the /etc files listed in `/var/lib/dpkg/info/<package>.list`, built once.
+    fn dpkg_files(&self) -> &HashMap<PathBuf, String> {
+        self.dpkg_files.get_or_init(|| {
+            let mut files = HashMap::new();
+            let Ok(entries) = fs::read_dir(in_root(&self.root, DPKG_INFO_DIR)) else {
+                return files;
+            };
+            for entry in entries.flatten() {
+                let file_name = entry.file_name();
+                let Some(package) = file_name.to_str().and_then(|n| n.strip_suffix(".list")) else {
+                    continue;
+                };
+                // Multi-arch packages are listed as <package>:<arch>.list
+                let package = package.split(':').next().unwrap_or(package);
+                let Ok(list) = fs::read_to_string(entry.path()) else {
+                    continue;
+                };
+                for line in list.lines().filter(|l| l.starts_with("/etc/")) {
+                    files.insert(PathBuf::from(line), package.to_owned());
+                }
+            }
+            log::debug!("{} /etc files are owned by dpkg packages", files.len());
+            files
+        })
+    }
+
+    fn rpm_owner(&self, path: &Path) -> Option<String> {
+        let output = Command::new("rpm")
+            .arg("--root")
+            .arg(&self.root)
+            .args(["--query", "--file", "--queryformat", "%{NAME}\\n"])
+            .arg(self.inside(path))
+            .output()
+            .inspect_err(|e| log::debug!("Cannot run rpm: {e}"))
+            .ok()?;
+        // rpm exits with an error when no package owns the file.
+        output
+            .status
+            .success()
+            .then(|| {
+                String::from_utf8_lossy(&output.stdout)
+                    .lines()
+                    .next()
+                    .map(str::to_owned)
+            })
+            .flatten()
+    }
+
+    fn run_dpkg_divert<const N: usize>(&self, args: [OsString; N]) -> anyhow::Result<()> {
+        let output = Command::new("dpkg-divert")
+            .arg("--root")
+            .arg(&self.root)
+            .args(args)
+            .output()
+            .context("Failed to execute dpkg-divert")?;
+        if !output.status.success() {
+            anyhow::bail!(
+                "dpkg-divert exited with status {}: {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        Ok(())
+    }
+}
+
+impl PackageDatabase for RootPackages {
+    fn owning_package(&self, path: &Path) -> Option<(PackageManager, String)> {
+        if in_root(&self.root, DPKG_INFO_DIR).is_dir() {
+            if let Some(package) = self.dpkg_files().get(&self.inside(path)) {
+                return Some((PackageManager::Dpkg, package.clone()));
+            }
+        }
+        if RPM_DB_DIRS
+            .iter()
+            .any(|dir| in_root(&self.root, dir).is_dir())
+        {
+            return self
+                .rpm_owner(path)
+                .map(|package| (PackageManager::Rpm, package));
+        }
+        None
+    }
+
+    fn add_diversion(&self, path: &Path, backup: &Path) -> anyhow::Result<()> {
+        log::info!(
+            "Diverting {} to {} for package upgrades",
+            path.display(),
+            backup.display()
+        );
+        // We rename the file ourselves, package upgrades then update the backup.
+        self.run_dpkg_divert([
+            "--local".into(),
+            "--no-rename".into(),
+            "--divert".into(),
+            self.inside(backup).into(),
+            "--add".into(),
+            self.inside(path).into(),
+        ])
+    }
+
+    fn remove_diversion(&self, path: &Path) -> anyhow::Result<()> {
+        log::info!("Removing the dpkg diversion of {}", path.display());
+        self.run_dpkg_divert([
+            "--local".into(),
+            "--no-rename".into(),
+            "--remove".into(),
+            self.inside(path).into(),
+        ])
+    }
+}
+
+/// Apply `policy` to `path`, which is about to be replaced and backed up to `backup`.
+///
+/// Returns whether a diversion was registered.
+pub fn handle_conflict(
+    packages: &dyn PackageDatabase,
+    path: &Path,
+    backup: &Path,
+    policy: PackageConflictPolicy,
+) -> anyhow::Result<bool> {
+    let Some((manager, package)) = packages.owning_package(path) else {
+        return Ok(false);
+    };
+    match (policy, manager) {
+        (PackageConflictPolicy::Fail, _) => anyhow::bail!(
+            "{} belongs to the {package} package, refusing to replace it. Set onPackageConflict to \"warn\" or \"divert\" to replace it anyway.",
+            path.display()
+        ),
+        (PackageConflictPolicy::Divert, PackageManager::Dpkg) => {
+            packages.add_diversion(path, backup)?;
+            Ok(true)
+        }
+        (PackageConflictPolicy::Divert, PackageManager::Rpm) => {
+            log::warn!(
+                "{} belongs to the {package} package, and diversions are only supported with dpkg. The next upgrade of {package} may conflict with system-manager.",
+                path.display()
+            );
+            Ok(false)
+        }
+        (PackageConflictPolicy::Warn, _) => {
+            log::warn!(
+                "{} belongs to the {package} package. The next upgrade of {package} may conflict with system-manager, set onPackageConflict to \"divert\" to prevent it.",
+                path.display()
+            );
+            Ok(false)
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// Package ownership given upfront, diversions recorded instead of registered.
+    #[derive(Default)]
+    pub(crate) struct FakePackages {
+        pub owners: HashMap<PathBuf, (PackageManager, String)>,
+        pub diversions: Mutex<Vec<PathBuf>>,
+    }
+
+    impl FakePackages {
+        pub fn owning(mut self, path: &Path, manager: PackageManager) -> FakePackages {
+            self.owners
+                .insert(path.to_owned(), (manager, "base-files".to_owned()));
+            self
+        }
+    }
+
+    impl PackageDatabase for FakePackages {
+        fn owning_package(&self, path: &Path) -> Option<(PackageManager, String)> {
+            self.owners.get(path).cloned()
+        }
+
+        fn add_diversion(&self, path: &Path, _backup: &Path) -> anyhow::Result<()> {
+            self.diversions.lock().unwrap().push(path.to_owned());
+            Ok(())
+        }
+
+        fn remove_diversion(&self, path: &Path) -> anyhow::Result<()> {
+            self.diversions.lock().unwrap().retain(|p| p != path);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn conflicts_are_handled_according_to_the_policy() {
+        let dpkg_file = Path::new("/etc/issue");
+        let rpm_file = Path::new("/etc/motd");
+        let unowned = Path::new("/etc/nginx.conf");
+        let packages = FakePackages::default()
+            .owning(dpkg_file, PackageManager::Dpkg)
+            .owning(rpm_file, PackageManager::Rpm);
+        let backup = Path::new("/etc/backup");
+        let handle = |path, policy| handle_conflict(&packages, path, backup, policy);
+
+        assert!(handle(dpkg_file, PackageConflictPolicy::Fail).is_err());
+        assert!(handle(rpm_file, PackageConflictPolicy::Fail).is_err());
+        assert!(!handle(unowned, PackageConflictPolicy::Fail).unwrap());
+        assert!(!handle(dpkg_file, PackageConflictPolicy::Warn).unwrap());
+        assert!(!handle(rpm_file, PackageConflictPolicy::Divert).unwrap());
+        assert!(!handle(unowned, PackageConflictPolicy::Divert).unwrap());
+        assert!(packages.diversions.lock().unwrap().is_empty());
+
+        assert!(handle(dpkg_file, PackageConflictPolicy::Divert).unwrap());
+        assert_eq!(*packages.diversions.lock().unwrap(), [dpkg_file]);
+    }
+
+    #[test]
+    fn paths_are_looked_up_inside_the_root() {
+        let root = tempfile::tempdir().unwrap();
+        let info = in_root(root.path(), DPKG_INFO_DIR);
+        fs::create_dir_all(&info).unwrap();
+        fs::write(info.join("base-files:amd64.list"), "/etc\n/etc/issue\n").unwrap();
+        let packages = RootPackages::new(root.path());
+
+        assert_eq!(
+            packages.owning_package(&root.path().join("etc/issue")),
+            Some((PackageManager::Dpkg, "base-files".to_owned()))
+        );
+        assert_eq!(packages.owning_package(Path::new("/etc/hostname")), None);
+    }
+
+    #[test]
+    fn policy_is_read_from_the_nix_option() {
+        let policies: Vec<PackageConflictPolicy> =
+            serde_json::from_str(r#"["warn", "fail", "divert"]"#).unwrap();
+        assert_eq!(
+            policies,
+            [
+                PackageConflictPolicy::Warn,
+                PackageConflictPolicy::Fail,
+                PackageConflictPolicy::Divert
+            ]
+        );
+    }
+}
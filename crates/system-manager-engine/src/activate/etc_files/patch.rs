@@ -0,0 +1,817 @@
+//! Key-level patching of distro-owned structured files in /etc.
+//!
+//! Instead of replacing a file, a patch entry sets a handful of keys in it
+//! (e.g. `[Journal] SystemMaxUse=` in `/etc/systemd/journald.conf`). The value
+//! each key had before we touched it is recorded in the state, so that it can be
+//! put back when the key or the whole entry disappears from the configuration,
+//! or when system-manager gets deactivated.
+
+use anyhow::{anyhow, Context};
+use im::HashMap;
+use ini::{Ini, ParseOption};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+
+use super::dir_fd::{Dir, EntryKind, Xattrs};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum PatchFormat {
+    /// systemd-style INI files, settings are `{ section = { key = value; }; }`
+    Ini,
+    /// JSON documents, settings are nested objects, leaves are patched
+    Json,
+    /// TOML documents, settings are nested tables, leaves are patched
+    Toml,
+    /// Flat `KEY=value` files such as `/etc/default/grub`
+    KeyValue,
+}
+
+/// The patch definition of an etc entry, as generated by the Nix module.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EtcPatch {
+    pub format: PatchFormat,
+    pub settings: serde_json::Map<String, Value>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PatchedKey {
+    pub path: Vec<String>,
+    /// Value of the key before system-manager patched it, `None` if it was absent.
+    pub original: Option<Value>,
+    /// Value written by system-manager.
+    pub value: Value,
+    /// How many leading keys of `path` name tables that existed before system-manager
+    /// patched the key, the deeper ones were created along with it. Only set for JSON and
+    /// TOML keys that were absent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_parents_from: Option<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PatchedFile {
+    pub format: PatchFormat,
+    /// Whether the file did not exist before system-manager patched it.
+    #[serde(default)]
+    pub created: bool,
+    pub keys: Vec<PatchedKey>,
+}
+
+pub type PatchedFiles = HashMap<PathBuf, PatchedFile>;
+
+/// Apply all the patch entries and revert the keys and files that are no longer
+/// part of the configuration.
+///
+/// Failing to patch a single file does not stop the activation, in that case we keep
+/// whatever we knew about the file from the old state.
+pub fn activate(patches: Vec<(PathBuf, EtcPatch)>, old_patched: &PatchedFiles) -> PatchedFiles {
+    let mut new_patched = PatchedFiles::new();
+
+    for (target, patch) in patches {
+        match patch_file(&target, &patch, old_patched.get(&target)) {
+            Ok(patched) => {
+                new_patched.insert(target, patched);
+            }
+            Err(e) => {
+                log::warn!("Can't patch {}: {e:?}", target.display());
+                if let Some(old) = old_patched.get(&target) {
+                    new_patched.insert(target, old.clone());
+                }
+            }
+        }
+    }
+
+    for (target, old) in old_patched {
+        if !new_patched.contains_key(target) {
+            if let Err(e) = restore_file(target, old) {
+                log::warn!("Can't restore {}: {e:?}", target.display());
+                new_patched.insert(target.clone(), old.clone());
+            }
+        }
+    }
+
+    new_patched
+}
+
+/// Restore the original values of all patched files.
+///
+/// Returns the files we failed to restore, so that they can be retried later.
+pub fn deactivate(old_patched: PatchedFiles) -> PatchedFiles {
+    old_patched
+        .into_iter()
+        .filter(|(target, patched)| match restore_file(target, patched) {
+            Ok(()) => false,
+            Err(e) => {
+                log::warn!("Can't restore {}: {e:?}", target.display());
+                true
+            }
+        })
+        .collect()
+}
+
+fn patch_file(
+    target: &Path,
+    patch: &EtcPatch,
+    old: Option<&PatchedFile>,
+) -> anyhow::Result<PatchedFile> {
+    let (dir, name) = Dir::open_parent(target, true)?;
+    let exists = dir.kind(&name)?.exists();
+    let mut doc = if exists {
+        Document::read(&dir, &name, patch.format)?
+    } else {
+        log::info!("{} does not exist, creating it", target.display());
+        Document::empty(patch.format)
+    };
+    let old = old.filter(|old| old.format == patch.format);
+    // Leave the file untouched when it already has the desired content, so that watchers
+    // of unchanged files don't fire on every switch.
+    let mut changed = !exists;
+
+    let desired = flatten_settings(patch.format, &patch.settings)?;
+    let mut keys = Vec::with_capacity(desired.len());
+    for (path, value) in desired {
+        let current = doc.get(&path)?;
+        let previous = old.and_then(|old| old.keys.iter().find(|k| k.path == path));
+        let original = match previous {
+            Some(previous) => {
+                if current.as_ref() != Some(&previous.value) {
+                    log::warn!(
+                        "{}: {} was changed outside of system-manager (expected {}, found {}), overriding it",
+                        target.display(),
+                        path.join("."),
+                        previous.value,
+                        current.as_ref().map_or("nothing".to_owned(), Value::to_string)
+                    );
+                }
+                previous.original.clone()
+            }
+            None => current.clone(),
+        };
+        let created_parents_from = match previous {
+            Some(previous) => previous.created_parents_from,
+            None if original.is_none() => {
+                let recorded = keys.iter().chain(old.iter().flat_map(|old| &old.keys));
+                created_parents(&doc, &path, recorded)?
+            }
+            None => None,
+        };
+        if current.as_ref() != Some(&value) {
+            log::debug!("{}: setting {}", target.display(), path.join("."));
+            doc.set(&path, &value)?;
+            changed = true;
+        }
+        keys.push(PatchedKey {
+            path,
+            original,
+            value,
+            created_parents_from,
+        });
+    }
+
+    // Keys we patched previously but that are no longer part of the configuration.
+    for previous in old.iter().flat_map(|old| &old.keys) {
+        if !keys.iter().any(|k| k.path == previous.path) {
+            log::debug!(
+                "{}: restoring {}",
+                target.display(),
+                previous.path.join(".")
+            );
+            doc.restore(previous)?;
+            changed = true;
+        }
+    }
+
+    if changed {
+        doc.write(&dir, &name)?;
+    }
+    Ok(PatchedFile {
+        format: patch.format,
+        created: old.map_or(!exists, |old| old.created),
+        keys,
+    })
+}
+
+fn restore_file(target: &Path, patched: &PatchedFile) -> anyhow::Result<()> {
+    let (dir, name) = Dir::open_parent(target, false)?;
+    if dir.kind(&name)? == EntryKind::Missing {
+        log::warn!(
+            "{} was removed outside of system-manager, nothing to restore",
+            target.display()
+        );
+        return Ok(());
+    }
+    if patched.created {
+        log::info!("Removing patched file {}", target.display());
+        dir.unlink(&name)?;
+        return Ok(());
+    }
+    log::info!("Restoring original values in {}", target.display());
+    let mut doc = Document::read(&dir, &name, patched.format)?;
+    let mut changed = false;
+    for key in &patched.keys {
+        let current = doc.get(&key.path)?;
+        if current.as_ref() != Some(&key.value) {
+            log::warn!(
+                "{}: {} was changed outside of system-manager, leaving it untouched",
+                target.display(),
+                key.path.join(".")
+            );
+            continue;
+        }
+        doc.restore(key)?;
+        changed = true;
+    }
+    if changed {
+        doc.write(&dir, &name)?;
+    }
+    Ok(())
+}
+
+/// Turn the nested settings into a list of (key path, value) pairs.
+///
+/// INI files are always two levels deep (section, key) and key=value files are flat.
+/// JSON and TOML settings are walked down to their non-object leaves.
+fn flatten_settings(
+    format: PatchFormat,
+    settings: &serde_json::Map<String, Value>,
+) -> anyhow::Result<Vec<(Vec<String>, Value)>> {
+    fn walk(
+        prefix: &[String],
+        settings: &serde_json::Map<String, Value>,
+        out: &mut Vec<(Vec<String>, Value)>,
+    ) {
+        for (key, value) in settings {
+            let mut path = prefix.to_vec();
+            path.push(key.clone());
+            match value {
+                Value::Object(nested) => walk(&path, nested, out),
+                leaf => out.push((path, leaf.clone())),
+            }
+        }
+    }
+
+    let mut out = Vec::new();
+    walk(&[], settings, &mut out);
+
+    let expected_depth = match format {
+        PatchFormat::Ini => Some(2),
+        PatchFormat::KeyValue => Some(1),
+        PatchFormat::Json | PatchFormat::Toml => None,
+    };
+    if let Some(depth) = expected_depth {
+        if let Some((path, _)) = out.iter().find(|(path, _)| path.len() != depth) {
+            anyhow::bail!(
+                "Invalid {:?} patch key {}: expected {} level(s) of nesting",
+                format,
+                path.join("."),
+                depth
+            );
+        }
+        // Line-based files only know about strings, compare them as such.
+        for (_, value) in out.iter_mut() {
+            *value = Value::String(value_to_string(value));
+        }
+    }
+    Ok(out)
+}
+
+fn value_to_string(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+enum Document {
+    /// INI and key=value files are edited line by line to keep comments and layout.
+    Lines(Vec<String>),
+    Json(Value),
+    Toml(toml_edit::DocumentMut),
+}
+
+impl Document {
+    fn empty(format: PatchFormat) -> Self {
+        match format {
+            PatchFormat::Ini | PatchFormat::KeyValue => Document::Lines(Vec::new()),
+            PatchFormat::Json => Document::Json(Value::Object(Default::default())),
+            PatchFormat::Toml => Document::Toml(toml_edit::DocumentMut::new()),
+        }
+    }
+
+    fn read(dir: &Dir, name: &OsStr, format: PatchFormat) -> anyhow::Result<Self> {
+        let path = dir.path().join(name);
+        let content = String::from_utf8(dir.read(name)?)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        Self::parse(&content, format).with_context(|| format!("Failed to parse {}", path.display()))
+    }
+
+    fn parse(content: &str, format: PatchFormat) -> anyhow::Result<Self> {
+        Ok(match format {
+            PatchFormat::Ini | PatchFormat::KeyValue => {
+                Document::Lines(content.lines().map(str::to_owned).collect())
+            }
+            PatchFormat::Json => Document::Json(serde_json::from_str(content)?),
+            PatchFormat::Toml => Document::Toml(content.parse()?),
+        })
+    }
+
+    fn render(&self) -> anyhow::Result<String> {
+        Ok(match self {
+            Document::Lines(lines) => {
+                let mut out = lines.join("\n");
+                out.push('\n');
+                out
+            }
+            Document::Json(value) => {
+                let mut out = serde_json::to_string_pretty(value)?;
+                out.push('\n');
+                out
+            }
+            Document::Toml(doc) => doc.to_string(),
+        })
+    }
+
+    fn write(&self, dir: &Dir, name: &OsStr) -> anyhow::Result<()> {
+        // Keep the permissions, ownership, ACL and security labels of the distro file.
+        let ((mode, uid, gid), xattrs) = match dir.kind(name)? {
+            EntryKind::File => (dir.mode_and_owner(name)?, dir.xattrs(name)?),
+            _ => ((0o644, 0, 0), Xattrs::new()),
+        };
+        dir.write_file(name, self.render()?.as_bytes(), mode, uid, gid, &xattrs)
+    }
+
+    fn get(&self, path: &[String]) -> anyhow::Result<Option<Value>> {
+        match self {
+            Document::Lines(lines) => {
+                let (section, key) = split_line_path(path)?;
+                let ini = Ini::load_from_str_opt(
+                    &lines.join("\n"),
+                    ParseOption {
+                        enabled_quote: false,
+                        enabled_indented_mutiline_value: false,
+                        enabled_preserve_key_leading_whitespace: false,
+                        enabled_escape: false,
+                    },
+                )?;
+                Ok(ini
+                    .section(section)
+                    .and_then(|s| s.get_all(key).last())
+                    .map(|v| Value::String(v.to_owned())))
+            }
+            Document::Json(value) => Ok(path.iter().try_fold(value, |v, key| v.get(key)).cloned()),
+            Document::Toml(doc) => {
+                let mut item = doc.as_item();
+                for key in path {
+                    match item.get(key) {
+                        Some(i) => item = i,
+                        None => return Ok(None),
+                    }
+                }
+                Ok(toml_to_json(item))
+            }
+        }
+    }
+
+    fn set(&mut self, path: &[String], value: &Value) -> anyhow::Result<()> {
+        match self {
+            Document::Lines(lines) => {
+                let (section, key) = split_line_path(path)?;
+                set_line(lines, section, key, &value_to_string(value));
+            }
+            Document::Json(root) => {
+                let (last, parents) = path.split_last().ok_or(anyhow!("Empty key path"))?;
+                let mut current = root;
+                for key in parents {
+                    let object = current
+                        .as_object_mut()
+                        .ok_or_else(|| anyhow!("{} is not an object", path.join(".")))?;
+                    current = object
+                        .entry(key.clone())
+                        .or_insert_with(|| Value::Object(Default::default()));
+                }
+                current
+                    .as_object_mut()
+                    .ok_or_else(|| anyhow!("{} is not an object", path.join(".")))?
+                    .insert(last.clone(), value.clone());
+            }
+            Document::Toml(doc) => {
+                let (last, parents) = path.split_last().ok_or(anyhow!("Empty key path"))?;
+                let mut table: &mut dyn toml_edit::TableLike = doc.as_table_mut();
+                for key in parents {
+                    table = table
+                        .entry(key)
+                        .or_insert(toml_edit::table())
+                        .as_table_like_mut()
+                        .ok_or_else(|| anyhow!("{} is not a table", path.join(".")))?;
+                }
+                table.insert(last, toml_edit::Item::Value(json_to_toml(value)?));
+            }
+        }
+        Ok(())
+    }
+
+    fn remove(&mut self, path: &[String]) -> anyhow::Result<()> {
+        match self {
+            Document::Lines(lines) => {
+                let (section, key) = split_line_path(path)?;
+                let matching = find_key_lines(lines, section, key);
+                for idx in matching.into_iter().rev() {
+                    lines.remove(idx);
+                }
+            }
+            Document::Json(root) => {
+                let (last, parents) = path.split_last().ok_or(anyhow!("Empty key path"))?;
+                if let Some(Value::Object(parent)) =
+                    parents.iter().try_fold(&mut *root, |v, key| v.get_mut(key))
+                {
+                    parent.remove(last);
+                }
+            }
+            Document::Toml(doc) => {
+                let (last, parents) = path.split_last().ok_or(anyhow!("Empty key path"))?;
+                let mut table: &mut dyn toml_edit::TableLike = doc.as_table_mut();
+                for key in parents {
+                    match table.get_mut(key).and_then(|i| i.as_table_like_mut()) {
+                        Some(t) => table = t,
+                        None => return Ok(()),
+                    }
+                }
+                table.remove(last);
+            }
+        }
+        Ok(())
+    }
+
+    /// Put back the original value of a patched key, or remove it along with the tables
+    /// created for it once they are empty.
+    fn restore(&mut self, key: &PatchedKey) -> anyhow::Result<()> {
+        if let Some(value) = &key.original {
+            return self.set(&key.path, value);
+        }
+        self.remove(&key.path)?;
+        let Some(from) = key.created_parents_from else {
+            return Ok(());
+        };
+        for depth in (from.max(1)..key.path.len()).rev() {
+            let parent = &key.path[..depth];
+            match self.get(parent)? {
+                Some(Value::Object(table)) if table.is_empty() => self.remove(parent)?,
+                _ => break,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The depth from which the parents of the absent key `path` don't exist yet in `doc`, or
+/// were created by system-manager for one of the `recorded` keys.
+fn created_parents<'a>(
+    doc: &Document,
+    path: &[String],
+    recorded: impl Iterator<Item = &'a PatchedKey>,
+) -> anyhow::Result<Option<usize>> {
+    if matches!(doc, Document::Lines(_)) {
+        return Ok(None);
+    }
+    let mut from = None;
+    for depth in 1..path.len() {
+        if doc.get(&path[..depth])?.is_none() {
+            from = Some(depth);
+            break;
+        }
+    }
+    for key in recorded {
+        if let Some(created) = key.created_parents_from {
+            if created < path.len() && key.path[..created] == path[..created] {
+                from = Some(from.map_or(created, |from: usize| from.min(created)));
+            }
+        }
+    }
+    Ok(from)
+}
+
+/// Split a key path of a line-based document into its section and key.
+/// Key=value files have no section, they map to the INI general section.
+fn split_line_path(path: &[String]) -> anyhow::Result<(Option<&str>, &str)> {
+    match path {
+        [key] => Ok((None, key)),
+        [section, key] => Ok((Some(section), key)),
+        _ => Err(anyhow!("Invalid key path {}", path.join("."))),
+    }
+}
+
+fn parse_section_header(line: &str) -> Option<&str> {
+    line.trim()
+        .strip_prefix('[')
+        .and_then(|l| l.strip_suffix(']'))
+        .map(str::trim)
+}
+
+fn line_key(line: &str) -> Option<&str> {
+    let trimmed = line.trim_start();
+    if trimmed.starts_with('#') || trimmed.starts_with(';') {
+        return None;
+    }
+    trimmed.split_once('=').map(|(k, _)| k.trim())
+}
+
+/// Indices of the non-commented lines setting `key` in `section`.
+fn find_key_lines(lines: &[String], section: Option<&str>, key: &str) -> Vec<usize> {
+    let mut current_section = None;
+    let mut found = Vec::new();
+    for (idx, line) in lines.iter().enumerate() {
+        if let Some(header) = parse_section_header(line) {
+            current_section = Some(header);
+        } else if current_section == section && line_key(line) == Some(key) {
+            found.push(idx);
+        }
+    }
+    found
+}
+
+fn set_line(lines: &mut Vec<String>, section: Option<&str>, key: &str, value: &str) {
+    let new_line = format!("{key}={value}");
+    let matching = find_key_lines(lines, section, key);
+    if let Some(&last) = matching.last() {
+        // Only the last definition counts for single-value keys, the earlier ones of
+        // multi-value keys like `Environment=` are kept.
+        lines[last] = new_line;
+        return;
+    }
+
+    match section {
+        None => {
+            // Keys of the general section must come before the first section header.
+            let idx = lines
+                .iter()
+                .position(|l| parse_section_header(l).is_some())
+                .unwrap_or(lines.len());
+            lines.insert(idx, new_line);
+        }
+        Some(section) => {
+            match lines
+                .iter()
+                .position(|l| parse_section_header(l) == Some(section))
+            {
+                Some(header) => lines.insert(header + 1, new_line),
+                None => {
+                    if lines.last().is_some_and(|l| !l.trim().is_empty()) {
+                        lines.push(String::new());
+                    }
+                    lines.push(format!("[{section}]"));
+                    lines.push(new_line);
+                }
+            }
+        }
+    }
+}
+
+fn toml_to_json(item: &toml_edit::Item) -> Option<Value> {
+    fn value_to_json(value: &toml_edit::Value) -> Value {
+        match value {
+            toml_edit::Value::String(s) => Value::String(s.value().clone()),
+            toml_edit::Value::Integer(i) => Value::from(*i.value()),
+            toml_edit::Value::Float(f) => Value::from(*f.value()),
+            toml_edit::Value::Boolean(b) => Value::Bool(*b.value()),
+            toml_edit::Value::Datetime(d) => Value::String(d.value().to_string()),
+            toml_edit::Value::Array(a) => Value::Array(a.iter().map(value_to_json).collect()),
+            toml_edit::Value::InlineTable(t) => Value::Object(
+                t.iter()
+                    .map(|(k, v)| (k.to_owned(), value_to_json(v)))
+                    .collect(),
+            ),
+        }
+    }
+    match item {
+        toml_edit::Item::None => None,
+        toml_edit::Item::Value(v) => Some(value_to_json(v)),
+        toml_edit::Item::Table(t) => Some(Value::Object(
+            t.iter()
+                .filter_map(|(k, v)| toml_to_json(v).map(|v| (k.to_owned(), v)))
+                .collect(),
+        )),
+        toml_edit::Item::ArrayOfTables(a) => Some(Value::Array(
+            a.iter()
+                .map(|t| toml_to_json(&toml_edit::Item::Table(t.clone())).unwrap_or(Value::Null))
+                .collect(),
+        )),
+    }
+}
+
+fn json_to_toml(value: &Value) -> anyhow::Result<toml_edit::Value> {
+    Ok(match value {
+        Value::Null => anyhow::bail!("TOML has no null value"),
+        Value::Bool(b) => (*b).into(),
+        Value::Number(n) => match n.as_i64() {
+            Some(i) => i.into(),
+            None => n
+                .as_f64()
+                .ok_or_else(|| anyhow!("Unsupported number {n}"))?
+                .into(),
+        },
+        Value::String(s) => s.as_str().into(),
+        Value::Array(values) => values
+            .iter()
+            .map(json_to_toml)
+            .collect::<anyhow::Result<toml_edit::Array>>()?
+            .into(),
+        Value::Object(map) => {
+            let mut table = toml_edit::InlineTable::new();
+            for (k, v) in map {
+                table.insert(k, json_to_toml(v)?);
+            }
+            table.into()
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn patch(format: PatchFormat, settings: Value) -> EtcPatch {
+        EtcPatch {
+            format,
+            settings: settings.as_object().unwrap().clone(),
+        }
+    }
+
+    #[test]
+    fn ini_patch_keeps_comments_and_restores_original() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("journald.conf");
+        let original = "# See journald.conf(5)\n[Journal]\n#SystemMaxUse=\nStorage=auto\n";
+        fs::write(&target, original).unwrap();
+
+        let entry = patch(
+            PatchFormat::Ini,
+            serde_json::json!({ "Journal": { "SystemMaxUse": "1G", "Storage": "persistent" } }),
+        );
+        let patched = activate(vec![(target.clone(), entry)], &PatchedFiles::new());
+        assert_eq!(
+            fs::read_to_string(&target).unwrap(),
+            "# See journald.conf(5)\n[Journal]\nSystemMaxUse=1G\n#SystemMaxUse=\nStorage=persistent\n"
+        );
+
+        let file = &patched[&target];
+        assert!(!file.created);
+        assert_eq!(file.keys[0].original, None);
+        assert_eq!(file.keys[1].original, Some(Value::from("auto")));
+
+        assert!(deactivate(patched).is_empty());
+        assert_eq!(fs::read_to_string(&target).unwrap(), original);
+    }
+
+    #[test]
+    fn dropped_keys_are_restored_on_switch() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("daemon.json");
+        fs::write(&target, r#"{"log-driver": "json-file"}"#).unwrap();
+
+        let first = patch(
+            PatchFormat::Json,
+            serde_json::json!({ "log-driver": "journald", "log-opts": { "tag": "docker" } }),
+        );
+        let state = activate(vec![(target.clone(), first)], &PatchedFiles::new());
+
+        let second = patch(
+            PatchFormat::Json,
+            serde_json::json!({ "log-driver": "journald" }),
+        );
+        let state = activate(vec![(target.clone(), second)], &state);
+        let content: Value = serde_json::from_str(&fs::read_to_string(&target).unwrap()).unwrap();
+        assert_eq!(content, serde_json::json!({ "log-driver": "journald" }));
+
+        let state = activate(vec![], &state);
+        assert!(state.is_empty());
+        let content: Value = serde_json::from_str(&fs::read_to_string(&target).unwrap()).unwrap();
+        assert_eq!(content["log-driver"], "json-file");
+    }
+
+    #[test]
+    fn files_already_patched_are_left_untouched() {
+        use std::os::unix::fs::MetadataExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("daemon.json");
+        let original = r#"{"log-driver":   "journald"}"#;
+        fs::write(&target, original).unwrap();
+        let inode = fs::metadata(&target).unwrap().ino();
+
+        let entry = || {
+            patch(
+                PatchFormat::Json,
+                serde_json::json!({ "log-driver": "journald" }),
+            )
+        };
+        let state = activate(vec![(target.clone(), entry())], &PatchedFiles::new());
+        let state = activate(vec![(target.clone(), entry())], &state);
+        assert_eq!(state[&target].keys.len(), 1);
+        assert_eq!(fs::read_to_string(&target).unwrap(), original);
+        assert_eq!(fs::metadata(&target).unwrap().ino(), inode);
+    }
+
+    #[test]
+    fn toml_and_key_value_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let toml_target = dir.path().join("config.toml");
+        fs::write(
+            &toml_target,
+            "# managed by the distro\n[server]\nport = 80\n",
+        )
+        .unwrap();
+        let grub = dir.path().join("grub");
+        fs::write(&grub, "GRUB_DEFAULT=0\nGRUB_TIMEOUT=5\n").unwrap();
+
+        let state = activate(
+            vec![
+                (
+                    toml_target.clone(),
+                    patch(
+                        PatchFormat::Toml,
+                        serde_json::json!({ "server": { "port": 8080, "tls": true } }),
+                    ),
+                ),
+                (
+                    grub.clone(),
+                    patch(
+                        PatchFormat::KeyValue,
+                        serde_json::json!({ "GRUB_TIMEOUT": 1 }),
+                    ),
+                ),
+            ],
+            &PatchedFiles::new(),
+        );
+        assert_eq!(
+            fs::read_to_string(&toml_target).unwrap(),
+            "# managed by the distro\n[server]\nport = 8080\ntls = true\n"
+        );
+        assert_eq!(
+            fs::read_to_string(&grub).unwrap(),
+            "GRUB_DEFAULT=0\nGRUB_TIMEOUT=1\n"
+        );
+
+        assert!(deactivate(state).is_empty());
+        assert_eq!(
+            fs::read_to_string(&toml_target).unwrap(),
+            "# managed by the distro\n[server]\nport = 80\n"
+        );
+        assert_eq!(
+            fs::read_to_string(&grub).unwrap(),
+            "GRUB_DEFAULT=0\nGRUB_TIMEOUT=5\n"
+        );
+    }
+
+    #[test]
+    fn restoring_leaves_no_trace_of_the_patch() {
+        let dir = tempfile::tempdir().unwrap();
+        let ini = dir.path().join("app.service");
+        let ini_original = "[Service]\nEnvironment=A=1\nEnvironment=B=2\n";
+        fs::write(&ini, ini_original).unwrap();
+        let toml = dir.path().join("config.toml");
+        let toml_original = "[server]\nport = 80\n";
+        fs::write(&toml, toml_original).unwrap();
+
+        let first = vec![
+            (
+                ini.clone(),
+                patch(
+                    PatchFormat::Ini,
+                    serde_json::json!({ "Service": { "Environment": "C=3" } }),
+                ),
+            ),
+            (
+                toml.clone(),
+                patch(
+                    PatchFormat::Toml,
+                    serde_json::json!({ "log": { "file": { "path": "/var/log/app" } } }),
+                ),
+            ),
+        ];
+        let state = activate(first, &PatchedFiles::new());
+        assert_eq!(
+            fs::read_to_string(&ini).unwrap(),
+            "[Service]\nEnvironment=A=1\nEnvironment=C=3\n"
+        );
+
+        // A key added later under the tables created by the first generation.
+        let second = vec![(
+            toml.clone(),
+            patch(
+                PatchFormat::Toml,
+                serde_json::json!({ "log": { "file": { "path": "/var/log/app" }, "level": "debug" } }),
+            ),
+        )];
+        let state = activate(second, &state);
+
+        assert!(deactivate(state).is_empty());
+        assert_eq!(fs::read_to_string(&ini).unwrap(), ini_original);
+        assert_eq!(fs::read_to_string(&toml).unwrap(), toml_original);
+    }
+}
@@ -0,0 +1,147 @@
+//! Activation-time materialisation of secrets in copy-mode etc files.
+//!
+//! The store copy of such a file contains placeholders like
+//! `@secret:/run/secrets/db_password@`, which get replaced by the content of the
+//! referenced file while the entry is copied to /etc. Only root-only files can be
+//! referenced, and the secret values never end up in the logs or in the state:
+//! we only record where they came from and when these sources were last modified.
+
+use anyhow::Context;
+use im::HashMap;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::Read;
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+static PLACEHOLDER_REGEX: OnceLock<regex::Regex> = OnceLock::new();
+
+fn get_placeholder_regex() -> &'static regex::Regex {
+    PLACEHOLDER_REGEX
+        .get_or_init(|| regex::Regex::new(r"@secret:(/[^@\s]+)@").expect("could not compile regex"))
+}
+
+/// Where a secret came from, and which version of the file was used.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SecretSource {
+    pub path: PathBuf,
+    pub inode: u64,
+    pub modified: i64,
+    pub modified_nsec: i64,
+}
+
+/// An etc file rendered from secrets, as recorded in the state.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SecretDerivedFile {
+    pub sources: Vec<SecretSource>,
+}
+
+pub type SecretDerivedFiles = HashMap<PathBuf, SecretDerivedFile>;
+
+/// Replace all the secret placeholders in `template`.
+///
+/// Errors only ever mention the secret paths, never their content.
+pub fn render(template: &str) -> anyhow::Result<(String, SecretDerivedFile)> {
+    let mut sources: Vec<SecretSource> = Vec::new();
+    let mut rendered = String::with_capacity(template.len());
+    let mut last = 0;
+    for captures in get_placeholder_regex().captures_iter(template) {
+        let placeholder = captures.get(0).expect("capture 0 always exists");
+        let path = Path::new(&captures[1]);
+        let (value, source) = read_secret(path)?;
+        rendered.push_str(&template[last..placeholder.start()]);
+        rendered.push_str(&value);
+        last = placeholder.end();
+        if !sources.iter().any(|s| s.path == source.path) {
+            sources.push(source);
+        }
+    }
+    rendered.push_str(&template[last..]);
+    Ok((rendered, SecretDerivedFile { sources }))
+}
+
+/// Read the secret at `path`, which must be a regular file owned by the user we run as
+/// (root) and not accessible to other users.
+///
+/// The checks and the read go through the same file descriptor, opened without following
+/// a symlink in place of the file, so the file can't be swapped in between.
+fn read_secret(path: &Path) -> anyhow::Result<(String, SecretSource)> {
+    let mut file = fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NOFOLLOW | libc::O_CLOEXEC)
+        .open(path)
+        .with_context(|| format!("Cannot read secret {}", path.display()))?;
+    let metadata = file
+        .metadata()
+        .with_context(|| format!("Cannot read secret {}", path.display()))?;
+    if !metadata.is_file()
+        || metadata.uid() != nix::unistd::geteuid().as_raw()
+        || metadata.mode() & 0o077 != 0
+    {
+        anyhow::bail!(
+            "Refusing to use secret {}: it must be a file owned by root and not accessible to other users",
+            path.display()
+        );
+    }
+    let mut value = String::new();
+    file.read_to_string(&mut value)
+        .with_context(|| format!("Cannot read secret {}", path.display()))?;
+    if value.ends_with('\n') {
+        value.pop();
+    }
+    let source = SecretSource {
+        path: path.to_owned(),
+        inode: metadata.ino(),
+        modified: metadata.mtime(),
+        modified_nsec: metadata.mtime_nsec(),
+    };
+    Ok((value, source))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn templates_without_placeholders_are_untouched() {
+        let (rendered, derived) = render("user = app\npassword = @secret:\n").unwrap();
+        assert_eq!(rendered, "user = app\npassword = @secret:\n");
+        assert!(derived.sources.is_empty());
+    }
+
+    #[test]
+    fn readable_secrets_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let secret = dir.path().join("db_password");
+        fs::write(&secret, "hunter2\n").unwrap();
+        fs::set_permissions(&secret, fs::Permissions::from_mode(0o644)).unwrap();
+
+        let err = render(&format!("password = @secret:{}@\n", secret.display())).unwrap_err();
+        let message = format!("{err:?}");
+        assert!(message.contains("Refusing to use secret"));
+        assert!(!message.contains("hunter2"));
+    }
+
+    #[test]
+    fn private_secrets_are_rendered() {
+        let dir = tempfile::tempdir().unwrap();
+        let secret = dir.path().join("db_password");
+        fs::write(&secret, "hunter2\n").unwrap();
+        fs::set_permissions(&secret, fs::Permissions::from_mode(0o600)).unwrap();
+
+        let template = format!("password = @secret:{}@\n", secret.display());
+        let (rendered, derived) = render(&template).unwrap();
+        assert_eq!(rendered, "password = hunter2\n");
+        assert_eq!(derived.sources.len(), 1);
+        assert_eq!(derived.sources[0].path, secret);
+
+        // A symlink planted in place of the secret is not followed.
+        let link = dir.path().join("link");
+        std::os::unix::fs::symlink(&secret, &link).unwrap();
+        assert!(render(&format!("password = @secret:{}@\n", link.display())).is_err());
+    }
+}
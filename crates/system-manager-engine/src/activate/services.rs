@@ -1,17 +1,131 @@
+mod dropins;
+mod etc_triggers;
+mod install;
+mod journal;
+mod masks;
+mod ordering;
+mod settle;
+mod templates;
+mod timeouts;
+mod unit_actions;
+pub mod user_units;
+
 use anyhow::Context;
 use im::{HashMap, HashSet};
 use serde::{Deserialize, Serialize};
-use std::path::{self, Path, PathBuf};
-use std::time::Duration;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
 use std::{fs, io};
 
 use super::ActivationResult;
-use crate::activate::ActivationError;
-use crate::unit_info::{parse_systemd_bool, parse_unit};
-use crate::{create_link, etc_dir, systemd, StorePath};
+use crate::activate::{ActivationError, ServiceOptions};
+use crate::unit_info::{parse_unit, UnitInfo};
+use crate::{create_link, etc_dir, in_root, systemd, StorePath};
+use dropins::UnitFingerprints;
+use etc_triggers::EtcTriggers;
+pub use install::EnabledUnits;
+use timeouts::JobTimeouts;
+use unit_actions::UnitAction;
 
 type ServiceActivationResult = ActivationResult<Services>;
 
+/// How the job queued for a unit ended.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UnitOutcome {
+    /// The job queued for the unit: `start`, `stop`, `restart` or `reload`.
+    pub job: String,
+    /// The result reported by systemd: `done`, `failed`, `timeout`, `dependency`,
+    /// `canceled` or `skipped`. `pending` when the job was still running when we stopped
+    /// waiting, `rejected` when systemd refused to queue it.
+    pub result: String,
+    /// The state of pending units when we stopped waiting, as `active state/sub state`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state: Option<String>,
+    /// The wave the job was queued in, counting from 1, when jobs were queued in
+    /// dependency order.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub wave: Option<usize>,
+}
+
+impl UnitOutcome {
+    pub fn succeeded(&self) -> bool {
+        self.result == "done"
+    }
+}
+
+pub type UnitOutcomes = BTreeMap<String, UnitOutcome>;
+
+/// The services of the new generation, along with the outcome of the jobs run for them.
+#[derive(Debug, Clone, Default)]
+pub struct ServicesActivation {
+    pub services: Services,
+    pub outcomes: UnitOutcomes,
+    /// The units that failed or kept restarting after their job finished.
+    pub unsettled: settle::UnsettledUnits,
+    /// The last journal lines of the failed units.
+    pub journals: BTreeMap<String, Vec<String>>,
+    pub enabled: EnabledUnits,
+}
+
+/// What happened to a unit during the activation, for the activation report.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UnitReport {
+    /// The job queued for the unit, if any.
+    #[serde(flatten)]
+    pub outcome: Option<UnitOutcome>,
+    /// Why the unit didn't settle after its job finished.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unsettled: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub journal: Vec<String>,
+}
+
+impl ServicesActivation {
+    /// The units whose job didn't finish successfully, or that didn't settle.
+    pub fn failed_units(&self) -> Vec<&str> {
+        let failed_jobs = self
+            .outcomes
+            .iter()
+            .filter(|(_, outcome)| !outcome.succeeded())
+            .map(|(unit, _)| unit.as_str());
+        let unsettled = self
+            .unsettled
+            .keys()
+            .map(String::as_str)
+            .filter(|unit| self.outcomes.get(*unit).is_none_or(|o| o.succeeded()));
+        failed_jobs.chain(unsettled).collect()
+    }
+
+    /// The units we queued a job for, or that didn't settle.
+    pub fn report(&self) -> BTreeMap<String, UnitReport> {
+        let mut report = outcomes_report(&self.outcomes);
+        for (unit, reason) in &self.unsettled {
+            report.entry(unit.clone()).or_default().unsettled = Some(reason.clone());
+        }
+        for (unit, journal) in &self.journals {
+            report.entry(unit.clone()).or_default().journal = journal.clone();
+        }
+        report
+    }
+}
+
+/// The report of units we only queued jobs for.
+pub fn outcomes_report(outcomes: &UnitOutcomes) -> BTreeMap<String, UnitReport> {
+    outcomes
+        .iter()
+        .map(|(unit, outcome)| {
+            let unit_report = UnitReport {
+                outcome: Some(outcome.clone()),
+                ..UnitReport::default()
+            };
+            (unit.clone(), unit_report)
+        })
+        .collect()
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct ServiceConfig {
@@ -19,6 +133,19 @@ pub struct ServiceConfig {
     store_path: Option<StorePath>,
     #[serde(default)]
     masked: bool,
+    /// How the unit was masked, recorded in the state.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    mask_mode: Option<masks::MaskMode>,
+    /// Enable the unit according to the preset files of the host, rather than always.
+    #[serde(default)]
+    preset: bool,
+}
+
+impl ServiceConfig {
+    /// The unit file in the store, absent for masked units.
+    pub fn unit_file(&self) -> Option<&Path> {
+        self.store_path.as_ref().map(|p| p.store_path.as_path())
+    }
 }
 
 pub type Services = HashMap<String, ServiceConfig>;
@@ -58,137 +185,597 @@ pub fn get_active_services(
     Ok(services)
 }
 
+/// The jobs to run once the /etc files of the new generation are activated.
+#[derive(Debug, Default)]
+pub struct UnitPlan {
+    /// The directory the unit files and drop-ins of the generations are linked into.
+    system_dir: PathBuf,
+    etc_triggers: EtcTriggers,
+    unit_fingerprints: UnitFingerprints,
+    restart: Vec<String>,
+    reload: Vec<String>,
+    /// Includes the units stopped by `prepare` that were running.
+    start: Vec<String>,
+    /// The outcome of the stop jobs run by `prepare`.
+    outcomes: UnitOutcomes,
+    /// The template instances configured by the previous generation.
+    instances: BTreeSet<String>,
+    timeouts: JobTimeouts,
+    /// When `prepare` started touching units, to only report the journal of the activation.
+    started: Option<SystemTime>,
+}
+
+/// Stop the units that must not run while their files change, and plan the jobs for the
+/// other changed units.
+///
+/// This runs before the /etc files of the new generation get activated, while systemd still
+/// knows about the old units. Errors are logged, the activation goes on regardless.
+pub fn prepare(
+    store_path: &StorePath,
+    old_services: &Services,
+    ephemeral: bool,
+    options: &ServiceOptions,
+) -> UnitPlan {
+    let started = SystemTime::now();
+    let system_dir = systemd_system_dir(Path::new("/"), ephemeral);
+    let services = match get_active_services(store_path, old_services.clone()) {
+        Ok(services) => services,
+        Err(ActivationError::WithPartialResult { source, .. }) => {
+            log::error!("Cannot read the new services: {source:?}");
+            return UnitPlan {
+                system_dir,
+                started: Some(started),
+                ..UnitPlan::default()
+            };
+        }
+    };
+    let plan = UnitPlan {
+        started: Some(started),
+        etc_triggers: EtcTriggers::snapshot(&services, &etc_dir(Path::new("/"), ephemeral)),
+        unit_fingerprints: UnitFingerprints::snapshot(&system_dir),
+        instances: templates::configured_instances(old_services, &system_dir),
+        system_dir,
+        // Stopped units run the old generation, restarted ones the new one.
+        timeouts: JobTimeouts::new(options.job_timeout).with_units([old_services, &services]),
+        ..UnitPlan::default()
+    };
+
+    let service_manager = match systemd::ServiceManager::new_session() {
+        Ok(service_manager) => service_manager,
+        Err(e) => {
+            log::error!("Cannot connect to systemd: {e:?}");
+            return plan;
+        }
+    };
+    prepare_units(&service_manager, plan, services, old_services)
+}
+
+/// Stop the units of `old_services` that must not run while their files change, and add
+/// the jobs for the other changed units to `plan`.
+fn prepare_units(
+    service_manager: &dyn systemd::ServiceManagerBackend,
+    mut plan: UnitPlan,
+    services: Services,
+    old_services: &Services,
+) -> UnitPlan {
+    let (masked, active): (Services, Services) =
+        services.into_iter().partition(|(_, cfg)| cfg.masked);
+
+    // Stop removed services and any masked services that might still be running
+    // (e.g. distro-provided units).
+    let mut units_to_stop: BTreeSet<String> = masked.keys().cloned().collect();
+    for (name, cfg) in old_services.iter() {
+        if active.contains_key(name) || masked.contains_key(name) || cfg.masked {
+            continue;
+        }
+        match unit_actions::keep_removed_unit(name, read_unit_info(name, cfg).as_ref()) {
+            Some(reason) => log::info!("Not stopping {name}: {reason}"),
+            None => {
+                units_to_stop.insert(name.clone());
+            }
+        }
+    }
+
+    let changed: Vec<(&String, &ServiceConfig, &ServiceConfig)> = active
+        .iter()
+        .filter_map(|(name, cfg)| {
+            let old_cfg = old_services.get(name).filter(|old| !old.masked)?;
+            (cfg.store_path != old_cfg.store_path).then_some((name, cfg, old_cfg))
+        })
+        .collect();
+
+    // Templates can't be stopped nor restarted, their running instances are.
+    let instances = {
+        let templates: Vec<&str> = units_to_stop
+            .iter()
+            .chain(changed.iter().map(|(name, _, _)| *name))
+            .map(String::as_str)
+            .filter(|name| templates::is_template(name))
+            .collect();
+        templates::running_instances(service_manager, &templates)
+    };
+    let mut units_to_stop: BTreeSet<String> =
+        templates::with_instances(units_to_stop.into_iter().collect(), &instances)
+            .into_iter()
+            .collect();
+
+    let mut restarted = Vec::new();
+    let mut targets = Vec::new();
+    for (name, cfg, old_cfg) in changed {
+        let new_info = read_unit_info(name, cfg);
+        let old_info = read_unit_info(name, old_cfg);
+        for unit in templates::with_instances(vec![name.clone()], &instances) {
+            match unit_actions::changed_unit_action(&unit, old_info.as_ref(), new_info.as_ref()) {
+                UnitAction::Skip(reason) => log::info!("Not restarting {unit}: {reason}"),
+                UnitAction::Reload => plan.reload.push(unit),
+                UnitAction::Start => targets.push(unit),
+                action @ (UnitAction::Restart | UnitAction::StopStart) => {
+                    let sockets = unit_actions::service_sockets(&unit, new_info.as_ref());
+                    restarted.push((unit, action == UnitAction::StopStart, sockets));
+                }
+            }
+        }
+    }
+    let candidates: Vec<&str> = restarted
+        .iter()
+        .flat_map(|(name, _, sockets)| std::iter::once(name).chain(sockets))
+        .chain(&targets)
+        .map(AsRef::as_ref)
+        .collect();
+    let running = running_units(service_manager, &candidates);
+    plan.start.extend(only_running(targets, &running));
+    for (name, stop_first, sockets) in restarted {
+        let sockets: Vec<String> = sockets
+            .into_iter()
+            .filter(|socket| running.contains(socket))
+            .collect();
+        if !sockets.is_empty() {
+            // Socket-activated services are started again through their sockets, which
+            // systemd refuses to restart while the service is running.
+            log::info!("Restarting {} instead of {name}", sockets.join(", "));
+            units_to_stop.insert(name);
+            units_to_stop.extend(sockets.iter().cloned());
+            plan.start.extend(sockets);
+        } else if stop_first {
+            if running.contains(&name) {
+                plan.start.push(name.clone());
+            }
+            units_to_stop.insert(name);
+        } else {
+            plan.restart.push(name);
+        }
+    }
+
+    if !units_to_stop.is_empty() {
+        let jobs = units_to_stop
+            .iter()
+            .map(|unit| (unit.as_str(), "stop"))
+            .collect();
+        let infos = unit_infos(&jobs, &[old_services]);
+        let result = service_manager.monitor_jobs().and_then(|job_monitor| {
+            run_in_waves(
+                service_manager,
+                &*job_monitor,
+                &jobs,
+                &infos,
+                &plan.timeouts,
+            )
+        });
+        match result {
+            Ok(outcomes) => plan.outcomes = outcomes,
+            Err(e) => log::error!("Error while stopping units: {e:?}"),
+        }
+    }
+    plan
+}
+
+/// Start the units stopped by `prepare` again, when the activation fails before
+/// `activate` could do it.
+pub fn restart_stopped_units(plan: &UnitPlan) -> anyhow::Result<()> {
+    if plan.start.is_empty() {
+        return Ok(());
+    }
+    let service_manager = systemd::ServiceManager::new_session()?;
+    let job_monitor = service_manager.monitor_jobs_init()?;
+    wait_for_jobs(
+        &service_manager,
+        &job_monitor,
+        start_units(&service_manager, convert_names(&plan.start)),
+        &plan.timeouts,
+    )
+}
+
 pub fn activate(
     store_path: &StorePath,
     old_services: Services,
     ephemeral: bool,
-) -> ServiceActivationResult {
-    verify_systemd_dir(ephemeral)
-        .map_err(|e| ActivationError::with_partial_result(old_services.clone(), e))?;
+    old_enabled: &EnabledUnits,
+    plan: &UnitPlan,
+    options: &ServiceOptions,
+) -> ActivationResult<ServicesActivation> {
+    verify_systemd_dir(Path::new("/"), ephemeral).map_err(|e| {
+        ActivationError::with_partial_result(partial(&old_services, &plan.outcomes, old_enabled), e)
+    })?;
+
+    let mode = masks::MaskMode::new(ephemeral);
+    let mut services = get_active_services(store_path, old_services.clone()).map_err(
+        |ActivationError::WithPartialResult { result, source }| {
+            ActivationError::with_partial_result(
+                partial(&result, &plan.outcomes, old_enabled),
+                source,
+            )
+        },
+    )?;
+    masks::carry_over(&mut services, &old_services, mode);
 
-    let services = get_active_services(store_path, old_services.clone())?;
+    let service_manager = systemd::ServiceManager::new_session().map_err(|e| {
+        ActivationError::with_partial_result(partial(&old_services, &plan.outcomes, old_enabled), e)
+    })?;
+    activate_units(
+        &service_manager,
+        services,
+        old_services,
+        ephemeral,
+        old_enabled,
+        plan,
+        options,
+    )
+}
 
+/// The services of a failed activation, along with the outcome of the jobs run so far.
+fn partial(
+    services: &Services,
+    outcomes: &UnitOutcomes,
+    enabled: &EnabledUnits,
+) -> ServicesActivation {
+    ServicesActivation {
+        services: services.clone(),
+        outcomes: outcomes.clone(),
+        enabled: enabled.clone(),
+        ..ServicesActivation::default()
+    }
+}
+
+/// Run the jobs of the activation of `services`, unmask, enable and mask units, and watch
+/// the units settle.
+fn activate_units(
+    service_manager: &dyn systemd::ServiceManagerBackend,
+    mut services: Services,
+    old_services: Services,
+    ephemeral: bool,
+    old_enabled: &EnabledUnits,
+    plan: &UnitPlan,
+    options: &ServiceOptions,
+) -> ActivationResult<ServicesActivation> {
+    let mode = masks::MaskMode::new(ephemeral);
     let (masked, active): (Services, Services) = services
         .clone()
         .into_iter()
         .partition(|(_, cfg)| cfg.masked);
 
-    let services_to_stop =
-        get_services_to_stop(old_services.clone().relative_complement(active.clone()));
-    let services_to_reload = get_services_to_reload(active.clone(), old_services.clone());
+    // Units that were already running the previous generation, and whose unit file didn't
+    // change, but whose /etc entries did.
+    let etc_triggered = plan.etc_triggers.changed_units();
+    let triggered_by_etc = |units: &BTreeSet<String>| -> Vec<String> {
+        units
+            .iter()
+            .filter(|name| {
+                active.get(*name).is_some_and(|cfg| {
+                    old_services
+                        .get(*name)
+                        .is_some_and(|old| old.store_path == cfg.store_path)
+                })
+            })
+            .cloned()
+            .collect()
+    };
+    let mut units_to_restart = plan.restart.clone();
+    units_to_restart.extend(
+        triggered_by_etc(&etc_triggered.restart)
+            .into_iter()
+            .filter(|name| !unit_actions::is_never_restarted(name)),
+    );
+    let mut units_to_reload = plan.reload.clone();
+    units_to_reload.extend(triggered_by_etc(&etc_triggered.reload));
+    let mut units_to_start = convert_names(&plan.start);
 
-    let service_manager = systemd::ServiceManager::new_session()
-        .map_err(|e| ActivationError::with_partial_result(old_services.clone(), e))?;
-    let job_monitor = service_manager
-        .monitor_jobs_init()
-        .map_err(|e| ActivationError::with_partial_result(old_services.clone(), e))?;
-    let timeout = Some(Duration::from_secs(30));
+    // Units whose unit file or drop-ins changed, like overrides of distro units. The units whose store
+    // path changed were already taken care of by `prepare`.
+    let changed_unit_files = plan.unit_fingerprints.changed_units(&plan.system_dir);
+    let mut changed_targets = Vec::new();
+    for (name, action) in &changed_unit_files {
+        let store_path_changed = old_services.get(name).is_some_and(|old| {
+            services
+                .get(name)
+                .is_some_and(|cfg| cfg.store_path != old.store_path)
+        });
+        if store_path_changed || masked.contains_key(name) {
+            continue;
+        }
+        match action {
+            UnitAction::Skip(reason) => log::info!("Not restarting {name}: {reason}"),
+            UnitAction::Reload => {
+                log::info!("Reloading {name}: its unit file or drop-ins changed");
+                units_to_reload.push(name.clone());
+            }
+            UnitAction::Restart | UnitAction::StopStart => {
+                log::info!("Restarting {name}: its unit file or drop-ins changed");
+                units_to_restart.push(name.clone());
+            }
+            UnitAction::Start => changed_targets.push(name.as_str()),
+        }
+    }
+    let running = running_units(service_manager, &changed_targets);
+    units_to_start.extend(only_running(changed_targets, &running));
 
-    // Stop removed services and any masked services that might still be running
-    // (e.g. distro-provided units). Must happen before daemon-reload so systemd
-    // still knows about these units.
-    let mut units_to_stop = convert_services(&services_to_stop);
-    units_to_stop.extend(convert_services(&masked));
-    wait_for_jobs(
-        &service_manager,
-        &job_monitor,
-        stop_services(&service_manager, units_to_stop),
-        &timeout,
-    )
-    .map_err(|e| ActivationError::with_partial_result(services.clone(), e))?;
+    let job_monitor = service_manager.monitor_jobs().map_err(|e| {
+        ActivationError::with_partial_result(partial(&old_services, &plan.outcomes, old_enabled), e)
+    })?;
+    let timeouts = JobTimeouts::new(options.job_timeout).with_units([&services, &old_services]);
 
-    wait_for_jobs(
-        &service_manager,
-        &job_monitor,
-        reload_or_restart_units(&service_manager, convert_services(&services_to_reload))
-            + start_units(&service_manager, ["system-manager.target"]),
-        &timeout,
+    // Templates can't be restarted nor reloaded, their running instances are.
+    let instances = {
+        let templates: Vec<&str> = units_to_restart
+            .iter()
+            .chain(&units_to_reload)
+            .map(String::as_str)
+            .filter(|name| templates::is_template(name))
+            .collect();
+        templates::running_instances(service_manager, &templates)
+    };
+    let mut units_to_restart = templates::with_instances(units_to_restart, &instances);
+    let mut units_to_reload = templates::with_instances(units_to_reload, &instances);
+    units_to_restart.sort();
+    units_to_restart.dedup();
+    units_to_reload.sort();
+    units_to_reload.dedup();
+    units_to_reload.retain(|name| !units_to_restart.contains(name));
+
+    // Stop the instances the previous generation configured and the new one doesn't.
+    let mut outcomes = plan.outcomes.clone();
+    let configured_instances = templates::configured_instances(&services, &plan.system_dir);
+    let removed_instances: Vec<&str> = plan
+        .instances
+        .difference(&configured_instances)
+        .map(String::as_str)
+        .collect();
+    let removed_instances = running_units(service_manager, &removed_instances);
+    if !removed_instances.is_empty() {
+        log::info!(
+            "Stopping removed instances: {}",
+            removed_instances
+                .iter()
+                .cloned()
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        let jobs = removed_instances
+            .iter()
+            .map(|unit| (unit.as_str(), "stop"))
+            .collect();
+        outcomes.extend(
+            run_in_waves(
+                service_manager,
+                &*job_monitor,
+                &jobs,
+                &BTreeMap::new(),
+                &timeouts,
+            )
+            .map_err(|e| {
+                ActivationError::with_partial_result(partial(&services, &outcomes, old_enabled), e)
+            })?,
+        );
+    }
+
+    // Unmask the units that left `systemd.maskedUnits` or are masked in the other mode now,
+    // and start the ones that systemd would have started if they hadn't been masked.
+    let unmasks = masks::masks_to_remove(&services, &old_services, mode);
+    let mut wanted_unmasked = Vec::new();
+    if !unmasks.is_empty() {
+        masks::unmask(service_manager, &unmasks)
+            .and_then(|()| {
+                log::info!("Reloading systemd daemon after unmasking...");
+                service_manager.daemon_reload()
+            })
+            .map_err(|e| {
+                ActivationError::with_partial_result(partial(&services, &outcomes, old_enabled), e)
+            })?;
+        let unmasked: Vec<&str> = unmasks
+            .keys()
+            .map(String::as_str)
+            .filter(|name| !masked.contains_key(*name))
+            .collect();
+        wanted_unmasked = masks::wanted_units(service_manager, &unmasked);
+    }
+    units_to_start.extend(wanted_unmasked.iter().map(String::as_str));
+
+    let enablement = install::enable_units(
+        service_manager,
+        &services,
+        &old_services,
+        old_enabled,
+        ephemeral,
     )
-    .map_err(|e| ActivationError::with_partial_result(services.clone(), e))?;
+    .map_err(|e| {
+        ActivationError::with_partial_result(partial(&services, &outcomes, old_enabled), e)
+    })?;
+    units_to_start.extend(enablement.to_start.iter().map(String::as_str));
+    units_to_start.push("system-manager.target");
+    // Units stopped by `prepare` must be started, not restarted.
+    let jobs: BTreeMap<&str, &'static str> = units_to_reload
+        .iter()
+        .map(|unit| (unit.as_str(), "reload"))
+        .chain(
+            units_to_restart
+                .iter()
+                .map(|unit| (unit.as_str(), "restart")),
+        )
+        .chain(units_to_start.iter().map(|unit| (*unit, "start")))
+        .collect();
+    let infos = unit_infos(&jobs, &[&services]);
+    outcomes.extend(
+        run_in_waves(service_manager, &*job_monitor, &jobs, &infos, &timeouts).map_err(|e| {
+            ActivationError::with_partial_result(
+                partial(&services, &outcomes, &enablement.enabled),
+                e,
+            )
+        })?,
+    );
 
     if !masked.is_empty() {
         let unit_names: Vec<&str> = masked.keys().map(AsRef::as_ref).collect();
         service_manager
-            .mask_unit_files(&unit_names, ephemeral)
+            .mask_unit_files(&unit_names, mode.runtime())
             .with_context(|| format!("masking {} unit(s)", masked.len()))
-            .map_err(|e| ActivationError::with_partial_result(services.clone(), e))?;
+            .map_err(|e| {
+                ActivationError::with_partial_result(
+                    partial(&services, &outcomes, &enablement.enabled),
+                    e,
+                )
+            })?;
 
         log::info!("Reloading systemd daemon after masking...");
-        service_manager
-            .daemon_reload()
-            .map_err(|e| ActivationError::with_partial_result(services.clone(), e))?;
+        service_manager.daemon_reload().map_err(|e| {
+            ActivationError::with_partial_result(
+                partial(&services, &outcomes, &enablement.enabled),
+                e,
+            )
+        })?;
 
         log::info!(
             "Masked {} unit(s): {}",
             masked.len(),
             masked.keys().cloned().collect::<Vec<_>>().join(", ")
         );
+        masks::record(&mut services, mode);
     }
 
+    // Only the units we just started, restarted or reloaded: a unit that was already failing
+    // before the switch is not a failure of this activation.
+    let windows = active
+        .iter()
+        .filter(|(name, _)| {
+            outcomes
+                .get(*name)
+                .is_some_and(|outcome| outcome.job != "stop")
+        })
+        .map(|(name, cfg)| {
+            let window =
+                settle::settle_window(read_unit_info(name, cfg).as_ref(), options.settle_time);
+            (name.clone(), window)
+        })
+        .collect();
+    let unsettled = settle::settle(service_manager, &windows).map_err(|e| {
+        ActivationError::with_partial_result(partial(&services, &outcomes, &enablement.enabled), e)
+    })?;
+
+    let mut activation = ServicesActivation {
+        services,
+        outcomes,
+        unsettled,
+        journals: BTreeMap::new(),
+        enabled: enablement.enabled,
+    };
+    activation.journals = activation
+        .failed_units()
+        .into_iter()
+        .map(|unit| {
+            let journal = journal::unit_journal(unit, plan.started, options.journal_lines);
+            if !journal.is_empty() {
+                log::error!("Last journal lines of {unit}:\n{}", journal.join("\n"));
+            }
+            (unit.to_owned(), journal)
+        })
+        .filter(|(_, journal)| !journal.is_empty())
+        .collect();
+
     log::info!("Done");
-    Ok(services)
+    Ok(activation)
 }
 
-fn get_services_to_reload(services: Services, old_services: Services) -> Services {
-    let mut services_to_reload = services.intersection(old_services.clone());
-    services_to_reload.retain(|name, service| {
-        if service.masked {
-            return false;
+/// Register the services of a system that is not running, like a disk image or a chroot.
+///
+/// Nothing gets started or stopped since there is no service manager to talk to, the
+/// units will be picked up on first boot. Masked units are linked to /dev/null directly.
+pub fn activate_in_root(
+    store_path: &StorePath,
+    old_services: Services,
+    root: &Path,
+) -> ServiceActivationResult {
+    let mut services = get_active_services(store_path, old_services.clone())?;
+    let system_dir = systemd_system_dir(root, false);
+    for (name, _) in services.iter().filter(|(_, cfg)| cfg.masked) {
+        let unit_path = system_dir.join(name);
+        create_link(Path::new("/dev/null"), &unit_path)
+            .with_context(|| format!("Error while masking {}", unit_path.display()))
+            .map_err(|e| ActivationError::with_partial_result(services.clone(), e))?;
+    }
+    masks::record(&mut services, masks::MaskMode::Persistent);
+    // Unmask the units that used to be masked.
+    for (name, _) in old_services
+        .iter()
+        .filter(|(name, cfg)| cfg.masked && !services.get(*name).is_some_and(|c| c.masked))
+    {
+        let unit_path = system_dir.join(name);
+        if fs::read_link(&unit_path).is_ok_and(|target| target == Path::new("/dev/null")) {
+            log::info!("Unmasking {}", unit_path.display());
+            fs::remove_file(&unit_path)
+                .map_err(|e| ActivationError::with_partial_result(services.clone(), e))?;
         }
-        if let Some(old_service) = old_services.get(name) {
-            if service.store_path == old_service.store_path {
-                return false;
-            }
-            if let Some(ref path) = service.store_path {
-                let unit_info = parse_unit(&path.store_path)
-                    .inspect_err(|e| log::debug!("unable to parse unit file {path}: {e}"))
-                    .ok();
-                if !parse_systemd_bool(unit_info.as_ref(), "Service", "X-RestartIfChanged", true) {
-                    log::info!("Skipping restart of {name}: X-RestartIfChanged=false");
-                    return false;
-                }
-            }
-            true
-        } else {
-            // Since we run this on the intersection, this should never happen
-            panic!("Something went terribly wrong!");
+    }
+    log::info!("Done");
+    Ok(services)
+}
+
+fn read_unit_info(name: &str, cfg: &ServiceConfig) -> Option<UnitInfo> {
+    parse_unit(cfg.unit_file()?)
+        .inspect_err(|e| log::debug!("unable to parse unit file of {name}: {e}"))
+        .ok()
+}
+
+/// The units among `names` that are currently running.
+fn running_units(
+    service_manager: &dyn systemd::ServiceManagerBackend,
+    names: &[&str],
+) -> BTreeSet<String> {
+    if names.is_empty() {
+        return BTreeSet::new();
+    }
+    match service_manager.list_units_by_patterns(&["active", "activating", "reloading"], names) {
+        Ok(units) => units.into_iter().map(|unit| unit.name).collect(),
+        Err(e) => {
+            log::error!("Cannot list the running units, assuming they all run: {e}");
+            names.iter().map(|name| (*name).to_owned()).collect()
         }
-    });
-    services_to_reload
-}
-
-fn get_services_to_stop(services_to_stop: Services) -> Services {
-    let mut services_to_stop = services_to_stop;
-    services_to_stop.retain(|name, service| {
-        if let Some(ref path) = service.store_path {
-            let unit_data = parse_unit(Path::new(&path.store_path)).ok();
-            if !parse_systemd_bool(unit_data.as_ref(), "Unit", "X-StopOnRemoval", true) {
-                log::info!("Skipping stop of {name}: X-StopOnRemoval=false");
-                return false;
+    }
+}
+
+/// The changed targets to start again: like NixOS, only the ones that are active, so that
+/// changing `poweroff.target` doesn't power the host off.
+fn only_running<S: AsRef<str>>(targets: Vec<S>, running: &BTreeSet<String>) -> Vec<S> {
+    targets
+        .into_iter()
+        .filter(|target| {
+            let active = running.contains(target.as_ref());
+            if !active {
+                log::info!("Not starting {}: it is not active", target.as_ref());
             }
-        }
-        true
-    });
-    services_to_stop
+            active
+        })
+        .collect()
 }
 
-fn systemd_system_dir(ephemeral: bool) -> PathBuf {
+fn systemd_system_dir(root: &Path, ephemeral: bool) -> PathBuf {
     if ephemeral {
-        Path::new(path::MAIN_SEPARATOR_STR)
-            .join("run")
-            .join("systemd")
-            .join("system")
+        in_root(root, "/run/systemd/system")
     } else {
-        Path::new(path::MAIN_SEPARATOR_STR)
-            .join("etc")
-            .join("systemd")
-            .join("system")
+        in_root(root, "/etc/systemd/system")
     }
 }
 
-fn verify_systemd_dir(ephemeral: bool) -> anyhow::Result<()> {
+fn verify_systemd_dir(root: &Path, ephemeral: bool) -> anyhow::Result<()> {
     if ephemeral {
-        let system_dir = systemd_system_dir(ephemeral);
+        let system_dir = systemd_system_dir(root, ephemeral);
         if system_dir.exists()
             && !system_dir.is_symlink()
             && system_dir.is_dir()
@@ -216,7 +803,7 @@ fn verify_systemd_dir(ephemeral: bool) -> anyhow::Result<()> {
             }
         }
 
-        let target = etc_dir(ephemeral).join("systemd").join("system");
+        let target = etc_dir(root, ephemeral).join("systemd").join("system");
         create_link(&target, &system_dir).with_context(|| {
             format!(
                 "Error while creating symlink: {} -> {}",
@@ -228,12 +815,28 @@ fn verify_systemd_dir(ephemeral: bool) -> anyhow::Result<()> {
     Ok(())
 }
 
-pub fn deactivate(old_services: Services) -> ServiceActivationResult {
+pub fn deactivate(
+    old_services: Services,
+    enabled: &EnabledUnits,
+    job_timeout: Duration,
+) -> ServiceActivationResult {
     log::debug!("{:?}", old_services);
 
     restore_ephemeral_system_dir()
         .map_err(|e| ActivationError::with_partial_result(old_services.clone(), e))?;
 
+    let service_manager = systemd::ServiceManager::new_session()
+        .map_err(|e| ActivationError::with_partial_result(old_services.clone(), e))?;
+    deactivate_units(&service_manager, old_services, enabled, job_timeout)
+}
+
+/// Stop the units of `old_services`, disable the `enabled` ones and unmask the masked ones.
+fn deactivate_units(
+    service_manager: &dyn systemd::ServiceManagerBackend,
+    old_services: Services,
+    enabled: &EnabledUnits,
+    job_timeout: Duration,
+) -> ServiceActivationResult {
     // masked units can't be running, skip them
     let stoppable: Services = old_services
         .clone()
@@ -241,47 +844,52 @@ pub fn deactivate(old_services: Services) -> ServiceActivationResult {
         .filter(|(_, cfg)| !cfg.masked)
         .collect();
 
-    let service_manager = systemd::ServiceManager::new_session()
-        .map_err(|e| ActivationError::with_partial_result(old_services.clone(), e))?;
     if !stoppable.is_empty() {
         let job_monitor = service_manager
-            .monitor_jobs_init()
+            .monitor_jobs()
             .map_err(|e| ActivationError::with_partial_result(old_services.clone(), e))?;
-        let timeout = Some(Duration::from_secs(30));
+        let timeouts = JobTimeouts::new(job_timeout).with_units([&stoppable]);
 
-        let mut units_to_stop = convert_services(&stoppable);
-        units_to_stop.push("system-manager.target");
+        // Templates can't be stopped, their running instances are.
+        let units = convert_services(&stoppable);
+        let templates: Vec<&str> = units
+            .iter()
+            .copied()
+            .filter(|name| templates::is_template(name))
+            .collect();
+        let instances = templates::running_instances(service_manager, &templates);
+        let units =
+            templates::with_instances(units.into_iter().map(str::to_owned).collect(), &instances);
+        let jobs: BTreeMap<&str, &'static str> = units
+            .iter()
+            .map(String::as_str)
+            .chain(["system-manager.target"])
+            .map(|unit| (unit, "stop"))
+            .collect();
+        let infos = unit_infos(&jobs, &[&stoppable]);
         // We need to do this before we reload the systemd daemon, so that the daemon
         // still knows about these units.
-        wait_for_jobs(
-            &service_manager,
-            &job_monitor,
-            stop_services(&service_manager, units_to_stop),
-            &timeout,
-        )
-        // We consider all jobs stopped now..
-        .map_err(|e| ActivationError::with_partial_result(im::HashMap::new(), e))?;
+        run_in_waves(service_manager, &*job_monitor, &jobs, &infos, &timeouts)
+            .and_then(|outcomes| fail_on_pending(&outcomes))
+            // We consider all jobs stopped now..
+            .map_err(|e| ActivationError::with_partial_result(im::HashMap::new(), e))?;
     } else {
         log::info!("No services to deactivate.");
     }
 
-    // Unmask previously masked units via D-Bus, try both persistent and
-    // runtime paths since we don't know which mode was used during activation
-    let masked_names: Vec<&str> = old_services
-        .iter()
-        .filter(|(_, cfg)| cfg.masked)
-        .map(|(name, _)| name.as_str())
-        .collect();
-    if !masked_names.is_empty() {
-        for runtime in [false, true] {
-            if let Err(e) = service_manager.unmask_unit_files(&masked_names, runtime) {
-                log::error!("Error unmasking units (runtime={runtime}): {e}");
-            }
-        }
+    if !enabled.is_empty() {
+        // We don't know whether the units were enabled at runtime either.
+        install::disable_units(service_manager, enabled, &[false, true]);
+        log::info!("Disabled {} unit(s)", enabled.len());
+    }
+
+    // Unmask previously masked units in the mode they were masked in, errors are logged.
+    let masks = masks::recorded_masks(&old_services);
+    if !masks.is_empty() && masks::unmask(service_manager, &masks).is_ok() {
         log::info!(
             "Unmasked {} unit(s): {}",
-            masked_names.len(),
-            masked_names.join(", ")
+            masks.len(),
+            masks.keys().cloned().collect::<Vec<_>>().join(", ")
         );
     }
 
@@ -300,7 +908,7 @@ pub fn deactivate(old_services: Services) -> ServiceActivationResult {
 // and we recreate it if needed.
 // NOTE: We rely on the fact that the etc files get cleaned up first, before this runs!
 fn restore_ephemeral_system_dir() -> anyhow::Result<()> {
-    let ephemeral_systemd_system_dir = systemd_system_dir(true);
+    let ephemeral_systemd_system_dir = systemd_system_dir(Path::new("/"), true);
     if !ephemeral_systemd_system_dir.exists() {
         if ephemeral_systemd_system_dir.is_symlink() {
             fs::remove_file(&ephemeral_systemd_system_dir)?;
@@ -310,35 +918,55 @@ fn restore_ephemeral_system_dir() -> anyhow::Result<()> {
     Ok(())
 }
 
-fn stop_services<'a, U>(service_manager: &systemd::ServiceManager, units: U) -> HashSet<JobId>
+fn stop_services<'a, U>(
+    service_manager: &dyn systemd::ServiceManagerBackend,
+    units: U,
+) -> HashSet<JobId>
+where
+    U: AsRef<[&'a str]>,
+{
+    for_each_unit(|s| service_manager.stop_unit(s), units.as_ref(), "stop")
+}
+
+fn try_restart_units<'a, U>(
+    service_manager: &dyn systemd::ServiceManagerBackend,
+    units: U,
+) -> HashSet<JobId>
 where
     U: AsRef<[&'a str]>,
 {
-    for_each_unit(|s| service_manager.stop_unit(s), units.as_ref(), "stopping")
+    for_each_unit(
+        |unit| service_manager.try_restart_unit(unit),
+        units.as_ref(),
+        "restart",
+    )
 }
 
-fn reload_or_restart_units<'a, U>(
-    service_manager: &systemd::ServiceManager,
+fn reload_or_try_restart_units<'a, U>(
+    service_manager: &dyn systemd::ServiceManagerBackend,
     units: U,
 ) -> HashSet<JobId>
 where
     U: AsRef<[&'a str]>,
 {
     for_each_unit(
-        |s| service_manager.reload_or_restart_unit(s),
+        |unit| service_manager.reload_or_try_restart_unit(unit),
         units.as_ref(),
-        "reloading",
+        "reload",
     )
 }
 
-fn start_units<'a, U>(service_manager: &systemd::ServiceManager, units: U) -> HashSet<JobId>
+fn start_units<'a, U>(
+    service_manager: &dyn systemd::ServiceManagerBackend,
+    units: U,
+) -> HashSet<JobId>
 where
     U: AsRef<[&'a str]>,
 {
     for_each_unit(
         |unit| service_manager.start_unit(unit),
         units.as_ref(),
-        "starting",
+        "start",
     )
 }
 
@@ -346,79 +974,250 @@ fn convert_services(services: &Services) -> Vec<&str> {
     services.keys().map(AsRef::as_ref).collect::<Vec<&str>>()
 }
 
-fn for_each_unit<'a, F, R, S>(action: F, units: S, log_action: &str) -> HashSet<JobId>
+fn convert_names(names: &[String]) -> Vec<&str> {
+    names.iter().map(AsRef::as_ref).collect()
+}
+
+fn for_each_unit<'a, F, S>(action: F, units: S, job: &'static str) -> HashSet<JobId>
 where
-    F: Fn(&str) -> anyhow::Result<R>,
+    F: Fn(&str) -> anyhow::Result<String>,
     S: AsRef<[&'a str]>,
 {
-    // TODO: do we want to propagate unit failures here in some way?
     units
         .as_ref()
         .iter()
         .fold(HashSet::new(), |mut set, unit| match action(unit) {
-            Ok(_) => {
-                log::debug!("Unit {}: {}...", unit, log_action);
+            Ok(queued) => {
+                log::debug!("Unit {}: {job} job queued", unit);
                 set.insert(JobId {
-                    id: (*unit).to_owned(),
+                    unit: (*unit).to_owned(),
+                    job,
+                    path: Some(queued),
                 });
                 set
             }
             Err(e) => {
-                log::error!(
-                    "Service {}: error {log_action}, please consult the logs",
-                    unit
-                );
+                log::error!("Service {}: error queueing a {job} job", unit);
                 log::error!("{e}");
+                set.insert(JobId {
+                    unit: (*unit).to_owned(),
+                    job,
+                    path: None,
+                });
                 set
             }
         })
 }
 
+/// Wait for `jobs` to finish, and collect how they ended.
+fn collect_outcomes(
+    service_manager: &dyn systemd::ServiceManagerBackend,
+    job_monitor: &dyn systemd::JobWatcher,
+    jobs: HashSet<JobId>,
+    timeouts: &JobTimeouts,
+) -> anyhow::Result<UnitOutcomes> {
+    let results = job_monitor.wait(
+        jobs.iter()
+            .filter_map(|job| {
+                Some(systemd::WaitedJob {
+                    path: job.path.clone()?,
+                    unit: job.unit.clone(),
+                    job: job.job,
+                    timeout: timeouts.for_job(&job.unit, job.job),
+                })
+            })
+            .collect(),
+    )?;
+    let mut outcomes: UnitOutcomes = jobs
+        .into_iter()
+        .map(|job| {
+            let result = match &job.path {
+                None => "rejected",
+                Some(path) => results.get(path).map_or("pending", String::as_str),
+            };
+            let outcome = UnitOutcome {
+                job: job.job.to_owned(),
+                result: result.to_owned(),
+                state: None,
+                wave: None,
+            };
+            (job.unit, outcome)
+        })
+        .collect();
+
+    let pending: Vec<&str> = outcomes
+        .iter()
+        .filter(|(_, o)| o.result == "pending")
+        .map(|(unit, _)| unit.as_str())
+        .collect();
+    if !pending.is_empty() {
+        let states: BTreeMap<String, String> = service_manager
+            .list_units_by_patterns(&[], &pending)
+            .inspect_err(|e| log::warn!("Cannot read the state of the pending units: {e}"))
+            .unwrap_or_default()
+            .into_iter()
+            .map(|unit| {
+                (
+                    unit.name,
+                    format!("{}/{}", unit.active_state, unit.sub_state),
+                )
+            })
+            .collect();
+        for (unit, outcome) in outcomes.iter_mut() {
+            if outcome.result == "pending" {
+                outcome.state = states.get(unit).cloned();
+            }
+        }
+    }
+
+    for (unit, outcome) in outcomes.iter().filter(|(_, o)| !o.succeeded()) {
+        if outcome.result == "pending" {
+            log::error!(
+                "Unit {unit}: timed out after {:?} waiting for its {} job, the unit is {}",
+                timeouts.for_job(unit, &outcome.job),
+                outcome.job,
+                outcome.state.as_deref().unwrap_or("in an unknown state")
+            );
+        } else {
+            log::error!(
+                "Unit {unit}: {} job finished with result '{}'",
+                outcome.job,
+                outcome.result
+            );
+        }
+    }
+    Ok(outcomes)
+}
+
+/// Queue the `jobs` of units in dependency order, or in reverse dependency order for
+/// stop jobs, waiting for the jobs of each wave to finish before queueing the next one.
+fn run_in_waves(
+    service_manager: &dyn systemd::ServiceManagerBackend,
+    job_monitor: &dyn systemd::JobWatcher,
+    jobs: &BTreeMap<&str, &'static str>,
+    infos: &BTreeMap<&str, UnitInfo>,
+    timeouts: &JobTimeouts,
+) -> anyhow::Result<UnitOutcomes> {
+    let units: Vec<&str> = jobs.keys().copied().collect();
+    let mut waves = ordering::waves(&units, infos);
+    if jobs.values().all(|job| *job == "stop") {
+        waves.reverse();
+    }
+    let total = waves.len();
+    let mut outcomes = UnitOutcomes::new();
+    for (number, wave) in (1..).zip(waves) {
+        if total > 1 {
+            log::info!("Wave {number} of {total}: {}", wave.join(", "));
+        }
+        let queued = wave.iter().fold(HashSet::new(), |queued, unit| {
+            queued + queue_job(service_manager, unit, jobs[unit])
+        });
+        let wave_outcomes = collect_outcomes(service_manager, job_monitor, queued, timeouts)
+            .with_context(|| format!("waiting for the jobs of wave {number} of {total}"))?;
+        let failed: Vec<&str> = wave_outcomes
+            .iter()
+            .filter(|(_, outcome)| !outcome.succeeded())
+            .map(|(unit, _)| unit.as_str())
+            .collect();
+        if !failed.is_empty() && total > 1 {
+            log::error!(
+                "Wave {number} of {total} failed for {}, going on with the next waves",
+                failed.join(", ")
+            );
+        }
+        outcomes.extend(wave_outcomes.into_iter().map(|(unit, outcome)| {
+            let outcome = UnitOutcome {
+                wave: (total > 1).then_some(number),
+                ..outcome
+            };
+            (unit, outcome)
+        }));
+    }
+    Ok(outcomes)
+}
+
+fn queue_job(
+    service_manager: &dyn systemd::ServiceManagerBackend,
+    unit: &str,
+    job: &'static str,
+) -> HashSet<JobId> {
+    match job {
+        "stop" => stop_services(service_manager, [unit]),
+        "restart" => try_restart_units(service_manager, [unit]),
+        "reload" => reload_or_try_restart_units(service_manager, [unit]),
+        _ => start_units(service_manager, [unit]),
+    }
+}
+
+/// The unit files of the units with `jobs`, to order them. The first of `services`
+/// listing a unit wins.
+fn unit_infos<'a>(
+    jobs: &BTreeMap<&'a str, &'static str>,
+    services: &[&Services],
+) -> BTreeMap<&'a str, UnitInfo> {
+    jobs.keys()
+        .filter_map(|unit| {
+            let info = services
+                .iter()
+                .find_map(|services| read_unit_info(unit, services.get(*unit)?))?;
+            Some((*unit, info))
+        })
+        .collect()
+}
+
+/// Wait for `jobs` to finish, failing when some of them are still running at the timeout.
 fn wait_for_jobs(
-    service_manager: &systemd::ServiceManager,
-    job_monitor: &systemd::JobMonitor,
+    service_manager: &dyn systemd::ServiceManagerBackend,
+    job_monitor: &dyn systemd::JobWatcher,
     jobs: HashSet<JobId>,
-    timeout: &Option<Duration>,
+    timeouts: &JobTimeouts,
 ) -> anyhow::Result<()> {
-    if !service_manager.monitor_jobs_finish(job_monitor, timeout, jobs)? {
-        anyhow::bail!("Timeout waiting for systemd jobs");
+    let outcomes = collect_outcomes(service_manager, job_monitor, jobs, timeouts)?;
+    fail_on_pending(&outcomes)
+}
+
+fn fail_on_pending(outcomes: &UnitOutcomes) -> anyhow::Result<()> {
+    let pending: Vec<String> = outcomes
+        .iter()
+        .filter(|(_, o)| o.result == "pending")
+        .map(|(unit, o)| format!("{unit} ({})", o.state.as_deref().unwrap_or("unknown state")))
+        .collect();
+    if !pending.is_empty() {
+        anyhow::bail!("Timeout waiting for systemd jobs of {}", pending.join(", "));
     }
     Ok(())
 }
 
 #[derive(PartialEq, Eq, Hash, Clone)]
 struct JobId {
-    id: String,
+    unit: String,
+    job: &'static str,
+    /// The object path of the job, absent when systemd refused to queue it.
+    path: Option<String>,
 }
 
-impl From<JobId> for String {
-    fn from(value: JobId) -> Self {
-        value.id
-    }
-}
-
-pub fn restart_sysinit_reactivation_target() -> anyhow::Result<()> {
+pub fn restart_sysinit_reactivation_target(job_timeout: Duration) -> anyhow::Result<()> {
     let service_manager = systemd::ServiceManager::new_session()?;
     let job_monitor = service_manager.monitor_jobs_init()?;
-    let timeout = Some(Duration::from_secs(30));
+    let timeouts = JobTimeouts::new(job_timeout);
 
     log::info!("Reloading the systemd daemon...");
     service_manager.daemon_reload()?;
 
     let jobs = for_each_unit(
-        |unit| service_manager.restart_unit(unit),
+        |unit| Ok(service_manager.restart_unit(unit)?.path()),
         ["sysinit-reactivation.target"],
-        "restarting",
+        "restart",
     );
 
-    wait_for_jobs(&service_manager, &job_monitor, jobs, &timeout)?;
+    wait_for_jobs(&service_manager, &job_monitor, jobs, &timeouts)?;
     Ok(())
 }
 
 /// This must be called after daemon-reload so systemd knows about the unit,
 /// but before tmpfiles activation since tmpfiles may reference users that
 /// userborn needs to create.
-pub fn restart_userborn_if_exists() -> anyhow::Result<()> {
+pub fn restart_userborn_if_exists(job_timeout: Duration) -> anyhow::Result<()> {
     let service_manager = systemd::ServiceManager::new_session()?;
 
     // Check if userborn.service exists by listing units matching the pattern
@@ -431,17 +1230,384 @@ pub fn restart_userborn_if_exists() -> anyhow::Result<()> {
 
     log::info!("Restarting userborn.service to create users before tmpfiles...");
     let job_monitor = service_manager.monitor_jobs_init()?;
-    let timeout = Some(Duration::from_secs(30));
+    let timeouts = JobTimeouts::new(job_timeout);
 
     // We use restart rather than start because userborn is a oneshot service
     // with RemainAfterExit=true.
     let jobs = for_each_unit(
-        |unit| service_manager.restart_unit(unit),
+        |unit| Ok(service_manager.restart_unit(unit)?.path()),
         ["userborn.service"],
-        "restarting",
+        "restart",
     );
 
-    wait_for_jobs(&service_manager, &job_monitor, jobs, &timeout)?;
+    wait_for_jobs(&service_manager, &job_monitor, jobs, &timeouts)?;
     log::info!("userborn.service completed");
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::systemd::fake::FakeServiceManager;
+
+    const OPTIONS: ServiceOptions = ServiceOptions {
+        settle_time: Duration::ZERO,
+        job_timeout: Duration::from_secs(5),
+        journal_lines: 0,
+    };
+
+    /// A unit of the generation in `dir`, with the given unit file.
+    fn unit(dir: &Path, name: &str, unit_file: &str) -> (String, ServiceConfig) {
+        fs::create_dir_all(dir).unwrap();
+        let path = dir.join(name);
+        fs::write(&path, unit_file).unwrap();
+        let cfg = ServiceConfig {
+            store_path: Some(StorePath { store_path: path }),
+            masked: false,
+            mask_mode: None,
+            preset: false,
+        };
+        (name.to_owned(), cfg)
+    }
+
+    fn masked(name: &str) -> (String, ServiceConfig) {
+        let cfg = ServiceConfig {
+            store_path: None,
+            masked: true,
+            mask_mode: None,
+            preset: false,
+        };
+        (name.to_owned(), cfg)
+    }
+
+    fn switch(
+        service_manager: &FakeServiceManager,
+        services: &Services,
+        old_services: &Services,
+    ) -> ServicesActivation {
+        // Don't pick up the unit files of the host.
+        let system_dir = tempfile::tempdir().unwrap();
+        let plan = UnitPlan {
+            system_dir: system_dir.path().to_owned(),
+            timeouts: JobTimeouts::new(OPTIONS.job_timeout),
+            ..UnitPlan::default()
+        };
+        let plan = prepare_units(service_manager, plan, services.clone(), old_services);
+        activate_units(
+            service_manager,
+            services.clone(),
+            old_services.clone(),
+            false,
+            &EnabledUnits::new(),
+            &plan,
+            &OPTIONS,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn failed_units_are_reported_with_their_journal() {
+        let outcome = |result: &str| UnitOutcome {
+            job: "start".to_owned(),
+            result: result.to_owned(),
+            state: None,
+            wave: None,
+        };
+        let activation = ServicesActivation {
+            outcomes: UnitOutcomes::from([
+                ("app.service".to_owned(), outcome("failed")),
+                ("system-manager.target".to_owned(), outcome("done")),
+            ]),
+            unsettled: settle::UnsettledUnits::from([(
+                "worker.service".to_owned(),
+                "restarted 3 time(s)".to_owned(),
+            )]),
+            journals: BTreeMap::from([(
+                "app.service".to_owned(),
+                vec!["app[42]: cannot bind to port 80".to_owned()],
+            )]),
+            ..ServicesActivation::default()
+        };
+
+        assert_eq!(activation.failed_units(), ["app.service", "worker.service"]);
+        assert_eq!(
+            serde_json::to_value(activation.report()).unwrap(),
+            serde_json::json!({
+                "app.service": {
+                    "job": "start",
+                    "result": "failed",
+                    "journal": ["app[42]: cannot bind to port 80"],
+                },
+                "system-manager.target": { "job": "start", "result": "done" },
+                "worker.service": { "unsettled": "restarted 3 time(s)" },
+            })
+        );
+    }
+
+    #[test]
+    fn units_follow_the_changes_of_the_generation() {
+        let store = tempfile::tempdir().unwrap();
+        let (old_gen, new_gen) = (store.path().join("old"), store.path().join("new"));
+        let kept = unit(&old_gen, "kept.service", "[Service]\nExecStart=/bin/kept\n");
+        let old_services = Services::from(vec![
+            kept.clone(),
+            unit(&old_gen, "changed.service", "[Service]\nExecStart=/bin/a\n"),
+            unit(&old_gen, "removed.service", "[Service]\nExecStart=/bin/a\n"),
+            unit(
+                &old_gen,
+                "kept-on-removal.service",
+                "[Unit]\nX-StopOnRemoval=false\n[Service]\nExecStart=/bin/a\n",
+            ),
+            unit(
+                &old_gen,
+                "not-restarted.service",
+                "[Service]\nX-RestartIfChanged=false\nExecStart=/bin/a\n",
+            ),
+        ]);
+        let services = Services::from(vec![
+            kept,
+            unit(&new_gen, "changed.service", "[Service]\nExecStart=/bin/b\n"),
+            unit(&new_gen, "added.service", "[Service]\nExecStart=/bin/b\n"),
+            unit(
+                &new_gen,
+                "not-restarted.service",
+                "[Service]\nX-RestartIfChanged=false\nExecStart=/bin/b\n",
+            ),
+            masked("distro.service"),
+        ]);
+        let service_manager = FakeServiceManager::new().with_running(&[
+            "kept.service",
+            "changed.service",
+            "removed.service",
+            "kept-on-removal.service",
+            "not-restarted.service",
+            "distro.service",
+        ]);
+
+        let activation = switch(&service_manager, &services, &old_services);
+
+        assert_eq!(
+            service_manager.calls(),
+            [
+                "stop changed.service",
+                "stop distro.service",
+                "stop removed.service",
+                "start changed.service",
+                "start system-manager.target",
+                "mask distro.service",
+                "daemon-reload",
+            ]
+        );
+        assert!(activation.failed_units().is_empty());
+        assert_eq!(
+            activation.services["distro.service"].mask_mode,
+            Some(masks::MaskMode::Persistent)
+        );
+        for unit in [
+            "kept.service",
+            "kept-on-removal.service",
+            "not-restarted.service",
+        ] {
+            assert!(service_manager.is_running(unit), "{unit} should still run");
+        }
+        assert!(!service_manager.is_running("removed.service"));
+    }
+
+    #[test]
+    fn unmasked_units_are_started_when_wanted() {
+        let mut old_services =
+            Services::from(vec![masked("getty.service"), masked("debug.service")]);
+        masks::record(&mut old_services, masks::MaskMode::Persistent);
+        let service_manager = FakeServiceManager::new()
+            .with_running(&["multi-user.target"])
+            .with_stopped(&["getty.service", "debug.service"])
+            .with_wanted_by("getty.service", &["multi-user.target"]);
+
+        switch(&service_manager, &Services::new(), &old_services);
+
+        assert_eq!(
+            service_manager.calls(),
+            [
+                "unmask debug.service getty.service",
+                "daemon-reload",
+                "start getty.service",
+                "start system-manager.target",
+            ]
+        );
+    }
+
+    #[test]
+    fn only_active_targets_are_started_again() {
+        let store = tempfile::tempdir().unwrap();
+        let (old_gen, new_gen) = (store.path().join("old"), store.path().join("new"));
+        let old_services = Services::from(vec![
+            unit(&old_gen, "app.target", "[Unit]\nConflicts=a.service\n"),
+            unit(
+                &old_gen,
+                "maintenance.target",
+                "[Unit]\nConflicts=a.service\n",
+            ),
+        ]);
+        let services = Services::from(vec![
+            unit(&new_gen, "app.target", "[Unit]\nConflicts=b.service\n"),
+            unit(
+                &new_gen,
+                "maintenance.target",
+                "[Unit]\nConflicts=b.service\n",
+            ),
+        ]);
+        let service_manager = FakeServiceManager::new()
+            .with_running(&["app.target"])
+            .with_stopped(&["maintenance.target"]);
+
+        switch(&service_manager, &services, &old_services);
+
+        assert!(service_manager
+            .calls()
+            .contains(&"start app.target".to_owned()));
+        assert!(!service_manager
+            .calls()
+            .contains(&"start maintenance.target".to_owned()));
+    }
+
+    #[test]
+    fn failed_jobs_are_reported() {
+        let store = tempfile::tempdir().unwrap();
+        let services = Services::from(vec![unit(
+            store.path(),
+            "app.service",
+            "[Unit]\nDescription=App\n",
+        )]);
+        let service_manager = FakeServiceManager::new()
+            .with_running(&["app.service"])
+            .with_result("system-manager.target", "dependency");
+
+        let activation = switch(&service_manager, &services, &Services::new());
+
+        assert_eq!(service_manager.calls(), ["start system-manager.target"]);
+        assert_eq!(
+            activation.outcomes["system-manager.target"].result,
+            "dependency"
+        );
+        assert_eq!(activation.failed_units(), ["system-manager.target"]);
+    }
+
+    #[test]
+    fn only_units_with_jobs_are_settled() {
+        let store = tempfile::tempdir().unwrap();
+        let (old_gen, new_gen) = (store.path().join("old"), store.path().join("new"));
+        let broken = unit(&old_gen, "broken.service", "[Service]\nExecStart=/bin/a\n");
+        let old_services = Services::from(vec![
+            broken.clone(),
+            unit(&old_gen, "app.service", "[Service]\nExecStart=/bin/a\n"),
+        ]);
+        let services = Services::from(vec![
+            broken,
+            unit(&new_gen, "app.service", "[Service]\nExecStart=/bin/b\n"),
+        ]);
+        let service_manager = FakeServiceManager::new()
+            .with_running(&["app.service"])
+            .with_failed(&["broken.service"]);
+
+        let activation = switch(&service_manager, &services, &old_services);
+
+        assert_eq!(
+            service_manager.calls(),
+            [
+                "stop app.service",
+                "start app.service",
+                "start system-manager.target",
+            ]
+        );
+        assert!(activation.unsettled.is_empty());
+        assert!(activation.failed_units().is_empty());
+    }
+
+    #[test]
+    fn deactivation_stops_and_unmasks_units() {
+        let store = tempfile::tempdir().unwrap();
+        let mut old_services = Services::from(vec![
+            unit(store.path(), "app.service", "[Service]\nExecStart=/bin/a\n"),
+            masked("distro.service"),
+        ]);
+        masks::record(&mut old_services, masks::MaskMode::Runtime);
+        let service_manager =
+            FakeServiceManager::new().with_running(&["app.service", "system-manager.target"]);
+
+        let services = deactivate_units(
+            &service_manager,
+            old_services,
+            &EnabledUnits::new(),
+            OPTIONS.job_timeout,
+        )
+        .unwrap();
+
+        assert!(services.is_empty());
+        assert_eq!(
+            service_manager.calls(),
+            [
+                "stop app.service",
+                "stop system-manager.target",
+                "unmask --runtime distro.service",
+                "daemon-reload",
+            ]
+        );
+        assert!(!service_manager.is_running("app.service"));
+    }
+
+    #[test]
+    fn deactivation_stops_the_instances_of_templates() {
+        let store = tempfile::tempdir().unwrap();
+        let old_services = Services::from(vec![unit(
+            store.path(),
+            "worker@.service",
+            "[Service]\nExecStart=/bin/worker %i\n",
+        )]);
+        let service_manager = FakeServiceManager::new()
+            .with_running(&["worker@a.service", "system-manager.target"])
+            .with_stopped(&["worker@b.service"]);
+
+        deactivate_units(
+            &service_manager,
+            old_services,
+            &EnabledUnits::new(),
+            OPTIONS.job_timeout,
+        )
+        .unwrap();
+
+        assert_eq!(
+            service_manager.calls(),
+            [
+                "stop system-manager.target",
+                "stop worker@a.service",
+                "daemon-reload",
+            ]
+        );
+        assert!(!service_manager.is_running("worker@a.service"));
+    }
+
+    #[test]
+    fn deactivation_fails_on_pending_stop_jobs() {
+        let store = tempfile::tempdir().unwrap();
+        let old_services = Services::from(vec![unit(
+            store.path(),
+            "app.service",
+            "[Service]\nExecStart=/bin/a\n",
+        )]);
+        let service_manager = FakeServiceManager::new()
+            .with_running(&["app.service"])
+            .with_result("app.service", "pending");
+
+        let result = deactivate_units(
+            &service_manager,
+            old_services,
+            &EnabledUnits::new(),
+            Duration::ZERO,
+        );
+
+        assert!(result.is_err());
+        assert!(!service_manager
+            .calls()
+            .contains(&"daemon-reload".to_owned()));
+    }
+}
@@ -0,0 +1,218 @@
+//! Restart or reload units whose drop-ins changed, even when their own store path did not.
+//!
+//! Profiles ship drop-ins (`foo.service.d/overrides.conf`) for units provided by the distro
+//! or by `systemd.packages`, which are not in services.json with a store path of their
+//! own. We fingerprint the unit file and the drop-ins of every unit in the systemd system
+//! directory before the /etc files get activated, and compare them with the new generation
+//! afterwards, including the units that only show up in the new generation.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs;
+use std::path::Path;
+
+use super::etc_triggers::fingerprint;
+use super::unit_actions::{changed_unit_action, UnitAction};
+use crate::unit_info::{dropin_files, parse_systemd_ini, parse_unit, UnitInfo};
+
+const UNIT_TYPES: &[&str] = &[
+    "service",
+    "socket",
+    "target",
+    "timer",
+    "path",
+    "mount",
+    "automount",
+    "swap",
+    "slice",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct UnitFingerprint {
+    unit_file: Option<u64>,
+    dropins: Option<u64>,
+}
+
+impl UnitFingerprint {
+    /// A unit with neither a unit file nor drop-ins.
+    const MISSING: UnitFingerprint = UnitFingerprint {
+        unit_file: None,
+        dropins: None,
+    };
+}
+
+#[derive(Debug)]
+struct UnitFiles {
+    fingerprint: UnitFingerprint,
+    info: Option<UnitInfo>,
+}
+
+/// The unit files and drop-ins of a systemd system directory, as they were before activation.
+#[derive(Debug, Default)]
+pub struct UnitFingerprints {
+    units: HashMap<String, UnitFiles>,
+}
+
+impl UnitFingerprints {
+    pub fn snapshot(system_dir: &Path) -> Self {
+        let units = unit_names(system_dir)
+            .into_iter()
+            .map(|name| {
+                let files = UnitFiles {
+                    fingerprint: unit_fingerprint(system_dir, &name),
+                    info: read_unit_files(system_dir, &name),
+                };
+                (name, files)
+            })
+            .collect();
+        Self { units }
+    }
+
+    /// The units whose unit file or drop-ins are now different in `system_dir`, with what
+    /// to do about them. This includes the units that got their first drop-in, or lost
+    /// their last one.
+    ///
+    /// Units whose unit file was added or removed are left to the rest of the activation.
+    pub fn changed_units(&self, system_dir: &Path) -> BTreeMap<String, UnitAction> {
+        let names: BTreeSet<String> = self
+            .units
+            .keys()
+            .cloned()
+            .chain(unit_names(system_dir))
+            .collect();
+        let mut changed = BTreeMap::new();
+        for name in names {
+            let before = self.units.get(&name);
+            let before_fingerprint = before.map_or(UnitFingerprint::MISSING, |b| b.fingerprint);
+            let after = unit_fingerprint(system_dir, &name);
+            if after == before_fingerprint
+                || after.unit_file.is_some() != before_fingerprint.unit_file.is_some()
+            {
+                continue;
+            }
+            let old_info = before.and_then(|b| b.info.as_ref());
+            let new_info = read_unit_files(system_dir, &name);
+            let action = changed_unit_action(&name, old_info, new_info.as_ref());
+            log::debug!("The unit files of {name} changed: {action:?}");
+            changed.insert(name, action);
+        }
+        changed
+    }
+}
+
+/// The units with a unit file or a drop-in directory in `system_dir`.
+fn unit_names(system_dir: &Path) -> Vec<String> {
+    let Ok(entries) = fs::read_dir(system_dir) else {
+        return Vec::new();
+    };
+    let mut names: Vec<String> = entries
+        .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+        .map(|name| name.strip_suffix(".d").map_or(name.clone(), str::to_owned))
+        .filter(|name| {
+            name.rsplit_once('.')
+                .is_some_and(|(_, suffix)| UNIT_TYPES.contains(&suffix))
+        })
+        .collect();
+    names.sort();
+    names.dedup();
+    names
+}
+
+fn unit_fingerprint(system_dir: &Path, name: &str) -> UnitFingerprint {
+    UnitFingerprint {
+        unit_file: fingerprint(&system_dir.join(name)),
+        dropins: fingerprint(&system_dir.join(format!("{name}.d"))),
+    }
+}
+
+/// Parse the unit file and its drop-ins, or only the drop-ins when the unit file lives
+/// in the distro's unit directory.
+fn read_unit_files(system_dir: &Path, name: &str) -> Option<UnitInfo> {
+    let unit_file = system_dir.join(name);
+    if unit_file.exists() {
+        return parse_unit(&unit_file)
+            .inspect_err(|e| log::debug!("unable to parse unit file of {name}: {e}"))
+            .ok();
+    }
+    let mut info = UnitInfo::new();
+    for dropin in dropin_files(&unit_file).ok()? {
+        let content = fs::read(&dropin).ok()?;
+        parse_systemd_ini(&mut info, content.as_slice()).ok()?;
+    }
+    Some(info)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn changed_dropins_restart_their_unit() {
+        let dir = tempfile::tempdir().unwrap();
+        let system_dir = dir.path();
+        fs::write(
+            system_dir.join("app.service"),
+            "[Service]\nExecStart=/bin/app\n",
+        )
+        .unwrap();
+        fs::create_dir(system_dir.join("distro.service.d")).unwrap();
+        fs::write(
+            system_dir.join("distro.service.d/overrides.conf"),
+            "[Service]\nEnvironment=LEVEL=1\n",
+        )
+        .unwrap();
+        fs::create_dir(system_dir.join("multi-user.target.wants")).unwrap();
+
+        assert_eq!(unit_names(system_dir), ["app.service", "distro.service"]);
+        let before = UnitFingerprints::snapshot(system_dir);
+        assert!(before.changed_units(system_dir).is_empty());
+
+        fs::write(
+            system_dir.join("distro.service.d/overrides.conf"),
+            "[Service]\nEnvironment=LEVEL=2\n",
+        )
+        .unwrap();
+        fs::create_dir(system_dir.join("app.service.d")).unwrap();
+        fs::write(
+            system_dir.join("app.service.d/reload.conf"),
+            "[Service]\nX-ReloadIfChanged=true\n",
+        )
+        .unwrap();
+        assert_eq!(
+            before.changed_units(system_dir),
+            BTreeMap::from([
+                ("app.service".to_owned(), UnitAction::Reload),
+                ("distro.service".to_owned(), UnitAction::StopStart),
+            ])
+        );
+    }
+
+    #[test]
+    fn first_and_last_dropins_restart_their_unit() {
+        let dir = tempfile::tempdir().unwrap();
+        let system_dir = dir.path();
+        let before = UnitFingerprints::snapshot(system_dir);
+
+        fs::create_dir(system_dir.join("foo.service.d")).unwrap();
+        fs::write(
+            system_dir.join("foo.service.d/override.conf"),
+            "[Service]\nEnvironment=LEVEL=1\n",
+        )
+        .unwrap();
+        fs::write(
+            system_dir.join("new.service"),
+            "[Service]\nExecStart=/bin/new\n",
+        )
+        .unwrap();
+        assert_eq!(
+            before.changed_units(system_dir),
+            BTreeMap::from([("foo.service".to_owned(), UnitAction::StopStart)])
+        );
+
+        let before = UnitFingerprints::snapshot(system_dir);
+        fs::remove_dir_all(system_dir.join("foo.service.d")).unwrap();
+        assert_eq!(
+            before.changed_units(system_dir),
+            BTreeMap::from([("foo.service".to_owned(), UnitAction::StopStart)])
+        );
+    }
+}
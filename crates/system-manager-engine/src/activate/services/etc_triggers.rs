@@ -0,0 +1,244 @@
+//! Restart or reload units when the /etc entries they declare change.
+//!
+//! Units list the entries they depend on with `X-RestartOnEtcChange=` and
+//! `X-ReloadOnEtcChange=` in their `[Unit]` section, relative to /etc or absolute. We take
+//! a fingerprint of these entries before the /etc files get activated, and compare it with
+//! the content of the new generation afterwards.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeSet, HashMap};
+use std::fs::{self, Metadata};
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use super::Services;
+use crate::activate::etc_files::validate_target;
+use crate::unit_info::{parse_unit, UnitInfo};
+
+const RESTART_KEY: &str = "X-RestartOnEtcChange";
+const RELOAD_KEY: &str = "X-ReloadOnEtcChange";
+
+#[derive(Debug, Default)]
+struct UnitTriggers {
+    restart: Vec<PathBuf>,
+    reload: Vec<PathBuf>,
+}
+
+/// The watched /etc entries of the units of a generation, as they were before activation.
+#[derive(Debug, Default)]
+pub struct EtcTriggers {
+    units: HashMap<String, UnitTriggers>,
+    fingerprints: HashMap<PathBuf, Option<u64>>,
+}
+
+/// The units whose watched /etc entries changed.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct TriggeredUnits {
+    pub restart: BTreeSet<String>,
+    pub reload: BTreeSet<String>,
+}
+
+impl EtcTriggers {
+    /// Read the triggers declared by `services`, and fingerprint the entries in `etc_dir`.
+    pub fn snapshot(services: &Services, etc_dir: &Path) -> Self {
+        let units: HashMap<String, UnitTriggers> = services
+            .iter()
+            .filter_map(|(name, cfg)| Some((name, cfg.unit_file()?)))
+            .filter_map(|(name, unit_file)| {
+                let unit_info = parse_unit(unit_file)
+                    .inspect_err(|e| log::debug!("unable to parse unit file of {name}: {e}"))
+                    .ok()?;
+                let triggers = UnitTriggers {
+                    restart: watched_paths(&unit_info, RESTART_KEY, etc_dir),
+                    reload: watched_paths(&unit_info, RELOAD_KEY, etc_dir),
+                };
+                (!triggers.restart.is_empty() || !triggers.reload.is_empty())
+                    .then(|| (name.clone(), triggers))
+            })
+            .collect();
+        Self::fingerprinted(units)
+    }
+
+    fn fingerprinted(units: HashMap<String, UnitTriggers>) -> Self {
+        let fingerprints = units
+            .values()
+            .flat_map(|t| t.restart.iter().chain(&t.reload))
+            .map(|path| (path.clone(), fingerprint(path)))
+            .collect();
+        Self {
+            units,
+            fingerprints,
+        }
+    }
+
+    /// Compare the watched entries with their content at the time of the snapshot.
+    ///
+    /// A unit whose restart and reload entries both changed only gets restarted.
+    pub fn changed_units(&self) -> TriggeredUnits {
+        let changed: HashMap<&Path, bool> = self
+            .fingerprints
+            .iter()
+            .map(|(path, before)| (path.as_path(), fingerprint(path) != *before))
+            .collect();
+        fn first_changed<'a>(
+            paths: &'a [PathBuf],
+            changed: &HashMap<&Path, bool>,
+        ) -> Option<&'a PathBuf> {
+            paths
+                .iter()
+                .find(|p| changed.get(p.as_path()).copied().unwrap_or(false))
+        }
+        let mut triggered = TriggeredUnits::default();
+        for (name, triggers) in &self.units {
+            if let Some(path) = first_changed(&triggers.restart, &changed) {
+                log::info!("Restarting {name}: {} changed", path.display());
+                triggered.restart.insert(name.clone());
+            } else if let Some(path) = first_changed(&triggers.reload, &changed) {
+                log::info!("Reloading {name}: {} changed", path.display());
+                triggered.reload.insert(name.clone());
+            }
+        }
+        triggered
+    }
+}
+
+/// The entries listed in `key`, space separated and possibly repeated.
+///
+/// Entries must stay inside /etc, like the targets of the etc files.
+fn watched_paths(unit_info: &UnitInfo, key: &str, etc_dir: &Path) -> Vec<PathBuf> {
+    unit_info
+        .get("Unit")
+        .and_then(|section| section.get(key))
+        .into_iter()
+        .flatten()
+        .flat_map(|value| value.split_whitespace())
+        .filter_map(|path| {
+            let target = Path::new(path.strip_prefix("/etc/").unwrap_or(path));
+            validate_target(target)
+                .inspect_err(|e| log::warn!("Ignoring {key}={path}: {e}"))
+                .ok()?;
+            Some(etc_dir.join(target))
+        })
+        .collect()
+}
+
+/// A hash of the content of `path`. Directories are hashed recursively.
+///
+/// The watched entry itself is followed, since /etc entries are links into the store, but
+/// the links found inside a directory only are when they point to a file. Linked
+/// directories are hashed by their target, so that a link loop cannot trap us.
+///
+/// Missing entries have no fingerprint, so that creating or removing them counts as a change.
+pub(super) fn fingerprint(path: &Path) -> Option<u64> {
+    let mut hasher = DefaultHasher::new();
+    hash_entry(path, &fs::metadata(path).ok()?, &mut hasher).ok()?;
+    Some(hasher.finish())
+}
+
+fn hash_entry(path: &Path, metadata: &Metadata, hasher: &mut DefaultHasher) -> io::Result<()> {
+    if metadata.is_dir() {
+        let mut entries = fs::read_dir(path)?
+            .map(|entry| entry.map(|e| e.file_name()))
+            .collect::<io::Result<Vec<_>>>()?;
+        entries.sort();
+        for name in entries {
+            name.hash(hasher);
+            // Dangling links inside a directory still count as entries.
+            let _ = hash_nested_entry(&path.join(name), hasher);
+        }
+    } else {
+        fs::read(path)?.hash(hasher);
+    }
+    Ok(())
+}
+
+fn hash_nested_entry(path: &Path, hasher: &mut DefaultHasher) -> io::Result<()> {
+    let metadata = fs::symlink_metadata(path)?;
+    if metadata.is_symlink() {
+        fs::read_link(path)?.hash(hasher);
+        let target = fs::metadata(path)?;
+        if !target.is_dir() {
+            return hash_entry(path, &target, hasher);
+        }
+        return Ok(());
+    }
+    hash_entry(path, &metadata, hasher)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::unit_info::parse_systemd_ini;
+
+    #[test]
+    fn watched_entries_are_compared_with_the_snapshot() {
+        let etc = tempfile::tempdir().unwrap();
+        fs::create_dir(etc.path().join("nginx")).unwrap();
+        fs::write(etc.path().join("nginx/nginx.conf"), "worker_processes 1;").unwrap();
+        fs::write(etc.path().join("motd"), "hello").unwrap();
+
+        let mut unit_info = UnitInfo::new();
+        parse_systemd_ini(
+            &mut unit_info,
+            "[Unit]\nX-RestartOnEtcChange=nginx\nX-ReloadOnEtcChange=/etc/motd\n".as_bytes(),
+        )
+        .unwrap();
+        let triggers = |unit_info: &UnitInfo| UnitTriggers {
+            restart: watched_paths(unit_info, RESTART_KEY, etc.path()),
+            reload: watched_paths(unit_info, RELOAD_KEY, etc.path()),
+        };
+        let snapshot = |unit_info: &UnitInfo| {
+            EtcTriggers::fingerprinted(HashMap::from([(
+                "nginx.service".to_owned(),
+                triggers(unit_info),
+            )]))
+        };
+
+        let before = snapshot(&unit_info);
+        assert_eq!(before.changed_units(), TriggeredUnits::default());
+
+        fs::write(etc.path().join("motd"), "bye").unwrap();
+        assert_eq!(
+            before.changed_units(),
+            TriggeredUnits {
+                restart: BTreeSet::new(),
+                reload: BTreeSet::from(["nginx.service".into()]),
+            }
+        );
+
+        let before = snapshot(&unit_info);
+        fs::write(etc.path().join("nginx/mime.types"), "text/html html;").unwrap();
+        fs::write(etc.path().join("motd"), "hello again").unwrap();
+        assert_eq!(
+            before.changed_units(),
+            TriggeredUnits {
+                restart: BTreeSet::from(["nginx.service".into()]),
+                reload: BTreeSet::new(),
+            }
+        );
+    }
+
+    #[test]
+    fn watched_entries_stay_inside_etc_and_links_are_not_recursed_into() {
+        let etc = tempfile::tempdir().unwrap();
+        fs::create_dir(etc.path().join("app")).unwrap();
+        fs::write(etc.path().join("app/app.conf"), "a").unwrap();
+        std::os::unix::fs::symlink("..", etc.path().join("app/loop")).unwrap();
+
+        let mut unit_info = UnitInfo::new();
+        parse_systemd_ini(
+            &mut unit_info,
+            "[Unit]\nX-RestartOnEtcChange=app ../shadow /etc/../shadow /var/lib/app\n".as_bytes(),
+        )
+        .unwrap();
+        let watched = watched_paths(&unit_info, RESTART_KEY, etc.path());
+        assert_eq!(watched, vec![etc.path().join("app")]);
+
+        let before = fingerprint(&watched[0]);
+        assert!(before.is_some());
+        assert_eq!(fingerprint(&watched[0]), before);
+        fs::write(etc.path().join("app/app.conf"), "b").unwrap();
+        assert_ne!(fingerprint(&watched[0]), before);
+    }
+}
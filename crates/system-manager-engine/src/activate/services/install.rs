@@ -0,0 +1,256 @@
+//! Enable units through the `[Install]` section of their unit file, like `systemctl enable`.
+//!
+//! The units generated by the modules are pulled in by `system-manager.target`, but units
+//! written in the distro style rely on `WantedBy=` or `RequiredBy=` to be pulled in by
+//! targets like `network-online.target` or `graphical.target`. We let systemd create the
+//! symlinks for their `WantedBy=`, `RequiredBy=`, `Alias=` and `Also=`, record them in the
+//! state, and remove them again once the unit is gone or on deactivation.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use super::{running_units, ServiceConfig, Services};
+use crate::systemd;
+use crate::unit_info::parse_install_section;
+
+const INSTALL_KEYS: &[&str] = &["WantedBy", "RequiredBy", "Alias", "Also"];
+
+/// A symlink created by systemd to enable a unit.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EnabledLink {
+    pub link: PathBuf,
+    pub target: PathBuf,
+}
+
+/// The symlinks created to enable units, by the name of the unit they point to.
+pub type EnabledUnits = BTreeMap<String, Vec<EnabledLink>>;
+
+/// The units enabled after the activation.
+#[derive(Debug, Default)]
+pub struct Enablement {
+    pub enabled: EnabledUnits,
+    /// The newly enabled units wanted or required by a running target, which need to be
+    /// started like systemd would have done at boot.
+    pub to_start: Vec<String>,
+}
+
+/// The `[Install]` section of the unit file, if it has keys to enable the unit with.
+fn install_section(name: &str, cfg: &ServiceConfig) -> Option<HashMap<String, Vec<String>>> {
+    if cfg.masked {
+        return None;
+    }
+    let section = parse_install_section(cfg.unit_file()?)
+        .inspect_err(|e| log::debug!("unable to parse the [Install] section of {name}: {e}"))
+        .ok()?;
+    INSTALL_KEYS
+        .iter()
+        .any(|key| section.get(*key).is_some_and(|values| !values.is_empty()))
+        .then_some(section)
+}
+
+/// Enable the units of `services` that have an `[Install]` section, and disable the ones
+/// we enabled before that are gone or whose unit file changed.
+///
+/// Units listed with `preset` follow the preset files of the host instead. The daemon is
+/// reloaded when symlinks changed.
+pub fn enable_units(
+    service_manager: &dyn systemd::ServiceManagerBackend,
+    services: &Services,
+    old_services: &Services,
+    old_enabled: &EnabledUnits,
+    runtime: bool,
+) -> anyhow::Result<Enablement> {
+    let installable: BTreeMap<&str, HashMap<String, Vec<String>>> = services
+        .iter()
+        .filter_map(|(name, cfg)| Some((name.as_str(), install_section(name, cfg)?)))
+        .collect();
+    // Units enabled through the `Also=` of another unit stay enabled along with it.
+    let also: BTreeSet<&str> = installable
+        .values()
+        .filter_map(|section| section.get("Also"))
+        .flatten()
+        .map(String::as_str)
+        .collect();
+    let unchanged = |name: &str| {
+        let unit_file_changed = services.get(name).map(|cfg| &cfg.store_path)
+            != old_services.get(name).map(|cfg| &cfg.store_path);
+        also.contains(name) || (installable.contains_key(name) && !unit_file_changed)
+    };
+    let (mut enabled, stale): (EnabledUnits, EnabledUnits) = old_enabled
+        .clone()
+        .into_iter()
+        .partition(|(name, _)| unchanged(name));
+    if !stale.is_empty() {
+        log::info!(
+            "Disabling {}",
+            stale.keys().cloned().collect::<Vec<_>>().join(", ")
+        );
+        disable_units(service_manager, &stale, &[runtime]);
+    }
+
+    let (preset, enable): (Vec<&str>, Vec<&str>) = installable
+        .keys()
+        .partition(|name| services.get(**name).is_some_and(|cfg| cfg.preset));
+    let mut changes = Vec::new();
+    if !enable.is_empty() {
+        changes.extend(service_manager.enable_unit_files(&enable, runtime)?);
+    }
+    if !preset.is_empty() {
+        changes.extend(service_manager.preset_unit_files(&preset, runtime)?);
+    }
+    let newly_enabled = record_changes(&mut enabled, &changes);
+
+    if !stale.is_empty() || !changes.is_empty() {
+        log::info!("Reloading systemd daemon after enabling units...");
+        service_manager.daemon_reload()?;
+    }
+
+    let targets: BTreeMap<&str, BTreeSet<&str>> = newly_enabled
+        .iter()
+        .map(|name| {
+            let targets = enabled
+                .get(name)
+                .into_iter()
+                .flatten()
+                .filter_map(|link| wanting_target(&link.link))
+                .collect();
+            (name.as_str(), targets)
+        })
+        .collect();
+    let all_targets: Vec<&str> = targets.values().flatten().copied().collect();
+    let running = running_units(service_manager, &all_targets);
+    let to_start = targets
+        .into_iter()
+        .filter(|(_, targets)| targets.iter().any(|target| running.contains(*target)))
+        .map(|(name, _)| name.to_owned())
+        .collect();
+    Ok(Enablement { enabled, to_start })
+}
+
+/// Record the symlinks created and removed by systemd. Returns the units that were not
+/// enabled before.
+fn record_changes(enabled: &mut EnabledUnits, changes: &[systemd::UnitFileChange]) -> Vec<String> {
+    let mut newly_enabled = Vec::new();
+    for change in changes {
+        log::debug!(
+            "Unit file change: {} {} -> {}",
+            change.change_type,
+            change.file,
+            change.destination
+        );
+        match change.change_type.as_str() {
+            "symlink" => {
+                let target = PathBuf::from(&change.destination);
+                let Some(name) = target.file_name().and_then(|name| name.to_str()) else {
+                    continue;
+                };
+                let link = EnabledLink {
+                    link: PathBuf::from(&change.file),
+                    target: target.clone(),
+                };
+                let links = enabled.entry(name.to_owned()).or_default();
+                if links.is_empty() {
+                    newly_enabled.push(name.to_owned());
+                }
+                if !links.contains(&link) {
+                    links.push(link);
+                }
+            }
+            "unlink" => {
+                for links in enabled.values_mut() {
+                    links.retain(|link| link.link != Path::new(&change.file));
+                }
+            }
+            _ => (),
+        }
+    }
+    enabled.retain(|_, links| !links.is_empty());
+    newly_enabled
+}
+
+/// The target wanting or requiring the unit, for links in `.wants` and `.requires`.
+fn wanting_target(link: &Path) -> Option<&str> {
+    let dir = link.parent()?.file_name()?.to_str()?;
+    dir.strip_suffix(".wants")
+        .or_else(|| dir.strip_suffix(".requires"))
+}
+
+/// Disable the units, and remove the links systemd left behind, for instance because the
+/// unit file is already gone. Errors are logged.
+pub fn disable_units(
+    service_manager: &dyn systemd::ServiceManagerBackend,
+    units: &EnabledUnits,
+    runtime: &[bool],
+) {
+    let names: Vec<&str> = units.keys().map(String::as_str).collect();
+    for runtime in runtime {
+        if let Err(e) = service_manager.disable_unit_files(&names, *runtime) {
+            log::warn!("Error disabling units (runtime={runtime}): {e}");
+        }
+    }
+    for link in units.values().flatten() {
+        if fs::read_link(&link.link).is_ok_and(|target| target == link.target) {
+            log::debug!("Removing {}", link.link.display());
+            if let Err(e) = fs::remove_file(&link.link) {
+                log::error!("Error removing {}: {e}", link.link.display());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn change(change_type: &str, file: &str, destination: &str) -> systemd::UnitFileChange {
+        systemd::UnitFileChange {
+            change_type: change_type.to_owned(),
+            file: file.to_owned(),
+            destination: destination.to_owned(),
+        }
+    }
+
+    #[test]
+    fn symlinks_are_recorded_by_unit() {
+        let mut enabled = EnabledUnits::from([(
+            "old.service".to_owned(),
+            vec![EnabledLink {
+                link: "/etc/systemd/system/multi-user.target.wants/old.service".into(),
+                target: "/etc/systemd/system/old.service".into(),
+            }],
+        )]);
+        let newly_enabled = record_changes(
+            &mut enabled,
+            &[
+                change(
+                    "symlink",
+                    "/etc/systemd/system/network-online.target.wants/app.service",
+                    "/etc/systemd/system/app.service",
+                ),
+                change(
+                    "symlink",
+                    "/etc/systemd/system/web.service",
+                    "/etc/systemd/system/app.service",
+                ),
+                change(
+                    "unlink",
+                    "/etc/systemd/system/multi-user.target.wants/old.service",
+                    "",
+                ),
+            ],
+        );
+        assert_eq!(newly_enabled, ["app.service"]);
+        assert_eq!(enabled.keys().collect::<Vec<_>>(), ["app.service"]);
+        assert_eq!(
+            enabled["app.service"]
+                .iter()
+                .filter_map(|link| wanting_target(&link.link))
+                .collect::<Vec<_>>(),
+            ["network-online.target"]
+        );
+    }
+}
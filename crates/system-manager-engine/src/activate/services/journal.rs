@@ -0,0 +1,78 @@
+//! The journal of units that failed to activate, so that the activation report shows why.
+//!
+//! We ask journalctl for the last lines logged by the unit, and by systemd about the unit,
+//! since the activation started. `--invocation` would be more precise but needs systemd 257.
+
+use std::process;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The last `lines` journal lines of `unit`, logged after `since` when given.
+///
+/// Errors are logged, we don't fail the activation because the journal can't be read.
+pub fn unit_journal(unit: &str, since: Option<SystemTime>, lines: usize) -> Vec<String> {
+    if lines == 0 {
+        return Vec::new();
+    }
+    let output = match process::Command::new("journalctl")
+        .args(journalctl_args(unit, since, lines))
+        .stdin(process::Stdio::null())
+        .stderr(process::Stdio::piped())
+        .output()
+    {
+        Ok(output) => output,
+        Err(e) => {
+            log::warn!("Cannot run journalctl to read the journal of {unit}: {e}");
+            return Vec::new();
+        }
+    };
+    if !output.status.success() {
+        log::warn!(
+            "Cannot read the journal of {unit}: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+        return Vec::new();
+    }
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(str::to_owned)
+        .collect()
+}
+
+fn journalctl_args(unit: &str, since: Option<SystemTime>, lines: usize) -> Vec<String> {
+    let mut args = vec![
+        format!("--unit={unit}"),
+        format!("--lines={lines}"),
+        "--output=short-iso".to_owned(),
+        "--no-pager".to_owned(),
+        "--quiet".to_owned(),
+    ];
+    if let Some(since) = since.and_then(|t| t.duration_since(UNIX_EPOCH).ok()) {
+        args.push(format!("--since=@{}", since.as_secs()));
+    }
+    args
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn journal_is_limited_to_the_activation() {
+        let since = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        assert_eq!(
+            journalctl_args("app.service", Some(since), 20),
+            [
+                "--unit=app.service",
+                "--lines=20",
+                "--output=short-iso",
+                "--no-pager",
+                "--quiet",
+                "--since=@1700000000",
+            ]
+        );
+        assert!(!journalctl_args("app.service", None, 20)
+            .iter()
+            .any(|arg| arg.starts_with("--since")));
+    }
+}
@@ -0,0 +1,203 @@
+//! Masked units, linked to /dev/null in /etc/systemd/system, or in /run/systemd/system for
+//! the runtime masks of an ephemeral activation.
+//!
+//! The mode of each mask is recorded in the state along with the unit, so that we remove
+//! the right link when the unit leaves `systemd.maskedUnits`, when a generation is activated
+//! in the other mode, and on deactivation.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use serde::{Deserialize, Serialize};
+
+use super::{running_units, Services};
+use crate::systemd;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum MaskMode {
+    /// Masked in /run/systemd/system, until the next reboot.
+    Runtime,
+    /// Masked in /etc/systemd/system.
+    Persistent,
+}
+
+impl MaskMode {
+    pub fn new(ephemeral: bool) -> Self {
+        if ephemeral {
+            MaskMode::Runtime
+        } else {
+            MaskMode::Persistent
+        }
+    }
+
+    pub fn runtime(self) -> bool {
+        self == MaskMode::Runtime
+    }
+}
+
+/// The masks to remove, with their modes: units masked by the previous generation that are
+/// no longer masked, or that are masked in the other `mode` now.
+///
+/// The mode of masks made before it was recorded is unknown. Both modes are removed when the
+/// unit is no longer masked, and we assume it didn't change otherwise.
+pub fn masks_to_remove(
+    services: &Services,
+    old_services: &Services,
+    mode: MaskMode,
+) -> BTreeMap<String, Vec<MaskMode>> {
+    old_services
+        .iter()
+        .filter(|(_, old)| old.masked)
+        .filter_map(|(name, old)| {
+            let still_masked = services.get(name).is_some_and(|cfg| cfg.masked);
+            let modes = match old.mask_mode {
+                Some(old_mode) if still_masked && old_mode == mode => return None,
+                Some(old_mode) => vec![old_mode],
+                None if still_masked => return None,
+                None => vec![MaskMode::Persistent, MaskMode::Runtime],
+            };
+            Some((name.clone(), modes))
+        })
+        .collect()
+}
+
+/// The modes of the masks of `services`, both when unknown.
+pub fn recorded_masks(services: &Services) -> BTreeMap<String, Vec<MaskMode>> {
+    services
+        .iter()
+        .filter(|(_, cfg)| cfg.masked)
+        .map(|(name, cfg)| {
+            let modes = cfg.mask_mode.map_or_else(
+                || vec![MaskMode::Persistent, MaskMode::Runtime],
+                |mode| vec![mode],
+            );
+            (name.clone(), modes)
+        })
+        .collect()
+}
+
+/// Keep the mode of the masks that don't change, for the state written when the
+/// activation fails before masking the units again.
+pub fn carry_over(services: &mut Services, old_services: &Services, mode: MaskMode) {
+    for (name, cfg) in services.iter_mut().filter(|(_, cfg)| cfg.masked) {
+        cfg.mask_mode = old_services
+            .get(name)
+            .and_then(|old| old.mask_mode)
+            .filter(|old_mode| *old_mode == mode);
+    }
+}
+
+/// Record the `mode` the masked units of `services` were masked in.
+pub fn record(services: &mut Services, mode: MaskMode) {
+    for (_, cfg) in services.iter_mut().filter(|(_, cfg)| cfg.masked) {
+        cfg.mask_mode = Some(mode);
+    }
+}
+
+/// Remove the `masks`. All of them are attempted, the first error is returned.
+pub fn unmask(
+    service_manager: &dyn systemd::ServiceManagerBackend,
+    masks: &BTreeMap<String, Vec<MaskMode>>,
+) -> anyhow::Result<()> {
+    let mut by_mode: BTreeMap<MaskMode, Vec<&str>> = BTreeMap::new();
+    for (name, modes) in masks {
+        for mode in modes {
+            by_mode.entry(*mode).or_default().push(name);
+        }
+    }
+    let mut result = Ok(());
+    for (mode, names) in by_mode {
+        log::info!("Unmasking ({mode:?}): {}", names.join(", "));
+        if let Err(e) = service_manager.unmask_unit_files(&names, mode.runtime()) {
+            log::error!("Error unmasking units ({mode:?}): {e}");
+            result = result.and(Err(e));
+        }
+    }
+    result
+}
+
+/// The units among `names` wanted or required by a running unit, which systemd would have
+/// started if they hadn't been masked.
+pub fn wanted_units(
+    service_manager: &dyn systemd::ServiceManagerBackend,
+    names: &[&str],
+) -> Vec<String> {
+    if names.is_empty() {
+        return Vec::new();
+    }
+    let units = match service_manager.list_units_by_patterns(&[], names) {
+        Ok(units) => units,
+        Err(e) => {
+            log::error!("Cannot list the unmasked units: {e}");
+            return Vec::new();
+        }
+    };
+    let wanted_by: BTreeMap<String, Vec<String>> = units
+        .iter()
+        .map(|unit| {
+            let wanted_by = service_manager
+                .wanted_by(unit)
+                .inspect_err(|e| log::warn!("Cannot read the dependents of {}: {e}", unit.name))
+                .unwrap_or_default();
+            (unit.name.clone(), wanted_by)
+        })
+        .collect();
+    let dependents: BTreeSet<&str> = wanted_by.values().flatten().map(String::as_str).collect();
+    let running = running_units(service_manager, &dependents.into_iter().collect::<Vec<_>>());
+    wanted_by
+        .into_iter()
+        .filter(|(_, dependents)| dependents.iter().any(|unit| running.contains(unit)))
+        .map(|(name, _)| name)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn services(json: &str) -> Services {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn masks_are_removed_in_their_recorded_mode() {
+        let old = services(
+            r#"{
+                "kept.service": { "masked": true, "maskMode": "persistent" },
+                "runtime.service": { "masked": true, "maskMode": "runtime" },
+                "unmasked.service": { "masked": true, "maskMode": "persistent" },
+                "legacy.service": { "masked": true }
+            }"#,
+        );
+        let new = services(
+            r#"{
+                "kept.service": { "masked": true },
+                "runtime.service": { "masked": true }
+            }"#,
+        );
+        assert_eq!(
+            masks_to_remove(&new, &old, MaskMode::Persistent),
+            BTreeMap::from([
+                (
+                    "legacy.service".to_owned(),
+                    vec![MaskMode::Persistent, MaskMode::Runtime]
+                ),
+                ("runtime.service".to_owned(), vec![MaskMode::Runtime]),
+                ("unmasked.service".to_owned(), vec![MaskMode::Persistent]),
+            ])
+        );
+
+        let mut new = new;
+        carry_over(&mut new, &old, MaskMode::Persistent);
+        assert_eq!(
+            recorded_masks(&new),
+            BTreeMap::from([
+                ("kept.service".to_owned(), vec![MaskMode::Persistent]),
+                (
+                    "runtime.service".to_owned(),
+                    vec![MaskMode::Persistent, MaskMode::Runtime]
+                ),
+            ])
+        );
+    }
+}
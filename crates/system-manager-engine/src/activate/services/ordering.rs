@@ -0,0 +1,127 @@
+//! Order the jobs of units that depend on each other.
+//!
+//! Systemd orders the jobs of a single transaction, but we queue one job per unit. We
+//! split the units in waves according to the `After=`, `Before=`, `Requires=` and
+//! `BindsTo=` of their unit files, and wait for the jobs of a wave before queueing the jobs
+//! of the next one.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::unit_info::UnitInfo;
+
+/// The `[Unit]` keys listing units that must be started before the unit.
+const AFTER_KEYS: &[&str] = &["After", "Requires", "BindsTo"];
+
+/// Split `units` in waves, each unit coming after the units it depends on.
+///
+/// `infos` holds the unit files of the units we know about, dependencies on units outside
+/// of `units` are ignored. The units of a dependency cycle end up together in the last wave.
+pub fn waves<'a>(units: &[&'a str], infos: &BTreeMap<&str, UnitInfo>) -> Vec<Vec<&'a str>> {
+    let wanted: BTreeSet<&'a str> = units.iter().copied().collect();
+    // The units each unit must wait for.
+    let mut pending: BTreeMap<&'a str, BTreeSet<&'a str>> =
+        wanted.iter().map(|unit| (*unit, BTreeSet::new())).collect();
+    for &unit in &wanted {
+        let Some(info) = infos.get(unit) else {
+            continue;
+        };
+        for dependency in unit_values(info, AFTER_KEYS) {
+            if let Some(dependency) = wanted.get(dependency) {
+                pending.entry(unit).or_default().insert(*dependency);
+            }
+        }
+        for dependent in unit_values(info, &["Before"]) {
+            if let Some(dependent) = wanted.get(dependent) {
+                pending.entry(*dependent).or_default().insert(unit);
+            }
+        }
+    }
+    for (unit, dependencies) in pending.iter_mut() {
+        dependencies.remove(unit);
+    }
+
+    let mut waves = Vec::new();
+    while !pending.is_empty() {
+        let ready: Vec<&'a str> = pending
+            .iter()
+            .filter(|(_, dependencies)| dependencies.is_empty())
+            .map(|(unit, _)| *unit)
+            .collect();
+        if ready.is_empty() {
+            let cycle: Vec<&'a str> = pending.keys().copied().collect();
+            log::warn!(
+                "Ordering cycle between {}, queueing their jobs together",
+                cycle.join(", ")
+            );
+            waves.push(cycle);
+            break;
+        }
+        for unit in &ready {
+            pending.remove(unit);
+        }
+        for dependencies in pending.values_mut() {
+            dependencies.retain(|dependency| !ready.contains(dependency));
+        }
+        waves.push(ready);
+    }
+    waves
+}
+
+/// The space separated values of `keys` in the `[Unit]` section.
+fn unit_values<'i>(info: &'i UnitInfo, keys: &'i [&str]) -> impl Iterator<Item = &'i str> {
+    keys.iter()
+        .filter_map(|key| info.get("Unit")?.get(*key))
+        .flatten()
+        .flat_map(|value| value.split_whitespace())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::unit_info::parse_systemd_ini;
+
+    fn unit(content: &str) -> UnitInfo {
+        let mut info = UnitInfo::new();
+        parse_systemd_ini(&mut info, content.as_bytes()).unwrap();
+        info
+    }
+
+    #[test]
+    fn dependencies_come_in_earlier_waves() {
+        let infos = BTreeMap::from([
+            (
+                "app.service",
+                unit("[Unit]\nAfter=network.target postgresql.service\nRequires=app.socket\n"),
+            ),
+            ("postgresql.service", unit("[Unit]\nBindsTo=data.mount\n")),
+            ("migrate.service", unit("[Unit]\nBefore=app.service\n")),
+        ]);
+        let units = [
+            "app.service",
+            "app.socket",
+            "data.mount",
+            "migrate.service",
+            "postgresql.service",
+        ];
+        assert_eq!(
+            waves(&units, &infos),
+            vec![
+                vec!["app.socket", "data.mount", "migrate.service"],
+                vec!["postgresql.service"],
+                vec!["app.service"],
+            ]
+        );
+    }
+
+    #[test]
+    fn cycles_end_up_in_the_last_wave() {
+        let infos = BTreeMap::from([
+            ("a.service", unit("[Unit]\nAfter=b.service\n")),
+            ("b.service", unit("[Unit]\nAfter=a.service\n")),
+        ]);
+        assert_eq!(
+            waves(&["a.service", "b.service", "c.service"], &infos),
+            vec![vec!["c.service"], vec!["a.service", "b.service"]]
+        );
+    }
+}
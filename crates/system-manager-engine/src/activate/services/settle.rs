@@ -0,0 +1,132 @@
+//! Watch the managed units for a while after activation.
+//!
+//! A job finishing with `done` only means that the unit got started: a `Type=simple`
+//! service crashing a few seconds later, or stuck in a restart loop, still looks
+//! successful. The window is set for all units on the command line, and per unit with
+//! `X-SystemManagerSettleSec=` in its `[Unit]` section.
+
+use std::collections::BTreeMap;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::systemd::{ServiceManagerBackend, UnitStatus};
+use crate::unit_info::{parse_timespan, UnitInfo};
+
+const SETTLE_KEY: &str = "X-SystemManagerSettleSec";
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Why units didn't settle, by unit name.
+pub type UnsettledUnits = BTreeMap<String, String>;
+
+struct WatchedUnit {
+    status: UnitStatus,
+    window: Duration,
+    restarts_before: Option<u32>,
+}
+
+/// The settle window of a unit, `default` unless its unit file overrides it.
+pub fn settle_window(unit_info: Option<&UnitInfo>, default: Duration) -> Duration {
+    unit_info
+        .and_then(|info| info.get("Unit"))
+        .and_then(|section| section.get(SETTLE_KEY))
+        .and_then(|values| values.last())
+        .and_then(|value| {
+            // Waiting forever makes no sense here.
+            parse_timespan(value)
+                .filter(|window| *window < Duration::MAX)
+                .or_else(|| {
+                    log::warn!("Ignoring invalid {SETTLE_KEY}={value}");
+                    None
+                })
+        })
+        .unwrap_or(default)
+}
+
+/// Watch `units` until their window elapsed, and report the ones that failed, are still
+/// activating, or got restarted by systemd in the meantime.
+pub fn settle(
+    service_manager: &dyn ServiceManagerBackend,
+    units: &BTreeMap<String, Duration>,
+) -> anyhow::Result<UnsettledUnits> {
+    let start = Instant::now();
+    let names: Vec<&str> = units.keys().map(AsRef::as_ref).collect();
+    if names.is_empty() {
+        return Ok(UnsettledUnits::new());
+    }
+    let mut watched: Vec<WatchedUnit> = service_manager
+        .list_units_by_patterns(&[], &names)?
+        .into_iter()
+        .filter_map(|status| {
+            let window = *units.get(&status.name)?;
+            let restarts_before = service_manager.n_restarts(&status).unwrap_or_default();
+            Some(WatchedUnit {
+                status,
+                window,
+                restarts_before,
+            })
+        })
+        .collect();
+    if let Some(longest) = watched.iter().map(|unit| unit.window).max() {
+        if !longest.is_zero() {
+            log::info!("Waiting up to {longest:?} for units to settle...");
+        }
+    }
+
+    let mut unsettled = UnsettledUnits::new();
+    while !watched.is_empty() {
+        let elapsed = start.elapsed();
+        watched.retain(|unit| {
+            if elapsed < unit.window {
+                return true;
+            }
+            if let Some(reason) = check(service_manager, unit) {
+                log::error!("Unit {} did not settle: {reason}", unit.status.name);
+                unsettled.insert(unit.status.name.clone(), reason);
+            }
+            false
+        });
+        if let Some(next) = watched.iter().map(|unit| unit.window - elapsed).min() {
+            thread::sleep(next.min(POLL_INTERVAL));
+        }
+    }
+    Ok(unsettled)
+}
+
+fn check(service_manager: &dyn ServiceManagerBackend, unit: &WatchedUnit) -> Option<String> {
+    let (state, sub_state) = service_manager
+        .unit_state(&unit.status)
+        .inspect_err(|e| log::warn!("Cannot read the state of {}: {e}", unit.status.name))
+        .ok()?;
+    match state.as_str() {
+        "failed" => return Some("failed".to_owned()),
+        "activating" => return Some(format!("still activating ({sub_state})")),
+        _ => {}
+    }
+    let restarts = service_manager.n_restarts(&unit.status).ok().flatten();
+    match (unit.restarts_before, restarts) {
+        (Some(before), Some(after)) if after > before => {
+            Some(format!("restarted {} time(s) by systemd", after - before))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::unit_info::parse_systemd_ini;
+
+    #[test]
+    fn units_can_override_the_window() {
+        let default = Duration::from_secs(5);
+        let mut info = UnitInfo::new();
+        parse_systemd_ini(
+            &mut info,
+            "[Unit]\nX-SystemManagerSettleSec=1min\n".as_bytes(),
+        )
+        .unwrap();
+
+        assert_eq!(settle_window(None, default), default);
+        assert_eq!(settle_window(Some(&info), default), Duration::from_secs(60));
+    }
+}
@@ -0,0 +1,153 @@
+//! Instances of template units, like `getty@tty1.service` for `getty@.service`.
+//!
+//! Instances are seldom listed in services.json: they are pulled in through symlinks in the
+//! `.wants` and `.requires` directories, or started by hand. A template can't be started,
+//! restarted nor stopped itself, so we act on its running instances instead, which systemd
+//! lists with a `getty@*.service` pattern.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::Path;
+
+use super::Services;
+use crate::systemd;
+
+/// Whether `name` is a template, like `getty@.service`.
+pub fn is_template(name: &str) -> bool {
+    name.split_once('@')
+        .is_some_and(|(_, rest)| rest.starts_with('.'))
+}
+
+/// The template of an instance, `getty@.service` for `getty@tty1.service`.
+pub fn template_of(name: &str) -> Option<String> {
+    let (prefix, rest) = name.split_once('@')?;
+    let (instance, suffix) = rest.rsplit_once('.')?;
+    (!instance.is_empty()).then(|| format!("{prefix}@.{suffix}"))
+}
+
+fn instance_pattern(template: &str) -> Option<String> {
+    let (prefix, suffix) = template.split_once("@.")?;
+    Some(format!("{prefix}@*.{suffix}"))
+}
+
+/// The running instances of `templates`, by template.
+pub fn running_instances(
+    service_manager: &dyn systemd::ServiceManagerBackend,
+    templates: &[&str],
+) -> BTreeMap<String, Vec<String>> {
+    let patterns: Vec<String> = templates
+        .iter()
+        .filter_map(|template| instance_pattern(template))
+        .collect();
+    if patterns.is_empty() {
+        return BTreeMap::new();
+    }
+    let patterns: Vec<&str> = patterns.iter().map(String::as_str).collect();
+    let units = match service_manager
+        .list_units_by_patterns(&["active", "activating", "reloading"], &patterns)
+    {
+        Ok(units) => units,
+        Err(e) => {
+            log::error!(
+                "Cannot list the running instances of {}: {e}",
+                templates.join(", ")
+            );
+            return BTreeMap::new();
+        }
+    };
+    let mut instances: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for unit in units {
+        if let Some(template) = template_of(&unit.name).filter(|t| templates.contains(&t.as_str()))
+        {
+            instances.entry(template).or_default().push(unit.name);
+        }
+    }
+    instances
+}
+
+/// Replace the templates among `units` with their running `instances`.
+pub fn with_instances(
+    units: Vec<String>,
+    instances: &BTreeMap<String, Vec<String>>,
+) -> Vec<String> {
+    units
+        .into_iter()
+        .flat_map(|unit| {
+            if is_template(&unit) {
+                instances.get(&unit).cloned().unwrap_or_default()
+            } else {
+                vec![unit]
+            }
+        })
+        .collect()
+}
+
+/// The instances of the managed templates that a generation configures: listed in
+/// `services`, or linked from the `.wants` and `.requires` directories of `system_dir`.
+pub fn configured_instances(services: &Services, system_dir: &Path) -> BTreeSet<String> {
+    let templates: BTreeSet<&str> = services
+        .iter()
+        .filter(|(name, cfg)| !cfg.masked && is_template(name))
+        .map(|(name, _)| name.as_str())
+        .collect();
+    let linked = fs::read_dir(system_dir)
+        .into_iter()
+        .flatten()
+        .filter_map(Result::ok)
+        .filter(|entry| {
+            entry
+                .file_name()
+                .to_str()
+                .is_some_and(|name| name.ends_with(".wants") || name.ends_with(".requires"))
+        })
+        .flat_map(|entry| fs::read_dir(entry.path()).into_iter().flatten())
+        .filter_map(|entry| entry.ok()?.file_name().into_string().ok());
+    services
+        .keys()
+        .cloned()
+        .chain(linked)
+        .filter(|name| template_of(name).is_some_and(|t| templates.contains(t.as_str())))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn instances_are_matched_with_their_template() {
+        assert!(is_template("getty@.service"));
+        assert!(!is_template("getty@tty1.service"));
+        assert!(!is_template("sshd.service"));
+        assert_eq!(
+            template_of("getty@tty1.service").as_deref(),
+            Some("getty@.service")
+        );
+        assert_eq!(
+            template_of("backup@home-alice.timer").as_deref(),
+            Some("backup@.timer")
+        );
+        assert_eq!(template_of("getty@.service"), None);
+        assert_eq!(template_of("sshd.service"), None);
+        assert_eq!(
+            instance_pattern("getty@.service").as_deref(),
+            Some("getty@*.service")
+        );
+
+        let instances = BTreeMap::from([(
+            "getty@.service".to_owned(),
+            vec!["getty@tty1.service".to_owned()],
+        )]);
+        assert_eq!(
+            with_instances(
+                vec![
+                    "getty@.service".to_owned(),
+                    "sshd.service".to_owned(),
+                    "backup@.service".to_owned(),
+                ],
+                &instances
+            ),
+            ["getty@tty1.service", "sshd.service"]
+        );
+    }
+}
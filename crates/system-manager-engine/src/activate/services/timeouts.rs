@@ -0,0 +1,147 @@
+//! How long to wait for the systemd jobs of each unit.
+//!
+//! The default comes from the command line. A unit can set its own timeout with
+//! `X-SystemManagerJobTimeoutSec=` in its `[Unit]` section, otherwise we wait at least as
+//! long as systemd itself would before giving up on the unit, according to its
+//! `TimeoutStartSec=` and `TimeoutStopSec=`.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use super::{read_unit_info, Services};
+use crate::unit_info::{parse_timespan, UnitInfo};
+
+const TIMEOUT_KEY: &str = "X-SystemManagerJobTimeoutSec";
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct UnitTimeouts {
+    /// From `X-SystemManagerJobTimeoutSec=`, applies to all jobs.
+    job: Option<Duration>,
+    start: Option<Duration>,
+    stop: Option<Duration>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct JobTimeouts {
+    default: Duration,
+    units: HashMap<String, UnitTimeouts>,
+}
+
+impl JobTimeouts {
+    /// Use `default` for all units.
+    pub fn new(default: Duration) -> Self {
+        Self {
+            default,
+            units: HashMap::new(),
+        }
+    }
+
+    /// Read the timeouts of `services` from their unit files. Units listed several times
+    /// keep the first timeouts found.
+    pub fn with_units<'a>(mut self, services: impl IntoIterator<Item = &'a Services>) -> Self {
+        for services in services {
+            for (name, cfg) in services.iter() {
+                if self.units.contains_key(name) {
+                    continue;
+                }
+                if let Some(info) = read_unit_info(name, cfg) {
+                    self.units.insert(name.clone(), unit_timeouts(&info));
+                }
+            }
+        }
+        self
+    }
+
+    /// The timeout of the `job` queued for `unit`.
+    pub fn for_job(&self, unit: &str, job: &str) -> Duration {
+        let Some(timeouts) = self.units.get(unit) else {
+            return self.default;
+        };
+        if let Some(timeout) = timeouts.job {
+            return timeout;
+        }
+        let unit_timeout = match job {
+            "stop" => timeouts.stop,
+            "restart" => timeouts
+                .stop
+                .zip(timeouts.start)
+                .map(|(stop, start)| stop.saturating_add(start))
+                .or(timeouts.stop)
+                .or(timeouts.start),
+            _ => timeouts.start,
+        };
+        unit_timeout.map_or(self.default, |timeout| timeout.max(self.default))
+    }
+}
+
+fn unit_timeouts(info: &UnitInfo) -> UnitTimeouts {
+    let value = |section: &str, key: &str| {
+        let value = info.get(section)?.get(key)?.last()?;
+        parse_timespan(value).or_else(|| {
+            log::warn!("Ignoring invalid {key}={value}");
+            None
+        })
+    };
+    // An infinite systemd timeout shouldn't make us wait forever.
+    let finite = |timeout: Option<Duration>| timeout.filter(|t| *t < Duration::MAX);
+    let service_timeout = || value("Service", "TimeoutSec");
+    UnitTimeouts {
+        job: finite(value("Unit", TIMEOUT_KEY)),
+        start: finite(value("Service", "TimeoutStartSec").or_else(service_timeout)),
+        stop: finite(value("Service", "TimeoutStopSec").or_else(service_timeout)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::unit_info::parse_systemd_ini;
+
+    fn timeouts(content: &str) -> JobTimeouts {
+        let mut info = UnitInfo::new();
+        parse_systemd_ini(&mut info, content.as_bytes()).unwrap();
+        let mut timeouts = JobTimeouts::new(Duration::from_secs(30));
+        timeouts
+            .units
+            .insert("app.service".to_owned(), unit_timeouts(&info));
+        timeouts
+    }
+
+    #[test]
+    fn unit_timeouts_extend_the_default() {
+        let slow = timeouts("[Service]\nTimeoutStartSec=5min\nTimeoutStopSec=10\n");
+        assert_eq!(
+            slow.for_job("app.service", "start"),
+            Duration::from_secs(300)
+        );
+        assert_eq!(slow.for_job("app.service", "stop"), Duration::from_secs(30));
+        assert_eq!(
+            slow.for_job("app.service", "restart"),
+            Duration::from_secs(310)
+        );
+        assert_eq!(
+            slow.for_job("other.service", "start"),
+            Duration::from_secs(30)
+        );
+
+        let infinite = timeouts("[Service]\nTimeoutSec=infinity\n");
+        assert_eq!(
+            infinite.for_job("app.service", "start"),
+            Duration::from_secs(30)
+        );
+    }
+
+    #[test]
+    fn units_can_set_their_job_timeout() {
+        let explicit =
+            timeouts("[Unit]\nX-SystemManagerJobTimeoutSec=5\n[Service]\nTimeoutStartSec=5min\n");
+        assert_eq!(
+            explicit.for_job("app.service", "start"),
+            Duration::from_secs(5)
+        );
+        assert_eq!(
+            explicit.for_job("app.service", "stop"),
+            Duration::from_secs(5)
+        );
+    }
+}
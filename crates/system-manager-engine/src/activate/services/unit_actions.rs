@@ -0,0 +1,251 @@
+//! Decide what happens to a unit on activation, ported from nixpkgs switch-to-configuration-ng.
+//!
+//! The decision depends on the unit type, on the `X-` keys set by the NixOS systemd module
+//! and on the part of the unit file that changed.
+
+use crate::unit_info::{parse_systemd_bool, UnitInfo};
+
+/// Units whose restart would take the whole system down with them.
+const NEVER_RESTARTED: &[&str] = &[
+    "dbus.service",
+    "dbus-broker.service",
+    "systemd-logind.service",
+];
+
+/// `[Unit]` keys whose change doesn't affect the running unit, a daemon-reload applies them.
+const IGNORED_UNIT_KEYS: &[&str] = &[
+    "Description",
+    "Documentation",
+    "OnFailure",
+    "OnSuccess",
+    "OnFailureJobMode",
+    "IgnoreOnIsolate",
+    "StopWhenUnneeded",
+    "RefuseManualStart",
+    "RefuseManualStop",
+    "AllowIsolate",
+    "CollectMode",
+    "SourcePath",
+];
+
+const RELOAD_TRIGGERS_KEY: &str = "X-Reload-Triggers";
+
+/// What to do with a unit present in both generations, whose unit file changed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UnitAction {
+    /// Leave the unit alone, for the logged reason.
+    Skip(&'static str),
+    Reload,
+    Restart,
+    /// Stop the unit before activating /etc, start it afterwards.
+    StopStart,
+    Start,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Comparison {
+    Equal,
+    NeedsReload,
+    NeedsRestart,
+}
+
+pub fn is_never_restarted(name: &str) -> bool {
+    NEVER_RESTARTED.contains(&name)
+}
+
+/// The action for the unit `name`, given its old and new unit file. A unit file that
+/// can't be parsed counts as changed.
+pub fn changed_unit_action(
+    name: &str,
+    old_info: Option<&UnitInfo>,
+    new_info: Option<&UnitInfo>,
+) -> UnitAction {
+    let comparison = match (old_info, new_info) {
+        (Some(old_info), Some(new_info)) => compare_units(old_info, new_info),
+        _ => Comparison::NeedsRestart,
+    };
+    let unit_bool = |section, key, default| parse_systemd_bool(new_info, section, key, default);
+    let only_manual_start = unit_bool("Unit", "X-OnlyManualStart", false);
+
+    if comparison == Comparison::Equal {
+        return UnitAction::Skip("only keys that a daemon-reload applies changed");
+    }
+    if is_never_restarted(name) {
+        return if comparison == Comparison::NeedsReload
+            || unit_bool("Service", "X-ReloadIfChanged", false)
+        {
+            UnitAction::Reload
+        } else {
+            UnitAction::Skip("restarting it would break the running system")
+        };
+    }
+    match unit_type(name) {
+        "target" => {
+            if only_manual_start || unit_bool("Unit", "RefuseManualStart", false) {
+                UnitAction::Skip("it can only be started manually")
+            } else {
+                UnitAction::Start
+            }
+        }
+        // Reloading a mount unit remounts it with the new options.
+        "mount" => UnitAction::Reload,
+        "swap" => UnitAction::Skip("swap changes take effect on the next boot"),
+        "slice" => UnitAction::Skip("restarting a slice would stop all of its units"),
+        // Restart the trigger units so that they pick up their new schedule or paths.
+        "timer" | "path" | "socket" => UnitAction::Restart,
+        _ if comparison == Comparison::NeedsReload => UnitAction::Reload,
+        _ if unit_bool("Service", "X-ReloadIfChanged", false) => UnitAction::Reload,
+        _ if !unit_bool("Service", "X-RestartIfChanged", true) => {
+            UnitAction::Skip("X-RestartIfChanged=false")
+        }
+        _ if only_manual_start => UnitAction::Skip("X-OnlyManualStart=true"),
+        _ if unit_bool("Unit", "RefuseManualStop", false) => {
+            UnitAction::Skip("RefuseManualStop=true")
+        }
+        _ if unit_bool("Service", "X-StopIfChanged", true) => UnitAction::StopStart,
+        _ => UnitAction::Restart,
+    }
+}
+
+/// Why a unit removed from the configuration keeps running, if it does.
+pub fn keep_removed_unit(name: &str, info: Option<&UnitInfo>) -> Option<&'static str> {
+    if is_never_restarted(name) {
+        Some("stopping it would break the running system")
+    } else if unit_type(name) == "slice" {
+        Some("stopping a slice would stop all of its units")
+    } else if !parse_systemd_bool(info, "Unit", "X-StopOnRemoval", true) {
+        Some("X-StopOnRemoval=false")
+    } else {
+        None
+    }
+}
+
+/// The sockets activating the service `name`: the ones listed in `Sockets=`, or the
+/// socket with the same name.
+pub fn service_sockets(name: &str, info: Option<&UnitInfo>) -> Vec<String> {
+    let Some(base) = name.strip_suffix(".service") else {
+        return Vec::new();
+    };
+    let listed: Vec<String> = info
+        .and_then(|info| info.get("Service"))
+        .and_then(|section| section.get("Sockets"))
+        .into_iter()
+        .flatten()
+        .flat_map(|value| value.split_whitespace())
+        .map(str::to_owned)
+        .collect();
+    if listed.is_empty() {
+        vec![format!("{base}.socket")]
+    } else {
+        listed
+    }
+}
+
+pub fn unit_type(name: &str) -> &str {
+    name.rsplit_once('.').map_or("", |(_, suffix)| suffix)
+}
+
+fn compare_units(old_info: &UnitInfo, new_info: &UnitInfo) -> Comparison {
+    let (old_info, old_triggers) = normalize(old_info);
+    let (new_info, new_triggers) = normalize(new_info);
+    if old_info != new_info {
+        Comparison::NeedsRestart
+    } else if old_triggers != new_triggers {
+        Comparison::NeedsReload
+    } else {
+        Comparison::Equal
+    }
+}
+
+/// Split the reload triggers out of a unit, and drop the keys that don't need a restart.
+fn normalize(info: &UnitInfo) -> (UnitInfo, Option<Vec<String>>) {
+    let mut info = info.clone();
+    let mut triggers = None;
+    if let Some(section) = info.get_mut("Unit") {
+        triggers = section.remove(RELOAD_TRIGGERS_KEY);
+        section.retain(|key, _| !IGNORED_UNIT_KEYS.contains(&key.as_str()));
+        if section.is_empty() {
+            info.remove("Unit");
+        }
+    }
+    (info, triggers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::unit_info::parse_systemd_ini;
+
+    fn unit(content: &str) -> UnitInfo {
+        let mut info = UnitInfo::new();
+        parse_systemd_ini(&mut info, content.as_bytes()).unwrap();
+        info
+    }
+
+    #[test]
+    fn changed_services_are_stopped_and_started_by_default() {
+        let old = unit("[Service]\nExecStart=/nix/store/a/bin/app\n");
+        let new = unit("[Service]\nExecStart=/nix/store/b/bin/app\n");
+        let action = |name, new: &UnitInfo| changed_unit_action(name, Some(&old), Some(new));
+
+        assert_eq!(action("app.service", &new), UnitAction::StopStart);
+        let restart = unit("[Service]\nExecStart=/nix/store/b/bin/app\nX-StopIfChanged=false\n");
+        assert_eq!(action("app.service", &restart), UnitAction::Restart);
+        let reload = unit("[Service]\nExecStart=/nix/store/b/bin/app\nX-ReloadIfChanged=true\n");
+        assert_eq!(action("app.service", &reload), UnitAction::Reload);
+        let manual =
+            unit("[Unit]\nX-OnlyManualStart=true\n[Service]\nExecStart=/nix/store/b/bin/app\n");
+        assert!(matches!(
+            action("app.service", &manual),
+            UnitAction::Skip(_)
+        ));
+        assert!(matches!(action("dbus.service", &new), UnitAction::Skip(_)));
+        assert!(matches!(
+            action("systemd-logind.service", &new),
+            UnitAction::Skip(_)
+        ));
+    }
+
+    #[test]
+    fn unit_types_have_their_own_actions() {
+        let old = unit("[Unit]\nX-Restart-Triggers=a\n");
+        let new = unit("[Unit]\nX-Restart-Triggers=b\n");
+        let action = |name| changed_unit_action(name, Some(&old), Some(&new));
+
+        assert_eq!(action("data.mount"), UnitAction::Reload);
+        assert_eq!(action("backup.timer"), UnitAction::Restart);
+        assert_eq!(action("watch.path"), UnitAction::Restart);
+        assert_eq!(action("app.target"), UnitAction::Start);
+        assert!(matches!(action("swapfile.swap"), UnitAction::Skip(_)));
+        assert!(matches!(action("app.slice"), UnitAction::Skip(_)));
+    }
+
+    #[test]
+    fn reload_triggers_and_descriptions_dont_restart() {
+        let old = unit("[Unit]\nDescription=Old\nX-Reload-Triggers=a\n[Service]\nExecStart=x\n");
+        let description =
+            unit("[Unit]\nDescription=New\nX-Reload-Triggers=a\n[Service]\nExecStart=x\n");
+        let triggers =
+            unit("[Unit]\nDescription=Old\nX-Reload-Triggers=b\n[Service]\nExecStart=x\n");
+
+        assert!(matches!(
+            changed_unit_action("app.service", Some(&old), Some(&description)),
+            UnitAction::Skip(_)
+        ));
+        assert_eq!(
+            changed_unit_action("app.service", Some(&old), Some(&triggers)),
+            UnitAction::Reload
+        );
+    }
+
+    #[test]
+    fn socket_activated_services_list_their_sockets() {
+        assert_eq!(service_sockets("app.service", None), ["app.socket"]);
+        let info = unit("[Service]\nSockets=a.socket b.socket\n");
+        assert_eq!(
+            service_sockets("app.service", Some(&info)),
+            ["a.socket", "b.socket"]
+        );
+        assert!(service_sockets("app.timer", None).is_empty());
+    }
+}
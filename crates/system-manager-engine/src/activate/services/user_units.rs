@@ -0,0 +1,340 @@
+//! Systemd user units, from `systemd.user.*`.
+//!
+//! Their unit files live in /etc/systemd/user, so every user manager loads them when it
+//! starts. On activation, we also stop, restart and start them in the user managers that
+//! are already running, for logged-in users and users with lingering enabled, following
+//! the same rules as for the system units. The units are tracked per user in the state.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::time::Duration;
+
+use anyhow::Context;
+use nix::unistd::{Uid, User};
+use serde::Deserialize;
+
+use super::unit_actions::{self, UnitAction};
+use super::{
+    fail_on_pending, read_unit_info, run_in_waves, unit_infos, JobTimeouts, Services, UnitOutcomes,
+};
+use crate::activate::ServiceOptions;
+use crate::{systemd, StorePath};
+
+/// The target pulling in the user units, started to start the new ones.
+const USER_TARGET: &str = "default.target";
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct UserServicesConfig {
+    /// The users to activate the units for, all the running ones when empty.
+    #[serde(default)]
+    users: Vec<String>,
+    #[serde(default)]
+    services: Services,
+}
+
+/// The user units we activated, by user name.
+pub type UserServices = BTreeMap<String, Services>;
+
+/// What happened to the user units of the running user managers.
+#[derive(Debug, Clone, Default)]
+pub struct UserUnitsActivation {
+    pub services: UserServices,
+    /// The outcome of the jobs queued for the units, by user name.
+    pub outcomes: BTreeMap<String, UnitOutcomes>,
+    /// The users whose user manager we couldn't talk to.
+    pub errors: BTreeMap<String, String>,
+}
+
+impl UserUnitsActivation {
+    /// The user units whose job didn't succeed, as `user: unit`, and the users we failed to
+    /// activate units for.
+    pub fn failed_units(&self) -> Vec<String> {
+        let failed_jobs = self.outcomes.iter().flat_map(|(user, outcomes)| {
+            outcomes
+                .iter()
+                .filter(|(_, outcome)| !outcome.succeeded())
+                .map(move |(unit, _)| format!("{user}: {unit}"))
+        });
+        let failed_users = self
+            .errors
+            .keys()
+            .map(|user| format!("{user}: user manager"));
+        failed_jobs.chain(failed_users).collect()
+    }
+}
+
+fn read_config(store_path: &StorePath) -> anyhow::Result<UserServicesConfig> {
+    let path = store_path
+        .store_path
+        .join("userServices")
+        .join("userServices.json");
+    // Profiles built before user units were supported.
+    if !path.exists() {
+        return Ok(UserServicesConfig::default());
+    }
+    let file =
+        fs::File::open(&path).with_context(|| format!("Failed to open {}", path.display()))?;
+    serde_json::from_reader(std::io::BufReader::new(file))
+        .with_context(|| format!("Failed to parse {}", path.display()))
+}
+
+/// The users with a running user manager, by name.
+fn running_users() -> BTreeMap<String, u32> {
+    let Ok(entries) = fs::read_dir("/run/user") else {
+        return BTreeMap::new();
+    };
+    entries
+        .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse::<u32>().ok())
+        .filter(|uid| systemd::user_manager_socket(*uid).exists())
+        .filter_map(|uid| {
+            let user = User::from_uid(Uid::from_raw(uid)).ok().flatten()?;
+            Some((user.name, uid))
+        })
+        .collect()
+}
+
+/// Activate the user units of `store_path` in the running user managers, once their unit
+/// files are in place in /etc/systemd/user.
+pub fn activate(
+    store_path: &StorePath,
+    old_services: &UserServices,
+    options: &ServiceOptions,
+) -> UserUnitsActivation {
+    let config = match read_config(store_path) {
+        Ok(config) => config,
+        Err(e) => {
+            log::error!("Cannot read the user units: {e:?}");
+            return UserUnitsActivation {
+                services: old_services.clone(),
+                ..UserUnitsActivation::default()
+            };
+        }
+    };
+    activate_users(
+        &config,
+        running_users(),
+        old_services,
+        |uid, services, old| activate_user(uid, services, old, options),
+    )
+}
+
+/// Activate the user units in the user managers of `running` users, by name.
+fn activate_users(
+    config: &UserServicesConfig,
+    running: BTreeMap<String, u32>,
+    old_services: &UserServices,
+    activate_user: impl Fn(u32, &Services, &Services) -> anyhow::Result<UnitOutcomes>,
+) -> UserUnitsActivation {
+    let mut activation = UserUnitsActivation::default();
+    let no_services = Services::new();
+    for (user, uid) in running {
+        if !config.users.is_empty() && !config.users.contains(&user) {
+            continue;
+        }
+        let old = old_services.get(&user).unwrap_or(&no_services);
+        if old.is_empty() && config.services.is_empty() {
+            continue;
+        }
+        log::info!("Activating the user units of {user}...");
+        match activate_user(uid, &config.services, old) {
+            Ok(outcomes) => {
+                activation.outcomes.insert(user.clone(), outcomes);
+                if !config.services.is_empty() {
+                    activation.services.insert(user, config.services.clone());
+                }
+            }
+            Err(e) => {
+                log::error!("Error activating the user units of {user}: {e:?}");
+                activation.errors.insert(user.clone(), format!("{e:#}"));
+                activation.services.insert(user, old.clone());
+            }
+        }
+    }
+    // Keep track of the units of the users we skipped, logged out or left out of `users`,
+    // to stop them once their manager runs again.
+    for (user, old) in old_services {
+        if !activation.outcomes.contains_key(user) && !activation.services.contains_key(user) {
+            activation.services.insert(user.clone(), old.clone());
+        }
+    }
+    activation
+}
+
+fn activate_user(
+    uid: u32,
+    services: &Services,
+    old_services: &Services,
+    options: &ServiceOptions,
+) -> anyhow::Result<UnitOutcomes> {
+    let service_manager = systemd::ServiceManager::new_user_session(uid)?;
+    let job_monitor = service_manager.monitor_jobs_init()?;
+    let timeouts = JobTimeouts::new(options.job_timeout).with_units([services, old_services]);
+
+    // The user manager still knows about the removed units until the daemon reload.
+    let removed: BTreeMap<&str, &'static str> = old_services
+        .iter()
+        .filter(|(name, _)| !services.contains_key(*name))
+        .filter(|(name, cfg)| {
+            match unit_actions::keep_removed_unit(name, read_unit_info(name, cfg).as_ref()) {
+                Some(reason) => {
+                    log::info!("Not stopping {name}: {reason}");
+                    false
+                }
+                None => true,
+            }
+        })
+        .map(|(name, _)| (name.as_str(), "stop"))
+        .collect();
+    let mut outcomes = run_in_waves(
+        &service_manager,
+        &job_monitor,
+        &removed,
+        &unit_infos(&removed, &[old_services]),
+        &timeouts,
+    )?;
+
+    service_manager.daemon_reload()?;
+
+    let mut jobs: BTreeMap<&str, &'static str> = BTreeMap::new();
+    for (name, cfg) in services.iter() {
+        let Some(old_cfg) = old_services.get(name) else {
+            continue;
+        };
+        if cfg.store_path == old_cfg.store_path {
+            continue;
+        }
+        let new_info = read_unit_info(name, cfg);
+        let old_info = read_unit_info(name, old_cfg);
+        // The new unit files are already in place, stopping first is too late.
+        match unit_actions::changed_unit_action(name, old_info.as_ref(), new_info.as_ref()) {
+            UnitAction::Skip(reason) => log::info!("Not restarting {name}: {reason}"),
+            UnitAction::Reload => {
+                jobs.insert(name, "reload");
+            }
+            UnitAction::Restart | UnitAction::StopStart => {
+                jobs.insert(name, "restart");
+            }
+            UnitAction::Start => {
+                jobs.insert(name, "start");
+            }
+        }
+    }
+    jobs.insert(USER_TARGET, "start");
+    outcomes.extend(run_in_waves(
+        &service_manager,
+        &job_monitor,
+        &jobs,
+        &unit_infos(&jobs, &[services]),
+        &timeouts,
+    )?);
+    Ok(outcomes)
+}
+
+/// Stop the user units in the running user managers, once their unit files are gone.
+pub fn deactivate(old_services: &UserServices, job_timeout: Duration) -> UserServices {
+    let running = running_users();
+    let mut remaining = UserServices::new();
+    for (user, services) in old_services {
+        let Some(uid) = running.get(user) else {
+            continue;
+        };
+        log::info!("Stopping the user units of {user}...");
+        if let Err(e) = deactivate_user(*uid, services, job_timeout) {
+            log::error!("Error stopping the user units of {user}: {e:?}");
+            remaining.insert(user.clone(), services.clone());
+        }
+    }
+    remaining
+}
+
+fn deactivate_user(uid: u32, services: &Services, job_timeout: Duration) -> anyhow::Result<()> {
+    let service_manager = systemd::ServiceManager::new_user_session(uid)?;
+    let job_monitor = service_manager.monitor_jobs_init()?;
+    let timeouts = JobTimeouts::new(job_timeout).with_units([services]);
+    let jobs: BTreeMap<&str, &'static str> = services
+        .keys()
+        .map(|name| (name.as_str(), "stop"))
+        .collect();
+    let outcomes = run_in_waves(
+        &service_manager,
+        &job_monitor,
+        &jobs,
+        &unit_infos(&jobs, &[services]),
+        &timeouts,
+    )?;
+    fail_on_pending(&outcomes)?;
+    service_manager.daemon_reload()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn user_units_are_read_from_the_profile() {
+        let config: UserServicesConfig = serde_json::from_str(
+            r#"{
+                "users": ["alice"],
+                "services": {
+                    "sync.service": {
+                        "storePath": "/nix/store/00000000000000000000000000000000-unit-sync.service/sync.service",
+                        "masked": false
+                    }
+                }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(config.users, ["alice"]);
+        assert!(config.services.contains_key("sync.service"));
+
+        let activation = UserUnitsActivation {
+            errors: BTreeMap::from([("bob".to_owned(), "connection refused".to_owned())]),
+            ..UserUnitsActivation::default()
+        };
+        assert_eq!(activation.failed_units(), ["bob: user manager"]);
+    }
+
+    #[test]
+    fn units_of_skipped_users_are_kept() {
+        let unit = |name: &str| {
+            let cfg = crate::activate::services::ServiceConfig {
+                store_path: None,
+                masked: false,
+                mask_mode: None,
+                preset: false,
+            };
+            (name.to_owned(), cfg)
+        };
+        let config = UserServicesConfig {
+            users: vec!["alice".to_owned(), "bob".to_owned()],
+            services: Services::from(vec![unit("sync.service")]),
+        };
+        let old_services = UserServices::from([
+            (
+                "alice".to_owned(),
+                Services::from(vec![unit("old.service")]),
+            ),
+            ("bob".to_owned(), Services::from(vec![unit("old.service")])),
+            (
+                "carol".to_owned(),
+                Services::from(vec![unit("old.service")]),
+            ),
+        ]);
+        let running = BTreeMap::from([("alice".to_owned(), 1000), ("carol".to_owned(), 1002)]);
+
+        let activation = activate_users(&config, running, &old_services, |uid, _, _| {
+            assert_eq!(uid, 1000);
+            Ok(UnitOutcomes::new())
+        });
+
+        let units =
+            |user: &str| -> Vec<String> { activation.services[user].keys().cloned().collect() };
+        assert_eq!(units("alice"), ["sync.service"]);
+        // Logged out.
+        assert_eq!(units("bob"), ["old.service"]);
+        // Not in `users`.
+        assert_eq!(units("carol"), ["old.service"]);
+    }
+}
@@ -1,26 +1,27 @@
 use crate::activate;
 
 use super::ActivationResult;
+use crate::{in_root, is_live_root};
 use std::collections::HashSet;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process;
 
 type TmpFilesActivationResult = ActivationResult<()>;
 
-pub fn activate(etc_tree: &HashSet<PathBuf>) -> TmpFilesActivationResult {
-    let tmp_files_prefix = PathBuf::from("/etc/tmpfiles.d");
-    // List and collect managed files under /etc/tmpFiles.d
-    let tmpfiles_conf_files: Vec<&str> = etc_tree
+pub fn activate(etc_tree: &HashSet<PathBuf>, root: &Path) -> TmpFilesActivationResult {
+    let tmp_files_prefix = in_root(root, "/etc/tmpfiles.d");
+    // List and collect managed files under /etc/tmpFiles.d.
+    // With --root, systemd-tmpfiles expects them relative to the alternate root.
+    let tmpfiles_conf_files: Vec<PathBuf> = etc_tree
         .iter()
-        .filter_map(|p| {
-            if p.starts_with(&tmp_files_prefix) {
-                p.to_str()
-            } else {
-                None
-            }
-        })
+        .filter(|p| p.starts_with(&tmp_files_prefix))
+        .filter_map(|p| p.strip_prefix(root).ok())
+        .map(|p| Path::new("/").join(p))
         .collect();
     let mut cmd = process::Command::new("systemd-tmpfiles");
+    if !is_live_root(root) {
+        cmd.arg(format!("--root={}", root.display()));
+    }
     cmd.arg("--create")
         .arg("--remove")
         .args(tmpfiles_conf_files);
@@ -33,7 +34,7 @@ pub fn activate(etc_tree: &HashSet<PathBuf>) -> TmpFilesActivationResult {
 
     output.status.success().then_some(()).ok_or_else(|| {
         activate::ActivationError::WithPartialResult {
-            result: (),
+            result: Box::new(()),
             source: anyhow::anyhow!(
                 "Error while creating tmpfiles\nstdout: {}\nstderr: {}",
                 String::from_utf8_lossy(output.stdout.as_ref()),
@@ -1,10 +1,14 @@
 use anyhow::{Context, Result};
 use std::fs;
 use std::io::Write;
+use std::os::unix;
 use std::path::Path;
 use std::process::{Command, Stdio};
 use tempfile::NamedTempFile;
 
+use crate::in_root;
+use crate::unit_info::parse_unit;
+
 const USERBORN_PREVIOUS_CONFIG: &str = "/var/lib/userborn/previous-userborn.json";
 const SYSTEM_MANAGER_SW_PREFIX: &str = "/run/system-manager/sw";
 
@@ -145,3 +149,72 @@ pub fn restore_original_shells() -> Result<()> {
 
     Ok(())
 }
+
+/// Runs userborn against an alternate root, the way `userborn.service` would on the live system.
+///
+/// The service can't be started there, so we take its command line and environment from
+/// the unit file, and point all the paths to the alternate root.
+pub fn run_userborn_in_root(unit_file: &Path, root: &Path) -> Result<()> {
+    let unit = parse_unit(unit_file)?;
+    let service = unit
+        .get("Service")
+        .with_context(|| format!("No [Service] section in {}", unit_file.display()))?;
+    let exec_start = service
+        .get("ExecStart")
+        .and_then(|values| values.last())
+        .with_context(|| format!("No ExecStart in {}", unit_file.display()))?;
+    // userborn <config> <directory of the password files>
+    let args: Vec<&str> = exec_start
+        .trim_start_matches(['@', '-', '+', '!', ':'])
+        .split_whitespace()
+        .collect();
+    let [userborn, config, password_files_location] = args.as_slice() else {
+        anyhow::bail!("Unexpected userborn command line: {exec_start}");
+    };
+
+    let mut command = Command::new(userborn);
+    command
+        .arg(config)
+        .arg(in_root(root, password_files_location));
+    let mut previous_config = None;
+    for assignment in service.get("Environment").into_iter().flatten() {
+        for (name, value) in assignment
+            .split_whitespace()
+            .filter_map(|a| a.trim_matches('"').split_once('='))
+        {
+            let value = match name {
+                "USERBORN_PREVIOUS_CONFIG" => {
+                    let path = in_root(root, value);
+                    previous_config = Some(path.clone());
+                    path.display().to_string()
+                }
+                _ => value.to_owned(),
+            };
+            command.env(name, value);
+        }
+    }
+
+    log::info!("Creating users in {}...", root.display());
+    log::debug!("running {command:?}");
+    let status = command
+        .stdout(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .status()
+        .context("Failed to execute userborn")?;
+    if !status.success() {
+        anyhow::bail!("userborn exited with status {status}");
+    }
+
+    // What the ExecStartPost of the unit does, so that the next run knows about these users.
+    if let Some(previous_config) = previous_config {
+        if let Some(parent) = previous_config.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        if previous_config.is_symlink() {
+            fs::remove_file(&previous_config)?;
+        }
+        unix::fs::symlink(config, &previous_config)
+            .with_context(|| format!("Failed to link {} to {config}", previous_config.display()))?;
+    }
+    Ok(())
+}
@@ -1,15 +1,19 @@
 use anyhow::Result;
+use std::path::Path;
+use std::time::Duration;
 
 use crate::activate::etc_files;
 use crate::activate::services;
 use crate::activate::users;
 use crate::activate::{get_state_file, ActivationError, StateV1};
+use crate::systemd;
 
 /// Deactivates system-manager by locking managed users, removing etc files,
-/// and stopping systemd services.
-pub fn deactivate() -> Result<()> {
+/// and stopping systemd services, waiting up to `job_timeout` for their jobs.
+pub fn deactivate(job_timeout: Duration) -> Result<()> {
     log::info!("Deactivating system-manager");
-    let state_file = &get_state_file()?;
+    let _inhibitor_lock = systemd::InhibitorLock::take_or_warn("Deactivating system-manager");
+    let state_file = &get_state_file(Path::new("/"))?;
     let old_state = StateV1::from_file(state_file)?;
     log::debug!("{old_state:?}");
 
@@ -23,18 +27,25 @@ pub fn deactivate() -> Result<()> {
 
     match etc_files::deactivate(old_state.file_tree) {
         Ok(etc_tree) => {
+            log::info!("Deactivating systemd user units...");
+            let user_services =
+                services::user_units::deactivate(&old_state.user_services, job_timeout);
             log::info!("Deactivating systemd services...");
-            match services::deactivate(old_state.services) {
+            match services::deactivate(old_state.services, &old_state.enabled_units, job_timeout) {
                 Ok(services) => StateV1 {
                     file_tree: etc_tree,
                     services,
+                    user_services,
+                    enabled_units: Default::default(),
                     version: Default::default(),
                 },
                 Err(ActivationError::WithPartialResult { result, source }) => {
                     log::error!("Error during deactivation: {source:?}");
                     StateV1 {
                         file_tree: etc_tree,
-                        services: result,
+                        services: *result,
+                        user_services,
+                        enabled_units: old_state.enabled_units,
                         version: Default::default(),
                     }
                 }
@@ -43,7 +54,7 @@ pub fn deactivate() -> Result<()> {
         Err(ActivationError::WithPartialResult { result, source }) => {
             log::error!("Error during deactivation: {source:?}");
             StateV1 {
-                file_tree: result,
+                file_tree: *result,
                 ..old_state
             }
         }
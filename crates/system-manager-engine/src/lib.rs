@@ -132,26 +132,21 @@ fn create_link(to: &Path, from: &Path) -> Result<()> {
     Ok(())
 }
 
-fn remove_link(from: &Path) -> Result<()> {
-    log::info!("Removing symlink: {}", from.display());
-    if from.is_symlink() {
-        fs::remove_file(from)?;
-        Ok(())
+pub fn etc_dir(root: &Path, ephemeral: bool) -> PathBuf {
+    if ephemeral {
+        in_root(root, "/run/etc")
     } else {
-        anyhow::bail!("Not a symlink!");
+        in_root(root, "/etc")
     }
 }
 
-fn remove_file(from: &Path) -> Result<()> {
-    log::info!("Removing file: {}", from.display());
-    fs::remove_file(from)?;
-    Ok(())
+/// Whether we are activating the system we are running on.
+pub fn is_live_root(root: &Path) -> bool {
+    root == Path::new("/")
 }
 
-pub fn etc_dir(ephemeral: bool) -> PathBuf {
-    if ephemeral {
-        Path::new("/run").join("etc")
-    } else {
-        PathBuf::from("/etc")
-    }
+/// Place an absolute path under an alternate root directory.
+pub fn in_root(root: &Path, path: impl AsRef<Path>) -> PathBuf {
+    let path = path.as_ref();
+    root.join(path.strip_prefix("/").unwrap_or(path))
 }
@@ -12,8 +12,13 @@
 
 use anyhow::Result;
 use clap::Parser;
+use std::path::PathBuf;
 use std::process::ExitCode;
+use std::time::Duration;
 
+use system_manager_engine::activate::{
+    ActivationError, ActivationReport, ActivationResult, ServiceOptions,
+};
 use system_manager_engine::{NixOptions, StorePath, PROFILE_DIR};
 
 #[derive(clap::Parser, Debug)]
@@ -39,6 +44,69 @@ struct ActivationArgs {
     /// If true, only write under /run, otherwise write under /etc
     #[arg(long, action)]
     ephemeral: bool,
+
+    /// If true, mount the static /etc entries as an overlay instead of linking them one by one
+    #[arg(long, action, conflicts_with = "ephemeral")]
+    overlay: bool,
+
+    /// Activate the system installed in this directory instead of the running one.
+    /// Services are registered but not started.
+    #[arg(
+        long,
+        default_value = "/",
+        value_parser = parse_root,
+        conflicts_with_all = ["ephemeral", "overlay"]
+    )]
+    root: PathBuf,
+}
+
+/// How the units get started and reported, only on activate: prepopulate doesn't start any.
+#[derive(clap::Args, Debug)]
+struct ServiceArgs {
+    /// How many seconds to watch the started units for failures and restart loops.
+    /// Units can override it with X-SystemManagerSettleSec=
+    #[arg(long, value_name = "SECONDS", default_value_t = 0)]
+    settle_time: u64,
+
+    /// How many seconds to wait for the systemd jobs of units that don't set a longer
+    /// TimeoutStartSec= or an X-SystemManagerJobTimeoutSec=
+    #[arg(long, value_name = "SECONDS", default_value_t = 30)]
+    job_timeout: u64,
+
+    /// How many journal lines to report for each unit that failed to activate
+    #[arg(long, value_name = "LINES", default_value_t = 20)]
+    journal_lines: usize,
+
+    /// Print a report of the activation on stdout, for instance as JSON
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    output: OutputFormat,
+}
+
+impl ServiceArgs {
+    fn to_options(&self) -> ServiceOptions {
+        ServiceOptions {
+            settle_time: Duration::from_secs(self.settle_time),
+            job_timeout: Duration::from_secs(self.job_timeout),
+            journal_lines: self.journal_lines,
+        }
+    }
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum OutputFormat {
+    /// Only log what happens
+    Text,
+    /// Print the activation report as JSON on stdout
+    Json,
+}
+
+fn parse_root(root: &str) -> Result<PathBuf, String> {
+    let root = PathBuf::from(root);
+    if root.is_absolute() {
+        Ok(root)
+    } else {
+        Err(format!("{} is not an absolute path", root.display()))
+    }
 }
 
 #[derive(clap::Args, Debug)]
@@ -64,11 +132,18 @@ enum Action {
         store_path_arg: StorePathArg,
         #[command(flatten)]
         activation_args: ActivationArgs,
+        #[command(flatten)]
+        service_args: ServiceArgs,
     },
     /// Deactivate the system-manager profile (remove managed configuration)
     Deactivate {
         #[command(flatten)]
         store_path_arg: OptionalStorePathArg,
+
+        /// How many seconds to wait for the systemd jobs of units that don't set a longer
+        /// TimeoutStopSec= or an X-SystemManagerJobTimeoutSec=
+        #[arg(long, value_name = "SECONDS", default_value_t = 30)]
+        job_timeout: u64,
     },
     /// Pre-populate files without starting services
     Prepopulate {
@@ -103,24 +178,45 @@ fn go(args: Args) -> Result<()> {
     match args.action {
         Action::Activate {
             store_path_arg: StorePathArg { store_path },
-            activation_args: ActivationArgs { ephemeral },
-        } => system_manager_engine::activate::activate(&store_path, ephemeral),
+            activation_args:
+                ActivationArgs {
+                    ephemeral,
+                    overlay,
+                    root,
+                },
+            service_args,
+        } => print_report(
+            service_args.output,
+            system_manager_engine::activate::activate(
+                &store_path,
+                &root,
+                ephemeral,
+                overlay,
+                &service_args.to_options(),
+            ),
+        ),
 
         Action::Deactivate {
             store_path_arg: OptionalStorePathArg { store_path },
+            job_timeout,
         } => {
             // Log which store path we're using if it was auto-detected
             if store_path.is_none() {
                 let path = std::path::Path::new(PROFILE_DIR).join("system-manager");
                 log::info!("No store path provided, using {}", path.display());
             }
-            system_manager_engine::deactivate::deactivate()
+            system_manager_engine::deactivate::deactivate(Duration::from_secs(job_timeout))
         }
 
         Action::Prepopulate {
             store_path_arg: StorePathArg { store_path },
-            activation_args: ActivationArgs { ephemeral },
-        } => system_manager_engine::activate::prepopulate(&store_path, ephemeral),
+            activation_args:
+                ActivationArgs {
+                    ephemeral,
+                    overlay,
+                    root,
+                },
+        } => system_manager_engine::activate::prepopulate(&store_path, &root, ephemeral, overlay),
 
         Action::Register {
             store_path_arg: StorePathArg { store_path },
@@ -128,6 +224,18 @@ fn go(args: Args) -> Result<()> {
     }
 }
 
+/// Print the activation report in the requested format, and return the activation error.
+fn print_report(output: OutputFormat, result: ActivationResult<ActivationReport>) -> Result<()> {
+    let (report, result) = match result {
+        Ok(report) => (report, Ok(())),
+        Err(ActivationError::WithPartialResult { result, source }) => (*result, Err(source)),
+    };
+    if output == OutputFormat::Json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    }
+    result
+}
+
 fn handle_toplevel_error<T>(r: Result<T>) -> ExitCode {
     if let Err(e) = r {
         log::error!("{:?}", e);
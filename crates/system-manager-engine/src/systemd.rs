@@ -1,21 +1,29 @@
 // TODO: Remove this
 #![allow(dead_code)]
 
+#[cfg(test)]
+pub mod fake;
+mod inhibit;
 mod manager;
 mod unit;
 
+pub use inhibit::InhibitorLock;
+
 use crate::{
     systemd::manager::{OrgFreedesktopSystemd1Manager, OrgFreedesktopSystemd1ManagerJobRemoved},
     systemd::unit::OrgFreedesktopSystemd1Unit,
 };
 use anyhow::Error;
 use dbus::{
-    blocking::{Connection, Proxy},
-    channel::Token,
+    arg::ReadAll,
+    blocking::{Connection, MakeSignal, Proxy},
+    channel::{Channel, MatchingReceiver, Token},
+    message::SignalArgs,
     Message, Path,
 };
 use std::{
-    hash::Hash,
+    collections::BTreeMap,
+    fmt,
     result::Result,
     sync::Arc,
     sync::{
@@ -28,10 +36,20 @@ use std::{
 const SD_DESTINATION: &str = "org.freedesktop.systemd1";
 const SD_PATH: &str = "/org/freedesktop/systemd1";
 
+/// The private socket of the systemd user manager of `uid`, present while it runs.
+pub fn user_manager_socket(uid: u32) -> std::path::PathBuf {
+    std::path::PathBuf::from(format!("/run/user/{uid}/systemd/private"))
+}
+
 pub struct ServiceManager {
     proxy: Proxy<'static, Box<Connection>>,
+    /// Whether we talk to systemd directly rather than through a bus daemon, in which
+    /// case there is no bus to register our signal matches with.
+    peer: bool,
 }
 
+type SignalCallback = <Connection as MatchingReceiver>::F;
+
 pub struct UnitManager<'a> {
     proxy: Proxy<'static, &'a Connection>,
 }
@@ -42,6 +60,26 @@ pub struct UnitFile {
     pub status: String,
 }
 
+/// A change systemd made to the unit file symlinks, as `(type, file, destination)`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnitFileChange {
+    /// `symlink` or `unlink`.
+    pub change_type: String,
+    pub file: String,
+    /// The target of the created symlink, empty when unlinking.
+    pub destination: String,
+}
+
+impl From<(String, String, String)> for UnitFileChange {
+    fn from((change_type, file, destination): (String, String, String)) -> Self {
+        UnitFileChange {
+            change_type,
+            file,
+            destination,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct UnitStatus {
     pub name: String,
@@ -75,8 +113,101 @@ pub struct Job<'a> {
     path: Path<'a>,
 }
 
+impl Job<'_> {
+    /// The object path of the job, which identifies it in `JobRemoved` signals.
+    pub fn path(&self) -> String {
+        self.path.to_string()
+    }
+}
+
+/// How long to wait for systemd to finish a daemon reload.
+const DAEMON_RELOAD_TIMEOUT: Duration = Duration::from_secs(90);
+/// How often to look for jobs that are gone without a `JobRemoved` signal.
+const JOB_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The log target of the progress of the jobs, like `3/12 units restarted`, so that it can
+/// be filtered on its own with `RUST_LOG`.
+pub const PROGRESS_TARGET: &str = "system_manager::progress";
+
+/// A queued job to wait for.
+#[derive(Debug, Clone)]
+pub struct WaitedJob {
+    /// The object path of the job.
+    pub path: String,
+    pub unit: String,
+    /// `start`, `stop`, `restart` or `reload`.
+    pub job: &'static str,
+    pub timeout: Duration,
+}
+
+/// How many of the jobs of a kind finished so far.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JobProgress {
+    pub job: &'static str,
+    pub finished: usize,
+    pub total: usize,
+}
+
+impl fmt::Display for JobProgress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let done = match self.job {
+            "start" => "started",
+            "stop" => "stopped",
+            "restart" => "restarted",
+            "reload" => "reloaded",
+            other => other,
+        };
+        write!(f, "{}/{} units {done}", self.finished, self.total)
+    }
+}
+
+/// Counts the finished jobs of each kind.
+struct ProgressTracker {
+    total: BTreeMap<&'static str, usize>,
+    finished: BTreeMap<&'static str, usize>,
+}
+
+impl ProgressTracker {
+    fn new(jobs: &[WaitedJob]) -> Self {
+        let mut total = BTreeMap::new();
+        for job in jobs {
+            *total.entry(job.job).or_default() += 1;
+        }
+        Self {
+            total,
+            finished: BTreeMap::new(),
+        }
+    }
+
+    fn finish(&mut self, job: &'static str) -> JobProgress {
+        let finished = self.finished.entry(job).or_default();
+        *finished += 1;
+        JobProgress {
+            job,
+            finished: *finished,
+            total: self.total.get(job).copied().unwrap_or_default(),
+        }
+    }
+}
+
+/// The id of the job with the object path `path`, like `/org/freedesktop/systemd1/job/42`.
+fn job_id(path: &str) -> Option<u32> {
+    path.rsplit_once('/')?.1.parse().ok()
+}
+
+/// The result of a `job` that is gone, given the active state of its unit now.
+fn inferred_result(job: &str, active_state: Option<&str>) -> &'static str {
+    match (job, active_state) {
+        ("stop", Some("active" | "activating" | "reloading")) => "failed",
+        ("stop", _) => "done",
+        (_, Some("failed")) => "failed",
+        _ => "done",
+    }
+}
+
 pub struct JobMonitor<'a> {
-    job_names: Arc<Mutex<im::HashSet<String>>>,
+    /// The result of the finished jobs, by object path.
+    finished_jobs: Arc<Mutex<im::HashMap<String, String>>>,
     tokens: im::HashSet<Token>,
     service_manager: &'a ServiceManager,
 }
@@ -86,7 +217,7 @@ impl Drop for JobMonitor<'_> {
         self.tokens.iter().for_each(|t| {
             self.service_manager
                 .proxy
-                .match_stop(*t, true)
+                .match_stop(*t, !self.service_manager.peer)
                 .unwrap_or_else(|e|
                     log::error!("Error while stopping match listener, memory might leak...\n  Caused by: {e}")
                 )
@@ -112,19 +243,52 @@ impl ServiceManager {
 
         OrgFreedesktopSystemd1Manager::subscribe(&proxy)?;
 
-        Ok(ServiceManager { proxy })
+        Ok(ServiceManager { proxy, peer: false })
+    }
+
+    /// Connects to the systemd user manager of `uid`, through its private socket which
+    /// only accepts root and the user itself.
+    pub fn new_user_session(uid: u32) -> Result<ServiceManager, Error> {
+        let channel =
+            Channel::open_private(&format!("unix:path={}", user_manager_socket(uid).display()))?;
+        let proxy = Proxy::new(
+            SD_DESTINATION,
+            SD_PATH,
+            Duration::from_secs(10),
+            Box::new(Connection::from(channel)),
+        );
+
+        OrgFreedesktopSystemd1Manager::subscribe(&proxy)?;
+
+        Ok(ServiceManager { proxy, peer: true })
+    }
+
+    /// Calls `f` with the signals `S` sent by systemd.
+    fn match_signal<S, F>(&self, f: F) -> Result<Token, Error>
+    where
+        S: SignalArgs + ReadAll,
+        F: MakeSignal<SignalCallback, S, Connection>,
+    {
+        if !self.peer {
+            return Ok(self.proxy.match_signal(f)?);
+        }
+        // Signals sent over a peer connection have no sender.
+        let rule = S::match_rule(None, Some(&self.proxy.path)).static_clone();
+        let callback = f.make(rule.match_str());
+        Ok(self.proxy.connection.start_receive(rule, callback))
     }
 
     pub fn unique_name(&self) -> String {
         self.proxy.connection.unique_name().to_string()
     }
 
-    /// Performs a systemd daemon reload, blocking until complete.
+    /// Performs a systemd daemon reload, blocking until complete or until
+    /// `DAEMON_RELOAD_TIMEOUT` elapsed.
     pub fn daemon_reload(&self) -> Result<(), Error> {
         let ready = Arc::new(AtomicBool::from(false));
         let ready_closure = ready.clone();
 
-        self.proxy.match_signal(
+        let token = self.match_signal(
             move |res: manager::OrgFreedesktopSystemd1ManagerReloading,
                   _: &Connection,
                   _: &Message| {
@@ -135,15 +299,40 @@ impl ServiceManager {
             },
         )?;
 
-        OrgFreedesktopSystemd1Manager::reload(&self.proxy)?;
+        if let Err(e) = OrgFreedesktopSystemd1Manager::reload(&self.proxy) {
+            self.stop_match(token);
+            return Err(e.into());
+        }
 
+        let deadline = Instant::now() + DAEMON_RELOAD_TIMEOUT;
         while !ready.load(Ordering::Relaxed) {
-            self.proxy.connection.process(Duration::from_secs(10))?;
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                break;
+            }
+            self.proxy.connection.process(remaining)?;
         }
 
+        if !ready.load(Ordering::Relaxed) {
+            self.stop_match(token);
+            // systemd doesn't answer while it reloads, an answer means that we missed the
+            // signal telling that the reload finished.
+            OrgFreedesktopSystemd1Manager::version(&self.proxy).map_err(|e| {
+                anyhow::anyhow!(
+                    "systemd did not finish reloading within {DAEMON_RELOAD_TIMEOUT:?}: {e}"
+                )
+            })?;
+            log::warn!("Missed the end of the systemd daemon reload, systemd answers again");
+        }
         Ok(())
     }
 
+    fn stop_match(&self, token: Token) {
+        self.proxy
+            .match_stop(token, !self.peer)
+            .unwrap_or_else(|e| log::error!("Error while stopping match listener: {e}"));
+    }
+
     pub fn reset_failed(&self) -> Result<(), Error> {
         OrgFreedesktopSystemd1Manager::reset_failed(&self.proxy)?;
         Ok(())
@@ -161,15 +350,18 @@ impl ServiceManager {
     }
 
     pub fn monitor_jobs_init(&'_ self) -> Result<JobMonitor<'_>, Error> {
-        let job_names = Arc::new(Mutex::from(im::HashSet::<String>::new()));
+        let finished_jobs = Arc::new(Mutex::from(im::HashMap::<String, String>::new()));
 
-        let job_names_clone = Arc::clone(&job_names);
-        let token = self.proxy.match_signal(
+        let finished_jobs_clone = Arc::clone(&finished_jobs);
+        let token = self.match_signal(
             move |h: OrgFreedesktopSystemd1ManagerJobRemoved, _: &Connection, _: &Message| {
-                log::debug!("Job for {} done", h.unit);
+                log::debug!("Job {} for {} finished: {}", h.job, h.unit, h.result);
                 {
-                    // Insert a new name, and let the lock go out of scope immediately
-                    job_names_clone.lock().unwrap().insert(h.unit);
+                    // Record the result, and let the lock go out of scope immediately
+                    finished_jobs_clone
+                        .lock()
+                        .unwrap()
+                        .insert(h.job.to_string(), h.result);
                 }
                 // The callback gets removed at the end of monitor_jobs_finish
                 true
@@ -177,67 +369,146 @@ impl ServiceManager {
         )?;
 
         Ok(JobMonitor {
-            job_names: Arc::clone(&job_names),
+            finished_jobs: Arc::clone(&finished_jobs),
             tokens: im::HashSet::unit(token),
             service_manager: self,
         })
     }
 
-    /// Waits for the monitored jobs to finish. Returns `true` if all jobs
-    /// finished before the timeout, `false` otherwise.
-    pub fn monitor_jobs_finish<I>(
+    /// Waits for the monitored `jobs` to finish. Returns the result of each job that
+    /// finished before its timeout, as reported by systemd.
+    ///
+    /// We sleep until a signal arrives or the next job times out. Jobs that are gone
+    /// without a `JobRemoved` signal, because they finished before we started listening or
+    /// the signal got lost, are looked up with `GetJob` every `JOB_CHECK_INTERVAL`, and
+    /// their result is derived from the state of their unit.
+    pub fn monitor_jobs_finish(
         &self,
         job_monitor: &JobMonitor,
-        timeout: &Option<Duration>,
-        services: I,
-    ) -> Result<bool, Error>
-    where
-        I: IntoIterator,
-        I::Item: Into<String> + Eq + Hash,
-    {
+        jobs: Vec<WaitedJob>,
+    ) -> Result<im::HashMap<String, String>, Error> {
         let start_time = Instant::now();
 
-        let mut waiting_for: im::HashSet<_> = services.into_iter().map(Into::into).collect();
-        let total_jobs = waiting_for.len();
+        let total_jobs = jobs.len();
+        let mut progress = ProgressTracker::new(&jobs);
+        let mut waiting_for: BTreeMap<String, WaitedJob> = jobs
+            .into_iter()
+            .map(|job| (job.path.clone(), job))
+            .collect();
+        let mut results = im::HashMap::new();
 
         if total_jobs > 0 {
             log::info!("Waiting for jobs to finish...");
             log::debug!("Waiting for jobs to finish... (0/{})", total_jobs);
         }
 
+        let mut next_check = start_time;
         while !waiting_for.is_empty() {
-            self.proxy.connection.process(Duration::from_millis(50))?;
-
-            if timeout
-                .map(|t| start_time.elapsed() > t)
-                .unwrap_or_default()
-            {
-                return Ok(false);
+            let mut vanished = Vec::new();
+            if Instant::now() >= next_check {
+                vanished = waiting_for
+                    .values()
+                    .filter(|job| !self.job_exists(&job.path))
+                    .collect::<Vec<_>>();
+                // The signals received while we looked the jobs up are still queued.
+                while self.proxy.connection.process(Duration::ZERO)? {}
+                next_check = Instant::now() + JOB_CHECK_INTERVAL;
             }
-            let mut job_names = job_monitor.job_names.lock().unwrap();
-            if !job_names.is_empty() {
-                waiting_for = waiting_for.relative_complement(job_names.clone());
-                *job_names = im::HashSet::new();
-                if !waiting_for.is_empty() {
+            let mut finished: BTreeMap<String, String> =
+                std::mem::take(&mut *job_monitor.finished_jobs.lock().unwrap())
+                    .into_iter()
+                    .collect();
+            for job in vanished {
+                if !finished.contains_key(&job.path) {
+                    let result = self.vanished_job_result(job);
                     log::debug!(
-                        "Waiting for jobs to finish... ({}/{})",
-                        total_jobs - waiting_for.len(),
-                        total_jobs
+                        "Job {} for {} is gone without a signal, assuming {result}",
+                        job.path,
+                        job.unit
                     );
-                    log::debug!("Waiting for: {waiting_for:?}")
+                    finished.insert(job.path.clone(), result.to_owned());
                 }
             }
+
+            let mut progressed = false;
+            for (path, result) in finished {
+                // Jobs queued by others finish too, only keep ours.
+                if let Some(job) = waiting_for.remove(&path) {
+                    log::info!(target: PROGRESS_TARGET, "{}", progress.finish(job.job));
+                    results.insert(path, result);
+                    progressed = true;
+                }
+            }
+            if progressed && !waiting_for.is_empty() {
+                log::debug!(
+                    "Waiting for jobs to finish... ({}/{})",
+                    results.len(),
+                    total_jobs
+                );
+                log::debug!("Waiting for: {:?}", waiting_for.keys());
+            }
+
+            let elapsed = start_time.elapsed();
+            waiting_for.retain(|_, job| elapsed <= job.timeout);
+
+            let Some(first_timeout) = waiting_for.values().map(|job| job.timeout).min() else {
+                break;
+            };
+            // Wake up right after the first timeout, to give up on its job. Infinite
+            // timeouts never do.
+            let wake_up = first_timeout
+                .checked_add(Duration::from_millis(1))
+                .and_then(|timeout| start_time.checked_add(timeout))
+                .map_or(next_check, |timeout| timeout.min(next_check));
+            self.proxy
+                .connection
+                .process(wake_up.saturating_duration_since(Instant::now()))?;
         }
 
-        if total_jobs > 0 {
+        if total_jobs > 0 && results.len() == total_jobs {
             log::info!("All jobs finished.");
         }
-        Ok(true)
+        Ok(results)
+    }
+
+    /// Whether systemd still knows the job with the object path `path`.
+    fn job_exists(&self, path: &str) -> bool {
+        let Some(id) = job_id(path) else {
+            return true;
+        };
+        match OrgFreedesktopSystemd1Manager::get_job(&self.proxy, id) {
+            Ok(_) => true,
+            Err(e) if e.name() == Some("org.freedesktop.systemd1.NoSuchJob") => false,
+            Err(e) => {
+                log::debug!("Cannot look up job {path}: {e}");
+                true
+            }
+        }
+    }
+
+    /// The result of a job that is gone without telling, guessed from its unit.
+    fn vanished_job_result(&self, job: &WaitedJob) -> &'static str {
+        let state = self
+            .list_units_by_patterns(&[], &[&job.unit])
+            .ok()
+            .and_then(|units| units.into_iter().next())
+            .map(|unit| unit.active_state);
+        inferred_result(job.job, state.as_deref())
+    }
+
+    pub fn reload_or_try_restart_unit(&'_ self, unit_name: &str) -> Result<Job<'_>, Error> {
+        Ok(Job {
+            path: OrgFreedesktopSystemd1Manager::reload_or_try_restart_unit(
+                &self.proxy,
+                unit_name,
+                "replace",
+            )?,
+        })
     }
 
-    pub fn reload_or_restart_unit(&'_ self, unit_name: &str) -> Result<Job<'_>, Error> {
+    pub fn try_restart_unit(&'_ self, unit_name: &str) -> Result<Job<'_>, Error> {
         Ok(Job {
-            path: OrgFreedesktopSystemd1Manager::reload_or_restart_unit(
+            path: OrgFreedesktopSystemd1Manager::try_restart_unit(
                 &self.proxy,
                 unit_name,
                 "replace",
@@ -285,6 +556,50 @@ impl ServiceManager {
         Ok(())
     }
 
+    /// Enables `units` according to their `[Install]` section, without replacing the
+    /// symlinks that already exist.
+    pub fn enable_unit_files(
+        &self,
+        units: &[&str],
+        runtime: bool,
+    ) -> Result<Vec<UnitFileChange>, Error> {
+        let (_, changes) = OrgFreedesktopSystemd1Manager::enable_unit_files(
+            &self.proxy,
+            units.to_vec(),
+            runtime,
+            false,
+        )?;
+        Ok(changes.into_iter().map(UnitFileChange::from).collect())
+    }
+
+    pub fn disable_unit_files(
+        &self,
+        units: &[&str],
+        runtime: bool,
+    ) -> Result<Vec<UnitFileChange>, Error> {
+        let changes = OrgFreedesktopSystemd1Manager::disable_unit_files(
+            &self.proxy,
+            units.to_vec(),
+            runtime,
+        )?;
+        Ok(changes.into_iter().map(UnitFileChange::from).collect())
+    }
+
+    /// Enables or disables `units` according to the preset files of the host.
+    pub fn preset_unit_files(
+        &self,
+        units: &[&str],
+        runtime: bool,
+    ) -> Result<Vec<UnitFileChange>, Error> {
+        let (_, changes) = OrgFreedesktopSystemd1Manager::preset_unit_files(
+            &self.proxy,
+            units.to_vec(),
+            runtime,
+            false,
+        )?;
+        Ok(changes.into_iter().map(UnitFileChange::from).collect())
+    }
+
     pub fn list_units_by_patterns(
         &self,
         states: &[&str],
@@ -325,4 +640,223 @@ impl UnitManager<'_> {
     pub fn refuse_manual_stop(&self) -> Result<bool, Error> {
         Ok(OrgFreedesktopSystemd1Unit::refuse_manual_stop(&self.proxy)?)
     }
+
+    /// The units with a `Wants=` or `Requires=` dependency on the unit.
+    pub fn wanted_by(&self) -> Result<Vec<String>, Error> {
+        let mut units = OrgFreedesktopSystemd1Unit::wanted_by(&self.proxy)?;
+        units.extend(OrgFreedesktopSystemd1Unit::required_by(&self.proxy)?);
+        Ok(units)
+    }
+
+    pub fn active_state(&self) -> Result<String, Error> {
+        Ok(OrgFreedesktopSystemd1Unit::active_state(&self.proxy)?)
+    }
+
+    pub fn sub_state(&self) -> Result<String, Error> {
+        Ok(OrgFreedesktopSystemd1Unit::sub_state(&self.proxy)?)
+    }
+
+    /// How many times systemd restarted the service automatically, `None` for other units.
+    pub fn n_restarts(&self) -> Result<Option<u32>, Error> {
+        use dbus::blocking::stdintf::org_freedesktop_dbus::Properties;
+        match self
+            .proxy
+            .get::<u32>("org.freedesktop.systemd1.Service", "NRestarts")
+        {
+            Ok(restarts) => Ok(Some(restarts)),
+            Err(e) if e.name() == Some("org.freedesktop.DBus.Error.UnknownProperty") => Ok(None),
+            Err(e) if e.name() == Some("org.freedesktop.DBus.Error.UnknownInterface") => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// Waits for the jobs queued through a `ServiceManagerBackend`.
+pub trait JobWatcher {
+    /// Waits for the `jobs` to finish. Returns the result of each job, by object path,
+    /// that finished before its timeout.
+    fn wait(&self, jobs: Vec<WaitedJob>) -> Result<im::HashMap<String, String>, Error>;
+}
+
+impl JobWatcher for JobMonitor<'_> {
+    fn wait(&self, jobs: Vec<WaitedJob>) -> Result<im::HashMap<String, String>, Error> {
+        self.service_manager.monitor_jobs_finish(self, jobs)
+    }
+}
+
+/// The operations of the service manager that the activation relies on.
+///
+/// `ServiceManager` implements them over D-Bus, the tests use an in-memory fake.
+pub trait ServiceManagerBackend {
+    /// Starts recording the jobs that finish, before queueing them so that none is missed.
+    fn monitor_jobs(&self) -> Result<Box<dyn JobWatcher + '_>, Error>;
+
+    /// Queues a start job, returning the object path of the job. So do the other jobs.
+    fn start_unit(&self, unit: &str) -> Result<String, Error>;
+    fn stop_unit(&self, unit: &str) -> Result<String, Error>;
+    fn restart_unit(&self, unit: &str) -> Result<String, Error>;
+    fn try_restart_unit(&self, unit: &str) -> Result<String, Error>;
+    fn reload_or_try_restart_unit(&self, unit: &str) -> Result<String, Error>;
+
+    /// Performs a daemon reload, blocking until complete.
+    fn daemon_reload(&self) -> Result<(), Error>;
+
+    fn mask_unit_files(&self, units: &[&str], runtime: bool) -> Result<(), Error>;
+    fn unmask_unit_files(&self, units: &[&str], runtime: bool) -> Result<(), Error>;
+    fn enable_unit_files(
+        &self,
+        units: &[&str],
+        runtime: bool,
+    ) -> Result<Vec<UnitFileChange>, Error>;
+    fn disable_unit_files(
+        &self,
+        units: &[&str],
+        runtime: bool,
+    ) -> Result<Vec<UnitFileChange>, Error>;
+    fn preset_unit_files(
+        &self,
+        units: &[&str],
+        runtime: bool,
+    ) -> Result<Vec<UnitFileChange>, Error>;
+
+    /// The loaded units matching `patterns`, in one of `states` unless empty.
+    fn list_units_by_patterns(
+        &self,
+        states: &[&str],
+        patterns: &[&str],
+    ) -> Result<Vec<UnitStatus>, Error>;
+    /// The current active state and sub state of the unit.
+    fn unit_state(&self, unit: &UnitStatus) -> Result<(String, String), Error>;
+    /// How many times systemd restarted the service automatically, `None` for other units.
+    fn n_restarts(&self, unit: &UnitStatus) -> Result<Option<u32>, Error>;
+    /// The units with a `Wants=` or `Requires=` dependency on the unit.
+    fn wanted_by(&self, unit: &UnitStatus) -> Result<Vec<String>, Error>;
+}
+
+impl ServiceManagerBackend for ServiceManager {
+    fn monitor_jobs(&self) -> Result<Box<dyn JobWatcher + '_>, Error> {
+        Ok(Box::new(self.monitor_jobs_init()?))
+    }
+
+    fn start_unit(&self, unit: &str) -> Result<String, Error> {
+        Ok(ServiceManager::start_unit(self, unit)?.path())
+    }
+
+    fn stop_unit(&self, unit: &str) -> Result<String, Error> {
+        Ok(ServiceManager::stop_unit(self, unit)?.path())
+    }
+
+    fn restart_unit(&self, unit: &str) -> Result<String, Error> {
+        Ok(ServiceManager::restart_unit(self, unit)?.path())
+    }
+
+    fn try_restart_unit(&self, unit: &str) -> Result<String, Error> {
+        Ok(ServiceManager::try_restart_unit(self, unit)?.path())
+    }
+
+    fn reload_or_try_restart_unit(&self, unit: &str) -> Result<String, Error> {
+        Ok(ServiceManager::reload_or_try_restart_unit(self, unit)?.path())
+    }
+
+    fn daemon_reload(&self) -> Result<(), Error> {
+        ServiceManager::daemon_reload(self)
+    }
+
+    fn mask_unit_files(&self, units: &[&str], runtime: bool) -> Result<(), Error> {
+        ServiceManager::mask_unit_files(self, units, runtime)
+    }
+
+    fn unmask_unit_files(&self, units: &[&str], runtime: bool) -> Result<(), Error> {
+        ServiceManager::unmask_unit_files(self, units, runtime)
+    }
+
+    fn enable_unit_files(
+        &self,
+        units: &[&str],
+        runtime: bool,
+    ) -> Result<Vec<UnitFileChange>, Error> {
+        ServiceManager::enable_unit_files(self, units, runtime)
+    }
+
+    fn disable_unit_files(
+        &self,
+        units: &[&str],
+        runtime: bool,
+    ) -> Result<Vec<UnitFileChange>, Error> {
+        ServiceManager::disable_unit_files(self, units, runtime)
+    }
+
+    fn preset_unit_files(
+        &self,
+        units: &[&str],
+        runtime: bool,
+    ) -> Result<Vec<UnitFileChange>, Error> {
+        ServiceManager::preset_unit_files(self, units, runtime)
+    }
+
+    fn list_units_by_patterns(
+        &self,
+        states: &[&str],
+        patterns: &[&str],
+    ) -> Result<Vec<UnitStatus>, Error> {
+        ServiceManager::list_units_by_patterns(self, states, patterns)
+    }
+
+    fn unit_state(&self, unit: &UnitStatus) -> Result<(String, String), Error> {
+        let manager = self.unit_manager(unit);
+        Ok((manager.active_state()?, manager.sub_state()?))
+    }
+
+    fn n_restarts(&self, unit: &UnitStatus) -> Result<Option<u32>, Error> {
+        self.unit_manager(unit).n_restarts()
+    }
+
+    fn wanted_by(&self, unit: &UnitStatus) -> Result<Vec<String>, Error> {
+        self.unit_manager(unit).wanted_by()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn job(path: &str, job: &'static str) -> WaitedJob {
+        WaitedJob {
+            path: path.to_owned(),
+            unit: "app.service".to_owned(),
+            job,
+            timeout: Duration::from_secs(30),
+        }
+    }
+
+    #[test]
+    fn progress_is_counted_by_kind_of_job() {
+        let mut progress = ProgressTracker::new(&[
+            job("/org/freedesktop/systemd1/job/1", "restart"),
+            job("/org/freedesktop/systemd1/job/2", "restart"),
+            job("/org/freedesktop/systemd1/job/3", "start"),
+        ]);
+        assert_eq!(
+            progress.finish("restart").to_string(),
+            "1/2 units restarted"
+        );
+        assert_eq!(progress.finish("start").to_string(), "1/1 units started");
+        assert_eq!(
+            progress.finish("restart").to_string(),
+            "2/2 units restarted"
+        );
+    }
+
+    #[test]
+    fn vanished_jobs_get_the_result_of_their_unit() {
+        assert_eq!(job_id("/org/freedesktop/systemd1/job/42"), Some(42));
+        assert_eq!(job_id("/org/freedesktop/systemd1/job/"), None);
+
+        assert_eq!(inferred_result("start", Some("active")), "done");
+        assert_eq!(inferred_result("restart", Some("failed")), "failed");
+        assert_eq!(inferred_result("stop", Some("failed")), "done");
+        assert_eq!(inferred_result("stop", Some("active")), "failed");
+        // Units that are gone from systemd are stopped.
+        assert_eq!(inferred_result("stop", None), "done");
+    }
 }
@@ -0,0 +1,275 @@
+//! An in-memory service manager for the tests of the activation.
+//!
+//! It records the calls it gets, like `start app.service` or `daemon-reload`, and finishes
+//! the jobs right away, with `done` unless told otherwise.
+
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+
+use anyhow::Error;
+use dbus::Path;
+
+use super::{JobWatcher, ServiceManagerBackend, UnitFileChange, UnitStatus, WaitedJob};
+
+#[derive(Debug, Default)]
+pub struct FakeServiceManager {
+    /// The active state of the units loaded in the fake systemd.
+    units: RefCell<BTreeMap<String, String>>,
+    /// How the jobs of a unit end, `done` when absent. `pending` jobs never finish.
+    results: BTreeMap<String, String>,
+    /// The units that want or require a unit.
+    wanted_by: BTreeMap<String, Vec<String>>,
+    /// The result of the finished jobs, by object path.
+    finished: RefCell<BTreeMap<String, String>>,
+    last_job: Cell<u32>,
+    calls: RefCell<Vec<String>>,
+}
+
+impl FakeServiceManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Units that are already running.
+    pub fn with_running(self, units: &[&str]) -> Self {
+        self.units.borrow_mut().extend(
+            units
+                .iter()
+                .map(|unit| (unit.to_string(), "active".to_owned())),
+        );
+        self
+    }
+
+    /// Units that are loaded but not running.
+    pub fn with_stopped(self, units: &[&str]) -> Self {
+        self.units.borrow_mut().extend(
+            units
+                .iter()
+                .map(|unit| (unit.to_string(), "inactive".to_owned())),
+        );
+        self
+    }
+
+    /// Units that are loaded and failed.
+    pub fn with_failed(self, units: &[&str]) -> Self {
+        self.units.borrow_mut().extend(
+            units
+                .iter()
+                .map(|unit| (unit.to_string(), "failed".to_owned())),
+        );
+        self
+    }
+
+    /// Make the jobs of `unit` end with `result`.
+    pub fn with_result(mut self, unit: &str, result: &str) -> Self {
+        self.results.insert(unit.to_owned(), result.to_owned());
+        self
+    }
+
+    pub fn with_wanted_by(mut self, unit: &str, dependents: &[&str]) -> Self {
+        self.wanted_by.insert(
+            unit.to_owned(),
+            dependents.iter().map(ToString::to_string).collect(),
+        );
+        self
+    }
+
+    /// The calls made so far, in order.
+    pub fn calls(&self) -> Vec<String> {
+        self.calls.borrow().clone()
+    }
+
+    pub fn is_running(&self, unit: &str) -> bool {
+        self.active_state(unit) == "active"
+    }
+
+    fn active_state(&self, unit: &str) -> String {
+        self.units
+            .borrow()
+            .get(unit)
+            .cloned()
+            .unwrap_or_else(|| "inactive".to_owned())
+    }
+
+    fn record(&self, call: String) {
+        self.calls.borrow_mut().push(call);
+    }
+
+    /// Queue a `job` for `unit`, changing its state to `state` once done. The job does
+    /// nothing when `state` is `None`.
+    fn queue(&self, job: &str, unit: &str, state: Option<&str>) -> Result<String, Error> {
+        self.record(format!("{job} {unit}"));
+        self.last_job.set(self.last_job.get() + 1);
+        let path = format!("/org/freedesktop/systemd1/job/{}", self.last_job.get());
+        let result = self.results.get(unit).map_or("done", String::as_str);
+        match (result, state) {
+            ("pending", _) => {
+                self.units
+                    .borrow_mut()
+                    .insert(unit.to_owned(), "activating".to_owned());
+                return Ok(path);
+            }
+            ("done", Some(state)) => {
+                self.units
+                    .borrow_mut()
+                    .insert(unit.to_owned(), state.to_owned());
+            }
+            ("done", None) => {}
+            (_, _) => {
+                self.units
+                    .borrow_mut()
+                    .insert(unit.to_owned(), "failed".to_owned());
+            }
+        }
+        self.finished
+            .borrow_mut()
+            .insert(path.clone(), result.to_owned());
+        Ok(path)
+    }
+
+    fn status(&self, name: &str, active_state: &str) -> UnitStatus {
+        let sub_state = match active_state {
+            "active" => "running",
+            "inactive" => "dead",
+            other => other,
+        };
+        UnitStatus {
+            name: name.to_owned(),
+            description: String::new(),
+            load_state: "loaded".to_owned(),
+            active_state: active_state.to_owned(),
+            sub_state: sub_state.to_owned(),
+            followed: String::new(),
+            object_path: Path::default(),
+            queued_job: 0,
+            queued_job_type: String::new(),
+            queued_job_path: Path::default(),
+        }
+    }
+}
+
+/// Whether `name` matches `pattern`, which may contain a `*`.
+fn matches(pattern: &str, name: &str) -> bool {
+    match pattern.split_once('*') {
+        Some((prefix, suffix)) => {
+            name.len() >= prefix.len() + suffix.len()
+                && name.starts_with(prefix)
+                && name.ends_with(suffix)
+        }
+        None => pattern == name,
+    }
+}
+
+struct FakeWatcher<'a> {
+    manager: &'a FakeServiceManager,
+}
+
+impl JobWatcher for FakeWatcher<'_> {
+    fn wait(&self, jobs: Vec<WaitedJob>) -> Result<im::HashMap<String, String>, Error> {
+        let finished = self.manager.finished.borrow();
+        Ok(jobs
+            .into_iter()
+            .filter_map(|job| Some((job.path.clone(), finished.get(&job.path)?.clone())))
+            .collect())
+    }
+}
+
+impl ServiceManagerBackend for FakeServiceManager {
+    fn monitor_jobs(&self) -> Result<Box<dyn JobWatcher + '_>, Error> {
+        Ok(Box::new(FakeWatcher { manager: self }))
+    }
+
+    fn start_unit(&self, unit: &str) -> Result<String, Error> {
+        self.queue("start", unit, Some("active"))
+    }
+
+    fn stop_unit(&self, unit: &str) -> Result<String, Error> {
+        self.queue("stop", unit, Some("inactive"))
+    }
+
+    fn restart_unit(&self, unit: &str) -> Result<String, Error> {
+        self.queue("restart", unit, Some("active"))
+    }
+
+    fn try_restart_unit(&self, unit: &str) -> Result<String, Error> {
+        let state = self.is_running(unit).then_some("active");
+        self.queue("try-restart", unit, state)
+    }
+
+    fn reload_or_try_restart_unit(&self, unit: &str) -> Result<String, Error> {
+        let state = self.is_running(unit).then_some("active");
+        self.queue("reload-or-try-restart", unit, state)
+    }
+
+    fn daemon_reload(&self) -> Result<(), Error> {
+        self.record("daemon-reload".to_owned());
+        Ok(())
+    }
+
+    fn mask_unit_files(&self, units: &[&str], runtime: bool) -> Result<(), Error> {
+        let runtime = if runtime { " --runtime" } else { "" };
+        self.record(format!("mask{runtime} {}", units.join(" ")));
+        Ok(())
+    }
+
+    fn unmask_unit_files(&self, units: &[&str], runtime: bool) -> Result<(), Error> {
+        let runtime = if runtime { " --runtime" } else { "" };
+        self.record(format!("unmask{runtime} {}", units.join(" ")));
+        Ok(())
+    }
+
+    fn enable_unit_files(
+        &self,
+        units: &[&str],
+        _runtime: bool,
+    ) -> Result<Vec<UnitFileChange>, Error> {
+        self.record(format!("enable {}", units.join(" ")));
+        Ok(Vec::new())
+    }
+
+    fn disable_unit_files(
+        &self,
+        units: &[&str],
+        _runtime: bool,
+    ) -> Result<Vec<UnitFileChange>, Error> {
+        self.record(format!("disable {}", units.join(" ")));
+        Ok(Vec::new())
+    }
+
+    fn preset_unit_files(
+        &self,
+        units: &[&str],
+        _runtime: bool,
+    ) -> Result<Vec<UnitFileChange>, Error> {
+        self.record(format!("preset {}", units.join(" ")));
+        Ok(Vec::new())
+    }
+
+    fn list_units_by_patterns(
+        &self,
+        states: &[&str],
+        patterns: &[&str],
+    ) -> Result<Vec<UnitStatus>, Error> {
+        Ok(self
+            .units
+            .borrow()
+            .iter()
+            .filter(|(name, _)| patterns.iter().any(|pattern| matches(pattern, name)))
+            .filter(|(_, state)| states.is_empty() || states.contains(&state.as_str()))
+            .map(|(name, state)| self.status(name, state))
+            .collect())
+    }
+
+    fn unit_state(&self, unit: &UnitStatus) -> Result<(String, String), Error> {
+        let status = self.status(&unit.name, &self.active_state(&unit.name));
+        Ok((status.active_state, status.sub_state))
+    }
+
+    fn n_restarts(&self, _unit: &UnitStatus) -> Result<Option<u32>, Error> {
+        Ok(None)
+    }
+
+    fn wanted_by(&self, unit: &UnitStatus) -> Result<Vec<String>, Error> {
+        Ok(self.wanted_by.get(&unit.name).cloned().unwrap_or_default())
+    }
+}
@@ -0,0 +1,119 @@
+//! Keep the host from shutting down or sleeping while we change /etc and the units.
+//!
+//! logind hands out a file descriptor for each inhibitor lock, and releases the lock once
+//! every copy of it is closed.
+//!
+//! We take a `block` lock rather than a `delay` one: logind only honours a delay lock for
+//! `InhibitDelayMaxSec=`, 5 seconds by default, which is not enough for an activation
+//! waiting on its jobs. So that a stuck activation cannot keep the host up forever, the
+//! lock is released after `INHIBIT_DEADLINE` even when we are not done yet.
+
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use anyhow::Error;
+use dbus::{
+    arg::OwnedFd,
+    blocking::{Connection, Proxy},
+};
+
+const LOGIND_DESTINATION: &str = "org.freedesktop.login1";
+const LOGIND_PATH: &str = "/org/freedesktop/login1";
+const LOGIND_MANAGER: &str = "org.freedesktop.login1.Manager";
+
+/// How long to wait for logind to hand out the lock.
+const INHIBIT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long we block shutdown and sleep at most.
+const INHIBIT_DEADLINE: Duration = Duration::from_secs(30 * 60);
+
+/// A logind inhibitor lock blocking shutdown and sleep, released when dropped, or once
+/// `INHIBIT_DEADLINE` passed.
+pub struct InhibitorLock {
+    why: String,
+    /// Dropping it tells the watchdog to release the lock.
+    release: Option<mpsc::Sender<()>>,
+    /// Holds the file descriptor of the lock until it gets released.
+    watchdog: Option<JoinHandle<()>>,
+}
+
+impl InhibitorLock {
+    /// Blocks shutdown and sleep while we do `why`.
+    pub fn take(why: &str) -> Result<InhibitorLock, Error> {
+        let conn = Connection::new_system()?;
+        let proxy = Proxy::new(LOGIND_DESTINATION, LOGIND_PATH, INHIBIT_TIMEOUT, &conn);
+        let (fd,): (OwnedFd,) = proxy.method_call(
+            LOGIND_MANAGER,
+            "Inhibit",
+            ("shutdown:sleep", "system-manager", why, "block"),
+        )?;
+        log::debug!("Took an inhibitor lock on shutdown and sleep: {why}");
+        Ok(InhibitorLock::hold(fd, why, INHIBIT_DEADLINE))
+    }
+
+    /// Takes the lock, or logs why we go on without it, for instance on hosts without
+    /// logind.
+    pub fn take_or_warn(why: &str) -> Option<InhibitorLock> {
+        InhibitorLock::take(why)
+            .inspect_err(|e| {
+                log::warn!("Cannot inhibit shutdown and sleep, going on regardless: {e}")
+            })
+            .ok()
+    }
+
+    /// Keep `fd` open until the lock is dropped or `deadline` passed.
+    fn hold<F: Send + 'static>(fd: F, why: &str, deadline: Duration) -> InhibitorLock {
+        let (release, released) = mpsc::channel::<()>();
+        let owned_why = why.to_owned();
+        let watchdog = thread::spawn(move || {
+            if let Err(RecvTimeoutError::Timeout) = released.recv_timeout(deadline) {
+                log::warn!(
+                    "Releasing the inhibitor lock after {deadline:?}, shutdown and sleep are no longer blocked: {owned_why}"
+                );
+            }
+            drop(fd);
+        });
+        InhibitorLock {
+            why: why.to_owned(),
+            release: Some(release),
+            watchdog: Some(watchdog),
+        }
+    }
+}
+
+impl Drop for InhibitorLock {
+    fn drop(&mut self) {
+        log::debug!("Releasing the inhibitor lock: {}", self.why);
+        drop(self.release.take());
+        if let Some(watchdog) = self.watchdog.take() {
+            let _ = watchdog.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::io::Read;
+
+    /// Whether the write end of the pipe was closed, as logind sees the lock released.
+    fn released(reader: &mut File) -> bool {
+        reader.read(&mut [0]).unwrap() == 0
+    }
+
+    #[test]
+    fn locks_are_released_on_drop_or_after_the_deadline() {
+        let (reader, writer) = nix::unistd::pipe().unwrap();
+        let mut reader = File::from(reader);
+        let lock = InhibitorLock::hold(writer, "testing", Duration::from_secs(3600));
+        drop(lock);
+        assert!(released(&mut reader));
+
+        let (reader, writer) = nix::unistd::pipe().unwrap();
+        let mut reader = File::from(reader);
+        let _lock = InhibitorLock::hold(writer, "testing", Duration::from_millis(10));
+        assert!(released(&mut reader));
+    }
+}
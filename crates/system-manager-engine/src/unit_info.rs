@@ -1,15 +1,16 @@
 // Systemd unit file parsing, ported from nixpkgs switch-to-configuration-ng.
 //
 // Handles systemd INI quirks: empty values clear previous definitions,
-// multiple values for the same key accumulate, [Install] sections are skipped.
-// Also merges drop-in override files from `.d/*.conf` directories.
+// multiple values for the same key accumulate, [Install] sections are skipped and read
+// separately. Also merges drop-in override files from `.d/*.conf` directories.
 
 use anyhow::{anyhow, Context};
 use glob::glob;
 use ini::{Ini, ParseOption};
 use std::collections::HashMap;
 use std::io::Read;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 pub type UnitInfo = HashMap<String, HashMap<String, Vec<String>>>;
 
@@ -28,18 +29,7 @@ pub fn parse_systemd_ini(data: &mut UnitInfo, mut unit_file: impl Read) -> anyho
         .read_to_string(&mut unit_file_content)
         .context("Failed to read unit file")?;
 
-    let ini = Ini::load_from_str_opt(
-        &unit_file_content,
-        ParseOption {
-            enabled_quote: true,
-            enabled_indented_mutiline_value: false,
-            enabled_preserve_key_leading_whitespace: false,
-            // Allow for escaped characters that won't get interpreted by the INI parser. These
-            // often show up in systemd unit files device/mount/swap unit names (e.g. dev-disk-by\x2dlabel-root.device).
-            enabled_escape: false,
-        },
-    )
-    .context("Failed parse unit file as INI")?;
+    let ini = load_ini(&unit_file_content)?;
 
     // Copy over all sections
     for (section, properties) in ini.iter() {
@@ -48,7 +38,8 @@ pub fn parse_systemd_ini(data: &mut UnitInfo, mut unit_file: impl Read) -> anyho
         };
 
         if section == "Install" {
-            // Skip the [Install] section because it has no relevant keys for us
+            // Skip the [Install] section, it is only used to enable units, see
+            // parse_install_section.
             continue;
         }
 
@@ -93,6 +84,51 @@ pub fn parse_systemd_ini(data: &mut UnitInfo, mut unit_file: impl Read) -> anyho
     Ok(())
 }
 
+fn load_ini(content: &str) -> anyhow::Result<Ini> {
+    Ini::load_from_str_opt(
+        content,
+        ParseOption {
+            enabled_quote: true,
+            enabled_indented_mutiline_value: false,
+            enabled_preserve_key_leading_whitespace: false,
+            // Allow for escaped characters that won't get interpreted by the INI parser. These
+            // often show up in systemd unit files device/mount/swap unit names (e.g. dev-disk-by\x2dlabel-root.device).
+            enabled_escape: false,
+        },
+    )
+    .context("Failed parse unit file as INI")
+}
+
+// Parses the [Install] section of a unit file into its keys (WantedBy, RequiredBy, Alias,
+// Also, ...) and their space separated values. Drop-ins are ignored, like systemd does for
+// this section.
+pub fn parse_install_section(unit_file: &Path) -> anyhow::Result<HashMap<String, Vec<String>>> {
+    let content = std::fs::read_to_string(unit_file)
+        .with_context(|| format!("Failed to read unit file {}", unit_file.display()))?;
+    let ini = load_ini(&content)?;
+    let mut install: HashMap<String, Vec<String>> = HashMap::new();
+    for (section, properties) in ini.iter() {
+        if section != Some("Install") {
+            continue;
+        }
+        for (key, _) in properties {
+            // Keys set several times are listed once per value.
+            if install.contains_key(key) {
+                continue;
+            }
+            let values = install.entry(key.to_owned()).or_default();
+            for value in properties.get_all(key) {
+                if value.is_empty() {
+                    values.clear();
+                } else {
+                    values.extend(value.split_whitespace().map(str::to_owned));
+                }
+            }
+        }
+    }
+    Ok(install)
+}
+
 // This function takes the path to a systemd configuration file (like a unit configuration) and
 // parses it into a UnitInfo structure.
 //
@@ -107,13 +143,7 @@ pub fn parse_unit(unit_file: &Path) -> anyhow::Result<UnitInfo> {
     parse_systemd_ini(&mut unit_data, base_unit_file)
         .with_context(|| format!("Failed to parse systemd unit file {}", unit_file.display()))?;
 
-    for entry in
-        glob(&format!("{}.d/*.conf", unit_file.display())).context("Invalid glob pattern")?
-    {
-        let Ok(entry) = entry else {
-            continue;
-        };
-
+    for entry in dropin_files(unit_file)? {
         let unit_file = std::fs::File::open(&entry)
             .with_context(|| format!("Failed to open unit file {}", entry.display()))?;
         parse_systemd_ini(&mut unit_data, unit_file)?;
@@ -122,6 +152,14 @@ pub fn parse_unit(unit_file: &Path) -> anyhow::Result<UnitInfo> {
     Ok(unit_data)
 }
 
+// The drop-in files of a unit, `<unit file>.d/*.conf`, in the order systemd applies them.
+pub fn dropin_files(unit_file: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    Ok(glob(&format!("{}.d/*.conf", unit_file.display()))
+        .context("Invalid glob pattern")?
+        .filter_map(Result::ok)
+        .collect())
+}
+
 // Checks whether a specified boolean in a systemd unit is true or false, with a default that is
 // applied when the value is not set.
 pub fn parse_systemd_bool(
@@ -143,3 +181,79 @@ pub fn parse_systemd_bool(
         default
     }
 }
+
+// Parses a systemd time span such as `90`, `5s`, `1min 30s` or `infinity`. A number without
+// unit is in seconds, `infinity` maps to `Duration::MAX`. See systemd.time(7).
+pub fn parse_timespan(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if value == "infinity" {
+        return Some(Duration::MAX);
+    }
+    let mut total = Duration::ZERO;
+    let mut rest = value;
+    if rest.is_empty() {
+        return None;
+    }
+    while !rest.is_empty() {
+        let number_end = rest
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(rest.len());
+        let number: f64 = rest[..number_end].parse().ok()?;
+        rest = rest[number_end..].trim_start();
+        let unit_end = rest
+            .find(|c: char| !c.is_ascii_alphabetic())
+            .unwrap_or(rest.len());
+        let seconds_per_unit = match &rest[..unit_end] {
+            "us" | "usec" => 1e-6,
+            "ms" | "msec" => 1e-3,
+            "" | "s" | "sec" | "second" | "seconds" => 1.0,
+            "m" | "min" | "minute" | "minutes" => 60.0,
+            "h" | "hr" | "hour" | "hours" => 3600.0,
+            "d" | "day" | "days" => 86400.0,
+            _ => return None,
+        };
+        total += Duration::try_from_secs_f64(number * seconds_per_unit).ok()?;
+        rest = rest[unit_end..].trim_start();
+    }
+    Some(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timespans_are_parsed() {
+        assert_eq!(parse_timespan("90"), Some(Duration::from_secs(90)));
+        assert_eq!(parse_timespan("1min 30s"), Some(Duration::from_secs(90)));
+        assert_eq!(parse_timespan("500ms"), Some(Duration::from_millis(500)));
+        assert_eq!(parse_timespan("infinity"), Some(Duration::MAX));
+        assert_eq!(parse_timespan("soon"), None);
+        assert_eq!(parse_timespan(""), None);
+    }
+
+    #[test]
+    fn install_sections_are_parsed_separately() {
+        let dir = tempfile::tempdir().unwrap();
+        let unit_file = dir.path().join("app.service");
+        std::fs::write(
+            &unit_file,
+            "[Service]\nExecStart=/bin/app\n[Install]\nWantedBy=multi-user.target\nWantedBy=network-online.target\nAlias=web.service\n",
+        )
+        .unwrap();
+        std::fs::create_dir(dir.path().join("app.service.d")).unwrap();
+        std::fs::write(
+            dir.path().join("app.service.d/override.conf"),
+            "[Install]\nWantedBy=graphical.target\n",
+        )
+        .unwrap();
+
+        assert!(!parse_unit(&unit_file).unwrap().contains_key("Install"));
+        let install = parse_install_section(&unit_file).unwrap();
+        assert_eq!(
+            install["WantedBy"],
+            ["multi-user.target", "network-online.target"]
+        );
+        assert_eq!(install["Alias"], ["web.service"]);
+    }
+}
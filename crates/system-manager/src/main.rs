@@ -179,6 +179,101 @@ struct ActivationArgs {
     #[arg(long, action)]
     /// If true, only write under /run, otherwise write under /etc
     ephemeral: bool,
+
+    #[arg(long, action, conflicts_with = "ephemeral")]
+    /// If true, mount the static /etc entries as an overlay instead of linking them one by one
+    overlay: bool,
+
+    #[arg(long, conflicts_with_all = ["ephemeral", "overlay"])]
+    /// Activate the system installed in this directory (a disk image, a chroot...) instead of
+    /// the running one. Services are registered but not started.
+    root: Option<PathBuf>,
+}
+
+impl ActivationArgs {
+    /// The flags to pass on to the engine.
+    fn to_engine_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        if self.ephemeral {
+            args.push("--ephemeral".to_string());
+        }
+        if self.overlay {
+            args.push("--overlay".to_string());
+        }
+        if let Some(root) = &self.root {
+            args.push("--root".to_string());
+            args.push(root.display().to_string());
+        }
+        args
+    }
+}
+
+/// How the units get started and reported, only on `switch`: `pre-populate` doesn't start
+/// any.
+#[derive(clap::Args, Debug)]
+struct ServiceArgs {
+    #[arg(long, value_name = "SECONDS")]
+    /// How many seconds to watch the started units for failures and restart loops before
+    /// reporting success. Units can override it with X-SystemManagerSettleSec=
+    settle_time: Option<u64>,
+
+    #[arg(long, value_name = "SECONDS")]
+    /// How many seconds to wait for the systemd jobs of units that don't set a longer
+    /// TimeoutStartSec= or an X-SystemManagerJobTimeoutSec= (30 by default)
+    job_timeout: Option<u64>,
+
+    #[arg(long, value_name = "LINES")]
+    /// How many journal lines to report for each unit that failed to activate (20 by default)
+    journal_lines: Option<usize>,
+
+    #[arg(long, value_parser = ["text", "json"])]
+    /// Print a report of the activation on stdout, including the job results and the journal
+    /// of the failed units with json
+    output: Option<String>,
+}
+
+impl ServiceArgs {
+    /// The flags to pass on to the engine.
+    fn to_engine_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        if let Some(settle_time) = self.settle_time {
+            args.push("--settle-time".to_string());
+            args.push(settle_time.to_string());
+        }
+        if let Some(job_timeout) = self.job_timeout {
+            args.push("--job-timeout".to_string());
+            args.push(job_timeout.to_string());
+        }
+        if let Some(journal_lines) = self.journal_lines {
+            args.push("--journal-lines".to_string());
+            args.push(journal_lines.to_string());
+        }
+        if let Some(output) = &self.output {
+            args.push("--output".to_string());
+            args.push(output.clone());
+        }
+        args
+    }
+}
+
+#[derive(clap::Args, Debug)]
+struct DeactivationArgs {
+    #[arg(long, value_name = "SECONDS")]
+    /// How many seconds to wait for the systemd jobs of units that don't set a longer
+    /// TimeoutStopSec= or an X-SystemManagerJobTimeoutSec= (30 by default)
+    job_timeout: Option<u64>,
+}
+
+impl DeactivationArgs {
+    /// The flags to pass on to the engine.
+    fn to_engine_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        if let Some(job_timeout) = self.job_timeout {
+            args.push("--job-timeout".to_string());
+            args.push(job_timeout.to_string());
+        }
+        args
+    }
 }
 
 #[derive(clap::Args, Debug)]
@@ -222,6 +317,8 @@ enum Action {
         #[command(flatten)]
         activation_args: ActivationArgs,
         #[command(flatten)]
+        service_args: ServiceArgs,
+        #[command(flatten)]
         sudo_args: SudoArgs,
     },
     /// Build a new system-manager generation and register it as the active system-manager profile
@@ -241,6 +338,8 @@ enum Action {
         #[command(flatten)]
         optional_store_path_args: OptionalStorePathArg,
         #[command(flatten)]
+        deactivation_args: DeactivationArgs,
+        #[command(flatten)]
         sudo_args: SudoArgs,
     },
     /// Put all files defined by the given generation in place, but do not start services
@@ -260,6 +359,8 @@ enum Action {
         #[command(flatten)]
         activation_args: ActivationArgs,
         #[command(flatten)]
+        service_args: ServiceArgs,
+        #[command(flatten)]
         sudo_args: SudoArgs,
     },
 }
@@ -304,13 +405,13 @@ fn go(args: Args) -> Result<()> {
     match action {
         Action::PrePopulate {
             store_or_flake_args,
-            activation_args: ActivationArgs { ephemeral },
+            activation_args,
             sudo_args,
         } => {
             let sudo_options = sudo_args.to_sudo_options(legacy_use_remote_sudo)?;
             prepopulate(
                 store_or_flake_args,
-                ephemeral,
+                &activation_args,
                 &target_host,
                 &sudo_options,
                 &nix_options,
@@ -326,11 +427,13 @@ fn go(args: Args) -> Result<()> {
 
         Action::Deactivate {
             optional_store_path_args: OptionalStorePathArg { maybe_store_path },
+            deactivation_args,
             sudo_args,
         } => {
             let sudo_options = sudo_args.to_sudo_options(legacy_use_remote_sudo)?;
             deactivate(
                 maybe_store_path,
+                &deactivation_args,
                 &target_host,
                 &sudo_options,
                 &ssh_options,
@@ -403,7 +506,8 @@ fn go(args: Args) -> Result<()> {
 
         Action::Switch {
             build_args,
-            activation_args: ActivationArgs { ephemeral },
+            activation_args,
+            service_args,
             sudo_args,
         } => {
             let mut nix_build_options = NixBuildOptions::from(&build_args);
@@ -419,7 +523,8 @@ fn go(args: Args) -> Result<()> {
             )?;
             invoke_engine_activate(
                 &store_path,
-                ephemeral,
+                &activation_args,
+                &service_args,
                 &target_host,
                 &sudo_options,
                 &ssh_options,
@@ -429,14 +534,16 @@ fn go(args: Args) -> Result<()> {
 
         Action::Activate {
             store_path,
-            activation_args: ActivationArgs { ephemeral },
+            activation_args,
+            service_args,
             sudo_args,
         } => {
             let sudo_options = sudo_args.to_sudo_options(legacy_use_remote_sudo)?;
             copy_closure(&store_path, &target_host, &ssh_options)?;
             invoke_engine_activate(
                 &store_path,
-                ephemeral,
+                &activation_args,
+                &service_args,
                 &target_host,
                 &sudo_options,
                 &ssh_options,
@@ -552,7 +659,7 @@ fn register(
 
 fn prepopulate(
     args: StoreOrFlakeArgs,
-    ephemeral: bool,
+    activation_args: &ActivationArgs,
     target_host: &Option<String>,
     sudo_options: &SudoOptions,
     nix_options: &NixOptions,
@@ -577,7 +684,7 @@ fn prepopulate(
             invoke_engine_register(&store_path, target_host, sudo_options, ssh_options, verbose)?;
             invoke_engine_prepopulate(
                 &store_path,
-                ephemeral,
+                activation_args,
                 target_host,
                 sudo_options,
                 ssh_options,
@@ -598,7 +705,7 @@ fn prepopulate(
             invoke_engine_register(&store_path, target_host, sudo_options, ssh_options, verbose)?;
             invoke_engine_prepopulate(
                 &store_path,
-                ephemeral,
+                activation_args,
                 target_host,
                 sudo_options,
                 ssh_options,
@@ -614,13 +721,21 @@ fn prepopulate(
 
 fn deactivate(
     maybe_store_path: Option<StorePath>,
+    deactivation_args: &DeactivationArgs,
     target_host: &Option<String>,
     sudo_options: &SudoOptions,
     ssh_options: &[String],
     verbose: bool,
 ) -> Result<()> {
     let store_path = store_path_or_active_profile(maybe_store_path);
-    invoke_engine_deactivate(&store_path, target_host, sudo_options, ssh_options, verbose)
+    invoke_engine_deactivate(
+        &store_path,
+        deactivation_args,
+        target_host,
+        sudo_options,
+        ssh_options,
+        verbose,
+    )
 }
 
 // --- Engine invocation functions ---
@@ -648,7 +763,8 @@ fn invoke_engine_register(
 /// Invoke the engine's activate subcommand
 fn invoke_engine_activate(
     store_path: &StorePath,
-    ephemeral: bool,
+    activation_args: &ActivationArgs,
+    service_args: &ServiceArgs,
     target_host: &Option<String>,
     sudo_options: &SudoOptions,
     ssh_options: &[String],
@@ -660,9 +776,8 @@ fn invoke_engine_activate(
         "--store-path".to_string(),
         store_path.to_string(),
     ];
-    if ephemeral {
-        args.push("--ephemeral".to_string());
-    }
+    args.extend(activation_args.to_engine_args());
+    args.extend(service_args.to_engine_args());
     if verbose {
         args.push("--verbose".to_string());
     }
@@ -672,7 +787,7 @@ fn invoke_engine_activate(
 /// Invoke the engine's prepopulate subcommand
 fn invoke_engine_prepopulate(
     store_path: &StorePath,
-    ephemeral: bool,
+    activation_args: &ActivationArgs,
     target_host: &Option<String>,
     sudo_options: &SudoOptions,
     ssh_options: &[String],
@@ -684,9 +799,7 @@ fn invoke_engine_prepopulate(
         "--store-path".to_string(),
         store_path.to_string(),
     ];
-    if ephemeral {
-        args.push("--ephemeral".to_string());
-    }
+    args.extend(activation_args.to_engine_args());
     if verbose {
         args.push("--verbose".to_string());
     }
@@ -696,6 +809,7 @@ fn invoke_engine_prepopulate(
 /// Invoke the engine's deactivate subcommand
 fn invoke_engine_deactivate(
     store_path: &Path,
+    deactivation_args: &DeactivationArgs,
     target_host: &Option<String>,
     sudo_options: &SudoOptions,
     ssh_options: &[String],
@@ -714,6 +828,7 @@ fn invoke_engine_deactivate(
         resolved.join("bin").join(ENGINE_BIN)
     };
     let mut args = vec!["deactivate".to_string()];
+    args.extend(deactivation_args.to_engine_args());
     if verbose {
         args.push("--verbose".to_string());
     }
@@ -1031,4 +1146,116 @@ mod tests {
 
         assert!(args.ssh_options.is_empty());
     }
+
+    #[test]
+    fn service_options_are_passed_on_to_the_engine() {
+        let args = Args::try_parse_from([
+            "system-manager",
+            "switch",
+            "--flake",
+            ".#test",
+            "--settle-time",
+            "10",
+            "--job-timeout",
+            "120",
+            "--journal-lines",
+            "50",
+            "--output",
+            "json",
+        ])
+        .expect("failed to parse args");
+
+        match args.action {
+            Action::Switch { service_args, .. } => {
+                assert_eq!(
+                    service_args.to_engine_args(),
+                    vec![
+                        "--settle-time".to_string(),
+                        "10".to_string(),
+                        "--job-timeout".to_string(),
+                        "120".to_string(),
+                        "--journal-lines".to_string(),
+                        "50".to_string(),
+                        "--output".to_string(),
+                        "json".to_string()
+                    ]
+                );
+            }
+            _ => panic!("Expected Switch action"),
+        }
+    }
+
+    #[test]
+    fn service_options_are_rejected_by_pre_populate() {
+        for (flag, value) in [
+            ("--settle-time", "10"),
+            ("--job-timeout", "10"),
+            ("--journal-lines", "10"),
+            ("--output", "json"),
+        ] {
+            assert!(Args::try_parse_from([
+                "system-manager",
+                "pre-populate",
+                "--flake",
+                ".#test",
+                flag,
+                value,
+            ])
+            .is_err());
+        }
+    }
+
+    #[test]
+    fn job_timeout_is_passed_on_to_the_engine_on_deactivation() {
+        let args = Args::try_parse_from(["system-manager", "deactivate", "--job-timeout", "120"])
+            .expect("failed to parse args");
+
+        match args.action {
+            Action::Deactivate {
+                deactivation_args, ..
+            } => {
+                assert_eq!(
+                    deactivation_args.to_engine_args(),
+                    vec!["--job-timeout".to_string(), "120".to_string()]
+                );
+            }
+            _ => panic!("Expected Deactivate action"),
+        }
+    }
+
+    #[test]
+    fn root_is_passed_on_to_the_engine() {
+        let args = Args::try_parse_from([
+            "system-manager",
+            "switch",
+            "--flake",
+            ".#test",
+            "--root",
+            "/mnt/target",
+        ])
+        .expect("failed to parse args");
+
+        match args.action {
+            Action::Switch {
+                activation_args, ..
+            } => {
+                assert_eq!(
+                    activation_args.to_engine_args(),
+                    vec!["--root".to_string(), "/mnt/target".to_string()]
+                );
+            }
+            _ => panic!("Expected Switch action"),
+        }
+
+        let result = Args::try_parse_from([
+            "system-manager",
+            "switch",
+            "--flake",
+            ".#test",
+            "--root",
+            "/mnt/target",
+            "--ephemeral",
+        ]);
+        assert!(result.is_err());
+    }
 }
@@ -139,6 +139,12 @@ let
           text = lib.generators.toJSON { } config.build.services;
         };
 
+        userServicesPath = pkgs.writeTextFile {
+          name = "userServices";
+          destination = "/userServices.json";
+          text = lib.generators.toJSON { } config.build.userServices;
+        };
+
         etcPath = pkgs.writeTextFile {
           name = "etcFiles";
           destination = "/etcFiles.json";
@@ -170,6 +176,7 @@ let
 
             entries = [
               (linkFarmEntryFromDrv servicesPath)
+              (linkFarmEntryFromDrv userServicesPath)
               (linkFarmEntryFromDrv etcPath)
               engineEntry
             ]
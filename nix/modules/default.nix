@@ -192,6 +192,11 @@
           # TODO: better type
           type = lib.types.attrsOf lib.types.raw;
         };
+
+        userServices = lib.mkOption {
+          # TODO: better type
+          type = lib.types.attrsOf lib.types.raw;
+        };
       };
     };
 
@@ -337,7 +342,9 @@
           srcDrvs = lib.mapAttrs addToStore filteredEntries;
 
           staticDrvs = lib.mapAttrs addToStore (
-            lib.filterAttrs (name: etcFile: etcFile.mode == "symlink") filteredEntries
+            lib.filterAttrs (
+              name: etcFile: etcFile.mode == "symlink" && etcFile.patch == null
+            ) filteredEntries
           );
 
           entries = lib.mapAttrs (name: file: file // { source = "${srcDrvs.${name}}"; }) filteredEntries;
@@ -361,6 +368,7 @@
             lib.nameValuePair unitName {
               storePath = "${unit.unit}/${unitName}";
               masked = false;
+              preset = lib.elem unitName config.systemd.presetUnits;
             }
           ) enabledUnits;
 
@@ -375,6 +383,17 @@
           );
         in
         activeServices // maskedServices;
+
+      userServices = {
+        inherit (config.systemd.user) users;
+        services = lib.mapAttrs' (
+          unitName: unit:
+          lib.nameValuePair unitName {
+            storePath = "${unit.unit}/${unitName}";
+            masked = false;
+          }
+        ) (lib.filterAttrs (_: unit: unit.enable) config.systemd.user.units);
+      };
     };
   };
 }
@@ -132,6 +132,121 @@
                   when the entry is removed from the configuration.
                 '';
               };
+
+              onPackageConflict = lib.mkOption {
+                type = lib.types.enum [
+                  "warn"
+                  "fail"
+                  "divert"
+                ];
+                default = "warn";
+                description = lib.mdDoc ''
+                  What to do when `replaceExisting` replaces a file owned by a dpkg or
+                  rpm package, whose next upgrade may conflict with the managed entry.
+                  `warn` logs a warning, `fail` leaves the file untouched and reports an
+                  error, and `divert` registers a local {manpage}`dpkg-divert(1)`
+                  diversion so that package upgrades update the backup instead. The
+                  diversion is removed along with the entry, or on deactivation. When
+                  activating into `--root`, the package databases of the root are used.
+                  On rpm systems, `divert` falls back to `warn`.
+                '';
+              };
+
+              acl = lib.mkOption {
+                type = lib.types.listOf (lib.types.strMatching "(user|group):[^:]+:[rwx-]{1,3}");
+                default = [ ];
+                example = [ "group:monitoring:r--" ];
+                description = lib.mdDoc ''
+                  Additional POSIX ACL entries of the created file, in the
+                  `user:<name>:<perms>` or `group:<name>:<perms>` form of
+                  {manpage}`setfacl(1)`. The owner, group and other entries come from
+                  `mode`. Only takes effect when the file is copied (that is, the mode
+                  is not 'symlink').
+                '';
+              };
+
+              xattrs = lib.mkOption {
+                type = lib.types.attrsOf lib.types.str;
+                default = { };
+                example = {
+                  "user.backup" = "skip";
+                };
+                description = lib.mdDoc ''
+                  Extended attributes of the created file. Changes made to them outside
+                  of system-manager are reverted on the next activation.
+                  Only takes effect when the file is copied (that is, the mode is not 'symlink').
+                '';
+              };
+
+              renderSecrets = lib.mkOption {
+                type = lib.types.bool;
+                default = false;
+                description = lib.mdDoc ''
+                  Whether to replace `@secret:<path>@` placeholders in the file
+                  with the content of `<path>` when it gets copied to {file}`/etc`,
+                  for instance `@secret:/run/secrets/db_password@`.
+                  The secret files must be owned by root and not accessible to other
+                  users, such as the ones decrypted by sops-nix. Secret values never
+                  end up in the Nix store, the logs or the system-manager state.
+                  Only takes effect when the file is copied (that is, the mode is not 'symlink').
+                '';
+              };
+
+              template = lib.mkOption {
+                type = lib.types.bool;
+                default = false;
+                description = lib.mdDoc ''
+                  Whether to replace `@fact:<name>@` placeholders in the file with
+                  facts about the host when it gets copied to {file}`/etc`.
+                  The available facts are `hostname`, `machineId`, `kernelRelease`,
+                  `architecture`, `cpuCount`, `osId`, `osVersionId`,
+                  `primaryInterface`, `primaryIpv4` and `primaryIpv6`.
+                  Only takes effect when the file is copied (that is, the mode is not 'symlink').
+                '';
+              };
+
+              patch = lib.mkOption {
+                default = null;
+                example = lib.literalExpression ''
+                  {
+                    format = "ini";
+                    settings.Journal.SystemMaxUse = "1G";
+                  }
+                '';
+                description = lib.mdDoc ''
+                  Set individual keys in an existing, distro-owned file instead of
+                  replacing it. The original value of every key is recorded and
+                  restored when the key or the entry is removed from the configuration,
+                  or when system-manager is deactivated.
+
+                  `settings` is nested according to `format`:
+                  `{ section = { key = value; }; }` for `ini`, `{ key = value; }` for
+                  `keyValue`, and arbitrarily nested attribute sets for `json` and `toml`.
+                  Values of `ini` and `keyValue` files are written verbatim, so shell
+                  quoting needs to be part of the value.
+                '';
+                type = lib.types.nullOr (
+                  lib.types.submodule {
+                    options = {
+                      format = lib.mkOption {
+                        type = lib.types.enum [
+                          "ini"
+                          "json"
+                          "toml"
+                          "keyValue"
+                        ];
+                        description = lib.mdDoc "Format of the patched file.";
+                      };
+
+                      settings = lib.mkOption {
+                        type = lib.types.attrsOf lib.types.anything;
+                        default = { };
+                        description = lib.mdDoc "Keys to set in the patched file.";
+                      };
+                    };
+                  }
+                );
+              };
             };
 
             config = {
@@ -142,6 +257,12 @@
                 in
                 lib.mkDerivedConfig options.text (pkgs.writeText name')
               );
+              # Patched files have no content of their own, the keys live in the entry.
+              source = lib.mkIf (config.patch != null) (
+                lib.mkDefault (
+                  pkgs.writeText ("etc-patch-" + baseNameOf name) (builtins.toJSON config.patch.settings)
+                )
+              );
             };
           }
         )
@@ -161,6 +161,67 @@ in
       '';
     };
 
+    presetUnits = lib.mkOption {
+      type = lib.types.listOf lib.types.str;
+      default = [ ];
+      example = [ "avahi-daemon.service" ];
+      description = lib.mdDoc ''
+        Units with an `[Install]` section to enable or disable according to the
+        preset files of the host, like `systemctl preset` does, instead of always
+        enabling them.
+      '';
+    };
+
+    user = {
+      units = lib.mkOption {
+        description = lib.mdDoc "Definition of systemd user units, installed in /etc/systemd/user.";
+        default = { };
+        type = systemdUtils.types.units;
+      };
+
+      services = lib.mkOption {
+        default = { };
+        type = systemdUtils.types.services;
+        description = lib.mdDoc "Definition of systemd user service units.";
+      };
+
+      sockets = lib.mkOption {
+        default = { };
+        type = systemdUtils.types.sockets;
+        description = lib.mdDoc "Definition of systemd user socket units.";
+      };
+
+      timers = lib.mkOption {
+        default = { };
+        type = systemdUtils.types.timers;
+        description = lib.mdDoc "Definition of systemd user timer units.";
+      };
+
+      paths = lib.mkOption {
+        default = { };
+        type = systemdUtils.types.paths;
+        description = lib.mdDoc "Definition of systemd user path units.";
+      };
+
+      targets = lib.mkOption {
+        default = { };
+        type = systemdUtils.types.targets;
+        description = lib.mdDoc "Definition of systemd user target units.";
+      };
+
+      users = lib.mkOption {
+        type = lib.types.listOf lib.types.str;
+        default = [ ];
+        example = [ "alice" ];
+        description = lib.mdDoc ''
+          Users whose running systemd user manager gets the user units started, stopped
+          and restarted on activation. By default, this is done for every user with a
+          running user manager, because they are logged in or have lingering enabled.
+          The other users pick up the units the next time their user manager starts.
+        '';
+      };
+    };
+
     sysusers = {
       enable = lib.mkEnableOption "systemd-sysusers" // {
         description = ''
@@ -193,6 +254,18 @@ in
         timerConfig.OnCalendar = service.startAt;
       }) (lib.filterAttrs (name: service: service.enable && service.startAt != [ ]) cfg.services);
 
+      user.timers = lib.mapAttrs (name: service: {
+        wantedBy = [ "timers.target" ];
+        timerConfig.OnCalendar = service.startAt;
+      }) (lib.filterAttrs (name: service: service.enable && service.startAt != [ ]) cfg.user.services);
+
+      user.units =
+        lib.mapAttrs' (n: v: lib.nameValuePair "${n}.path" (systemd-lib.pathToUnit v)) cfg.user.paths
+        // lib.mapAttrs' (n: v: lib.nameValuePair "${n}.service" (systemd-lib.serviceToUnit v)) cfg.user.services
+        // lib.mapAttrs' (n: v: lib.nameValuePair "${n}.socket" (systemd-lib.socketToUnit v)) cfg.user.sockets
+        // lib.mapAttrs' (n: v: lib.nameValuePair "${n}.target" (systemd-lib.targetToUnit v)) cfg.user.targets
+        // lib.mapAttrs' (n: v: lib.nameValuePair "${n}.timer" (systemd-lib.timerToUnit v)) cfg.user.timers;
+
       units =
         lib.mapAttrs' (n: v: lib.nameValuePair "${n}.path" (systemd-lib.pathToUnit v)) cfg.paths
         // lib.mapAttrs' (n: v: lib.nameValuePair "${n}.service" (systemd-lib.serviceToUnit v)) cfg.services
@@ -223,6 +296,7 @@ in
     environment.etc =
       let
         enabledUnits = lib.filterAttrs (_: unit: unit.enable) cfg.units;
+        enabledUserUnits = lib.filterAttrs (_: unit: unit.enable) cfg.user.units;
         # The `aliases` option is part of the nixpkgs systemd unit schema and
         # but system-manager does not implement it: we cannot emit
         # sibling-relative alias symlinks safely because the activator
@@ -293,6 +367,37 @@ in
                 ) enabledUnits
               )}
             '';
+      }
+      // lib.optionalAttrs (enabledUserUnits != { }) {
+        # Only our own units, the distro's user units stay in /usr/lib/systemd/user.
+        "systemd/user".source =
+          pkgs.runCommand "system-manager-user-units"
+            {
+              preferLocalBuild = true;
+              allowSubstitutes = false;
+            }
+            ''
+              set -e
+              mkdir -p $out
+
+              for i in ${toString (lib.mapAttrsToList (n: v: v.unit) enabledUserUnits)}; do
+                ln -fs $i/$(basename $i/*) $out/
+              done
+
+              ${lib.concatStrings (
+                lib.mapAttrsToList (
+                  name: unit:
+                  lib.concatMapStrings (target: ''
+                    mkdir -p $out/'${target}.wants'
+                    ln -sfn '../${name}' $out/'${target}.wants'/
+                  '') (unit.wantedBy or [ ])
+                  + lib.concatMapStrings (target: ''
+                    mkdir -p $out/'${target}.requires'
+                    ln -sfn '../${name}' $out/'${target}.requires'/
+                  '') (unit.requiredBy or [ ])
+                ) enabledUserUnits
+              )}
+            '';
       };
   };
 }